@@ -32,19 +32,35 @@ impl Canvas {
     where
         Self: Converts<T, ScreenCoordinate>,
     {
-        let screen_coord = self.convert(coord);
-        if let ScreenCoordinate::OnScreen { x, y } = screen_coord {
-            let pixel_index = (y * self.width + x) * 4;
+        if let Some(index) = self.pixel_index(coord) {
+            let pixel_index = index * 4;
             let pixel = &mut frame[pixel_index..pixel_index + 4];
             pixel.copy_from_slice(color.as_array())
         }
         // otherwise do nothing
     }
 
-    pub(crate) fn window(self) -> WindowBuilder {
+    /**
+     * The row-major index of the pixel at `coord`, or None if it is off screen
+     */
+    pub(crate) fn pixel_index<T>(self, coord: T) -> Option<usize>
+    where
+        Self: Converts<T, ScreenCoordinate>,
+    {
+        match self.convert(coord) {
+            ScreenCoordinate::OnScreen { x, y } => Some(y * self.width + x),
+            ScreenCoordinate::OffScreen => None,
+        }
+    }
+
+    pub(crate) fn pixel_count(self) -> usize {
+        self.width * self.height
+    }
+
+    pub(crate) fn window(self, title: &str) -> WindowBuilder {
         let size = self.logical_size();
         WindowBuilder::new()
-            .with_title("Giraffics")
+            .with_title(title)
             .with_inner_size(size)
             .with_min_inner_size(size)
     }
@@ -61,7 +77,7 @@ impl Canvas {
         self.width
     }
     pub(crate) fn height(self) -> usize {
-        self.width
+        self.height
     }
 
     pub(crate) fn iter_pixels(self) -> EachCanvasCoordinate {
@@ -138,7 +154,7 @@ impl Iterator for EachCanvasCoordinate {
         let coord = Some(CanvasCoordinate::new(self.next_x, self.next_y));
 
         self.next_x += 1;
-        if self.next_x >= self.max_x as isize {
+        if self.next_x >= self.max_x {
             self.next_x = self.min_x;
             self.next_y += 1;
             if self.next_y >= self.max_y {
@@ -1,4 +1,5 @@
-use std::ops::{Add, Mul};
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, Mul, Sub};
 
 pub(crate) const RED: Color = Color::rgb(255, 0, 0);
 pub(crate) const GREEN: Color = Color::rgb(0, 255, 0);
@@ -11,13 +12,13 @@ pub(crate) const WHITE: Color = Color::rgb(255, 255, 255);
 pub(crate) struct Color([u8; 4]);
 
 impl Color {
-    pub(crate) fn as_array<'a>(&'a self) -> &'a [u8; 4] {
+    pub(crate) fn as_array(&self) -> &[u8; 4] {
         &self.0
     }
 
     #[inline]
     pub(crate) const fn rgb(red: u8, green: u8, blue: u8) -> Color {
-        Color([red, green, blue, 255])
+        Color::rgba(red, green, blue, 255)
     }
 
     #[inline]
@@ -37,64 +38,447 @@ impl Color {
         self.0[2]
     }
 
-    pub(crate) fn alpha(&self) -> u8 {
-        self.0[3]
+    /**
+     * Look up a CSS color name, such as crimson
+     */
+    pub(crate) fn named(name: &str) -> Option<Self> {
+        NAMED_COLORS
+            .binary_search_by(|(candidate, _)| candidate.cmp(&name))
+            .ok()
+            .map(|index| NAMED_COLORS[index].1)
     }
 
-    pub(crate) fn scale(&self, scalar: f64) -> Self {
-        let red = mul_with_ceiling(self.red(), scalar);
-        let green = mul_with_ceiling(self.green(), scalar);
-        let blue = mul_with_ceiling(self.blue(), scalar);
-        let alpha = mul_with_ceiling(self.alpha(), scalar);
+    /**
+     * Decode this 8-bit color into linear light, interpreting the channels in the given color space
+     */
+    pub(crate) fn to_linear(self, color_space: ColorSpace) -> LinearColor {
+        LinearColor::new(
+            color_space.decode(self.red()),
+            color_space.decode(self.green()),
+            color_space.decode(self.blue()),
+        )
+    }
+}
+
+/**
+ * How 8-bit channel values relate to linear light intensity
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ColorSpace {
+    /// Channels are sRGB encoded, so must be decoded before shading and encoded for display
+    #[default]
+    Srgb,
+    /// Channels are already proportional to light intensity
+    Linear,
+}
+
+impl ColorSpace {
+    pub(crate) fn decode(self, channel: u8) -> f64 {
+        self.decode_unit(channel as f64 / u8::MAX as f64)
+    }
+
+    /**
+     * Decode a channel value given between 0 and 1 rather than as a byte
+     */
+    pub(crate) fn decode_unit(self, value: f64) -> f64 {
+        match self {
+            Self::Linear => value,
+            Self::Srgb => {
+                if value <= 0.04045 {
+                    value / 12.92
+                } else {
+                    ((value + 0.055) / 1.055).powf(2.4)
+                }
+            }
+        }
+    }
+
+    pub(crate) fn encode(self, value: f64) -> u8 {
+        (self.encode_unit(value) * u8::MAX as f64).round() as u8
+    }
+
+    /**
+     * Encode a linear value, clamped to [0, 1], as a channel value between 0 and 1
+     */
+    pub(crate) fn encode_unit(self, value: f64) -> f64 {
+        let value = value.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => value,
+            Self::Srgb => {
+                if value <= 0.003_130_8 {
+                    value * 12.92
+                } else {
+                    1.055 * value.powf(1.0 / 2.4) - 0.055
+                }
+            }
+        }
+    }
+}
+
+/**
+ * A color in linear light, with each channel nominally in [0, 1].
+ * All shading is done with linear colors; they are only encoded to a `Color` for display.
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub(crate) struct LinearColor {
+    red: f64,
+    green: f64,
+    blue: f64,
+}
+
+impl LinearColor {
+    pub(crate) const fn new(red: f64, green: f64, blue: f64) -> Self {
+        Self { red, green, blue }
+    }
+
+    /**
+     * Decode a color whose channels are given between 0 and 1 in `color_space`
+     */
+    pub(crate) fn decode(channels: (f64, f64, f64), color_space: ColorSpace) -> Self {
+        let (red, green, blue) = channels;
+        LinearColor::new(
+            color_space.decode_unit(red),
+            color_space.decode_unit(green),
+            color_space.decode_unit(blue),
+        )
+    }
+
+    pub(crate) fn red(self) -> f64 {
+        self.red
+    }
+
+    pub(crate) fn green(self) -> f64 {
+        self.green
+    }
+
+    pub(crate) fn blue(self) -> f64 {
+        self.blue
+    }
+
+    pub(crate) fn max_channel(self) -> f64 {
+        self.red.max(self.green).max(self.blue)
+    }
 
-        Color::rgba(red, green, blue, alpha)
+    /**
+     * The brightness of the color as perceived by the eye, using the Rec. 709 weights
+     */
+    pub(crate) fn luminance(self) -> f64 {
+        0.2126 * self.red + 0.7152 * self.green + 0.0722 * self.blue
     }
 
-    pub(crate) fn from_rgb_tuple(tuple: (f64, f64, f64)) -> Self {
-        let (red, green, blue) = tuple;
+    pub(crate) fn average(self) -> f64 {
+        (self.red + self.green + self.blue) / 3.0
+    }
 
-        Self::rgb(red as u8, green as u8, blue as u8)
+    /**
+     * Encode this color along with `alpha`, the fraction of the pixel it covers
+     */
+    pub(crate) fn to_color_with_alpha(self, color_space: ColorSpace, alpha: f64) -> Color {
+        Color::rgba(
+            color_space.encode(self.red),
+            color_space.encode(self.green),
+            color_space.encode(self.blue),
+            ColorSpace::Linear.encode(alpha),
+        )
     }
 }
 
-impl Add<Color> for Color {
-    type Output = Color;
+impl Add<LinearColor> for LinearColor {
+    type Output = LinearColor;
+
+    fn add(self, other: LinearColor) -> LinearColor {
+        LinearColor::new(
+            self.red + other.red,
+            self.green + other.green,
+            self.blue + other.blue,
+        )
+    }
+}
 
-    fn add(self, other: Color) -> Color {
-        let red = add_with_ceiling(self.red(), other.red());
-        let green = add_with_ceiling(self.green(), other.green());
-        let blue = add_with_ceiling(self.blue(), other.blue());
-        let alpha = add_with_ceiling(self.alpha(), other.alpha());
+impl Sub<LinearColor> for LinearColor {
+    type Output = LinearColor;
 
-        Color::rgba(red, green, blue, alpha)
+    fn sub(self, other: LinearColor) -> LinearColor {
+        LinearColor::new(
+            self.red - other.red,
+            self.green - other.green,
+            self.blue - other.blue,
+        )
     }
 }
 
-impl<T> Mul<T> for Color
-where
-    T: Into<f64>,
-{
-    type Output = Color;
+impl Mul<LinearColor> for LinearColor {
+    type Output = LinearColor;
 
-    fn mul(self, other: T) -> Color {
-        self.scale(other.into())
+    fn mul(self, other: LinearColor) -> LinearColor {
+        LinearColor::new(
+            self.red * other.red,
+            self.green * other.green,
+            self.blue * other.blue,
+        )
     }
 }
 
-fn add_with_ceiling(a: u8, b: u8) -> u8 {
-    let sum = (a as u16) + (b as u16);
-    if sum > (u8::MAX as u16) {
-        u8::MAX
-    } else {
-        sum as u8
+impl Mul<f64> for LinearColor {
+    type Output = LinearColor;
+
+    fn mul(self, scalar: f64) -> LinearColor {
+        LinearColor::new(self.red * scalar, self.green * scalar, self.blue * scalar)
     }
 }
 
-fn mul_with_ceiling(a: u8, b: f64) -> u8 {
-    let sum = (a as f64) * b;
-    if sum > (u8::MAX as f64) {
-        u8::MAX
-    } else {
-        sum as u8
+/**
+ * Convert a hue in degrees, and a saturation and value between 0 and 1, to red, green and blue
+ * channels between 0 and 1
+ */
+pub(crate) fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> (f64, f64, f64) {
+    let chroma = value * saturation;
+    let sector = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (red, green, blue) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    (red + m, green + m, blue + m)
+}
+
+/**
+ * The CSS named colors, sorted by name
+ */
+const NAMED_COLORS: [(&str, Color); 148] = [
+    ("aliceblue", Color::rgb(240, 248, 255)),
+    ("antiquewhite", Color::rgb(250, 235, 215)),
+    ("aqua", Color::rgb(0, 255, 255)),
+    ("aquamarine", Color::rgb(127, 255, 212)),
+    ("azure", Color::rgb(240, 255, 255)),
+    ("beige", Color::rgb(245, 245, 220)),
+    ("bisque", Color::rgb(255, 228, 196)),
+    ("black", BLACK),
+    ("blanchedalmond", Color::rgb(255, 235, 205)),
+    ("blue", BLUE),
+    ("blueviolet", Color::rgb(138, 43, 226)),
+    ("brown", Color::rgb(165, 42, 42)),
+    ("burlywood", Color::rgb(222, 184, 135)),
+    ("cadetblue", Color::rgb(95, 158, 160)),
+    ("chartreuse", Color::rgb(127, 255, 0)),
+    ("chocolate", Color::rgb(210, 105, 30)),
+    ("coral", Color::rgb(255, 127, 80)),
+    ("cornflowerblue", Color::rgb(100, 149, 237)),
+    ("cornsilk", Color::rgb(255, 248, 220)),
+    ("crimson", Color::rgb(220, 20, 60)),
+    ("cyan", Color::rgb(0, 255, 255)),
+    ("darkblue", Color::rgb(0, 0, 139)),
+    ("darkcyan", Color::rgb(0, 139, 139)),
+    ("darkgoldenrod", Color::rgb(184, 134, 11)),
+    ("darkgray", Color::rgb(169, 169, 169)),
+    ("darkgreen", Color::rgb(0, 100, 0)),
+    ("darkgrey", Color::rgb(169, 169, 169)),
+    ("darkkhaki", Color::rgb(189, 183, 107)),
+    ("darkmagenta", Color::rgb(139, 0, 139)),
+    ("darkolivegreen", Color::rgb(85, 107, 47)),
+    ("darkorange", Color::rgb(255, 140, 0)),
+    ("darkorchid", Color::rgb(153, 50, 204)),
+    ("darkred", Color::rgb(139, 0, 0)),
+    ("darksalmon", Color::rgb(233, 150, 122)),
+    ("darkseagreen", Color::rgb(143, 188, 143)),
+    ("darkslateblue", Color::rgb(72, 61, 139)),
+    ("darkslategray", Color::rgb(47, 79, 79)),
+    ("darkslategrey", Color::rgb(47, 79, 79)),
+    ("darkturquoise", Color::rgb(0, 206, 209)),
+    ("darkviolet", Color::rgb(148, 0, 211)),
+    ("deeppink", Color::rgb(255, 20, 147)),
+    ("deepskyblue", Color::rgb(0, 191, 255)),
+    ("dimgray", Color::rgb(105, 105, 105)),
+    ("dimgrey", Color::rgb(105, 105, 105)),
+    ("dodgerblue", Color::rgb(30, 144, 255)),
+    ("firebrick", Color::rgb(178, 34, 34)),
+    ("floralwhite", Color::rgb(255, 250, 240)),
+    ("forestgreen", Color::rgb(34, 139, 34)),
+    ("fuchsia", Color::rgb(255, 0, 255)),
+    ("gainsboro", Color::rgb(220, 220, 220)),
+    ("ghostwhite", Color::rgb(248, 248, 255)),
+    ("gold", Color::rgb(255, 215, 0)),
+    ("goldenrod", Color::rgb(218, 165, 32)),
+    ("gray", Color::rgb(128, 128, 128)),
+    ("green", Color::rgb(0, 128, 0)),
+    ("greenyellow", Color::rgb(173, 255, 47)),
+    ("grey", Color::rgb(128, 128, 128)),
+    ("honeydew", Color::rgb(240, 255, 240)),
+    ("hotpink", Color::rgb(255, 105, 180)),
+    ("indianred", Color::rgb(205, 92, 92)),
+    ("indigo", Color::rgb(75, 0, 130)),
+    ("ivory", Color::rgb(255, 255, 240)),
+    ("khaki", Color::rgb(240, 230, 140)),
+    ("lavender", Color::rgb(230, 230, 250)),
+    ("lavenderblush", Color::rgb(255, 240, 245)),
+    ("lawngreen", Color::rgb(124, 252, 0)),
+    ("lemonchiffon", Color::rgb(255, 250, 205)),
+    ("lightblue", Color::rgb(173, 216, 230)),
+    ("lightcoral", Color::rgb(240, 128, 128)),
+    ("lightcyan", Color::rgb(224, 255, 255)),
+    ("lightgoldenrodyellow", Color::rgb(250, 250, 210)),
+    ("lightgray", Color::rgb(211, 211, 211)),
+    ("lightgreen", Color::rgb(144, 238, 144)),
+    ("lightgrey", Color::rgb(211, 211, 211)),
+    ("lightpink", Color::rgb(255, 182, 193)),
+    ("lightsalmon", Color::rgb(255, 160, 122)),
+    ("lightseagreen", Color::rgb(32, 178, 170)),
+    ("lightskyblue", Color::rgb(135, 206, 250)),
+    ("lightslategray", Color::rgb(119, 136, 153)),
+    ("lightslategrey", Color::rgb(119, 136, 153)),
+    ("lightsteelblue", Color::rgb(176, 196, 222)),
+    ("lightyellow", Color::rgb(255, 255, 224)),
+    ("lime", GREEN),
+    ("limegreen", Color::rgb(50, 205, 50)),
+    ("linen", Color::rgb(250, 240, 230)),
+    ("magenta", Color::rgb(255, 0, 255)),
+    ("maroon", Color::rgb(128, 0, 0)),
+    ("mediumaquamarine", Color::rgb(102, 205, 170)),
+    ("mediumblue", Color::rgb(0, 0, 205)),
+    ("mediumorchid", Color::rgb(186, 85, 211)),
+    ("mediumpurple", Color::rgb(147, 112, 219)),
+    ("mediumseagreen", Color::rgb(60, 179, 113)),
+    ("mediumslateblue", Color::rgb(123, 104, 238)),
+    ("mediumspringgreen", Color::rgb(0, 250, 154)),
+    ("mediumturquoise", Color::rgb(72, 209, 204)),
+    ("mediumvioletred", Color::rgb(199, 21, 133)),
+    ("midnightblue", Color::rgb(25, 25, 112)),
+    ("mintcream", Color::rgb(245, 255, 250)),
+    ("mistyrose", Color::rgb(255, 228, 225)),
+    ("moccasin", Color::rgb(255, 228, 181)),
+    ("navajowhite", Color::rgb(255, 222, 173)),
+    ("navy", Color::rgb(0, 0, 128)),
+    ("oldlace", Color::rgb(253, 245, 230)),
+    ("olive", Color::rgb(128, 128, 0)),
+    ("olivedrab", Color::rgb(107, 142, 35)),
+    ("orange", Color::rgb(255, 165, 0)),
+    ("orangered", Color::rgb(255, 69, 0)),
+    ("orchid", Color::rgb(218, 112, 214)),
+    ("palegoldenrod", Color::rgb(238, 232, 170)),
+    ("palegreen", Color::rgb(152, 251, 152)),
+    ("paleturquoise", Color::rgb(175, 238, 238)),
+    ("palevioletred", Color::rgb(219, 112, 147)),
+    ("papayawhip", Color::rgb(255, 239, 213)),
+    ("peachpuff", Color::rgb(255, 218, 185)),
+    ("peru", Color::rgb(205, 133, 63)),
+    ("pink", Color::rgb(255, 192, 203)),
+    ("plum", Color::rgb(221, 160, 221)),
+    ("powderblue", Color::rgb(176, 224, 230)),
+    ("purple", Color::rgb(128, 0, 128)),
+    ("rebeccapurple", Color::rgb(102, 51, 153)),
+    ("red", RED),
+    ("rosybrown", Color::rgb(188, 143, 143)),
+    ("royalblue", Color::rgb(65, 105, 225)),
+    ("saddlebrown", Color::rgb(139, 69, 19)),
+    ("salmon", Color::rgb(250, 128, 114)),
+    ("sandybrown", Color::rgb(244, 164, 96)),
+    ("seagreen", Color::rgb(46, 139, 87)),
+    ("seashell", Color::rgb(255, 245, 238)),
+    ("sienna", Color::rgb(160, 82, 45)),
+    ("silver", Color::rgb(192, 192, 192)),
+    ("skyblue", Color::rgb(135, 206, 235)),
+    ("slateblue", Color::rgb(106, 90, 205)),
+    ("slategray", Color::rgb(112, 128, 144)),
+    ("slategrey", Color::rgb(112, 128, 144)),
+    ("snow", Color::rgb(255, 250, 250)),
+    ("springgreen", Color::rgb(0, 255, 127)),
+    ("steelblue", Color::rgb(70, 130, 180)),
+    ("tan", Color::rgb(210, 180, 140)),
+    ("teal", Color::rgb(0, 128, 128)),
+    ("thistle", Color::rgb(216, 191, 216)),
+    ("tomato", Color::rgb(255, 99, 71)),
+    ("turquoise", Color::rgb(64, 224, 208)),
+    ("violet", Color::rgb(238, 130, 238)),
+    ("wheat", Color::rgb(245, 222, 179)),
+    ("white", WHITE),
+    ("whitesmoke", Color::rgb(245, 245, 245)),
+    ("yellow", Color::rgb(255, 255, 0)),
+    ("yellowgreen", Color::rgb(154, 205, 50)),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_channel_value_survives_decoding_and_encoding() {
+        for color_space in [ColorSpace::Srgb, ColorSpace::Linear] {
+            for channel in 0..=u8::MAX {
+                assert_eq!(color_space.encode(color_space.decode(channel)), channel);
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_matches_known_values() {
+        assert_eq!(ColorSpace::Srgb.decode(0), 0.0);
+        assert_eq!(ColorSpace::Srgb.decode(255), 1.0);
+        // Mid grey in linear light is much lighter once encoded
+        assert_eq!(ColorSpace::Srgb.encode(0.5), 188);
+        assert!((ColorSpace::Srgb.decode(188) - 0.5).abs() < 0.005);
+        // Both pieces of the curve meet at 0.04045 encoded, 0.0031308 linear
+        assert_eq!(ColorSpace::Srgb.encode(0.003_130_8), 10);
+        assert!((ColorSpace::Srgb.decode(10) - 10.0 / 255.0 / 12.92).abs() < 1e-12);
+        // Out of range values are clamped
+        assert_eq!(ColorSpace::Srgb.encode(-1.0), 0);
+        assert_eq!(ColorSpace::Srgb.encode(2.0), 255);
+    }
+
+    #[test]
+    fn linear_channels_are_unchanged() {
+        assert_eq!(ColorSpace::Linear.decode(51), 0.2);
+        assert_eq!(ColorSpace::Linear.encode(0.2), 51);
+    }
+
+    #[test]
+    fn unit_srgb_values_decode_along_the_srgb_curve() {
+        let decode = |value| ColorSpace::Srgb.decode_unit(value);
+        assert_eq!(decode(0.0), 0.0);
+        assert_eq!(decode(1.0), 1.0);
+        assert!((decode(0.5) - 0.214_041).abs() < 1e-6);
+        // The linear and power pieces of the curve meet at 0.04045
+        let linear_piece = 0.04045 / 12.92;
+        let power_piece = ((0.04045_f64 + 0.055) / 1.055).powf(2.4);
+        assert_eq!(decode(0.04045), linear_piece);
+        assert!((power_piece - linear_piece).abs() < 1e-7);
+        assert_eq!(ColorSpace::Linear.decode_unit(0.5), 0.5);
+    }
+
+    #[test]
+    fn decoded_colors_encode_back_to_their_channels() {
+        for color_space in [ColorSpace::Srgb, ColorSpace::Linear] {
+            for channel in (0..=u8::MAX).step_by(5) {
+                let unit = channel as f64 / 255.0;
+                let decoded = LinearColor::decode((unit, unit, 1.0 - unit), color_space);
+                let encoded = decoded.to_color_with_alpha(color_space, 1.0);
+                assert_eq!(
+                    (encoded.red(), encoded.green(), encoded.blue()),
+                    (channel, channel, u8::MAX - channel)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn named_colors_can_be_found() {
+        assert!(NAMED_COLORS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(Color::named("rebeccapurple") == Some(Color::rgb(102, 51, 153)));
+        assert!(Color::named("white") == Some(WHITE));
+        assert!(Color::named("Crimson").is_none());
+    }
+
+    #[test]
+    fn hsv_hues_run_through_the_primaries() {
+        assert_eq!(hsv_to_rgb(0.0, 1.0, 1.0), (1.0, 0.0, 0.0));
+        assert_eq!(hsv_to_rgb(120.0, 1.0, 1.0), (0.0, 1.0, 0.0));
+        assert_eq!(hsv_to_rgb(240.0, 1.0, 0.5), (0.0, 0.0, 0.5));
+        assert_eq!(hsv_to_rgb(-60.0, 1.0, 1.0), (1.0, 0.0, 1.0));
+        assert_eq!(hsv_to_rgb(200.0, 0.0, 0.25), (0.25, 0.25, 0.25));
     }
 }
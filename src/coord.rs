@@ -1,4 +1,4 @@
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, Div, Mul, Neg, Sub};
 
 pub(crate) const ORIGIN: WorldCoordinate = WorldCoordinate {
     x: 0.0,
@@ -30,6 +30,49 @@ impl WorldCoordinate {
         (x * x + y * y + z * z).sqrt()
     }
 
+    pub(crate) fn cross(self, other: Self) -> Self {
+        WorldCoordinate::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    /**
+     * The unit vector pointing in the same direction as this one
+     */
+    pub(crate) fn normalize(self) -> Self {
+        self / self.abs()
+    }
+
+    /**
+     * Mirror this vector about `normal`, which must be a unit vector
+     */
+    pub(crate) fn reflect(self, normal: Self) -> Self {
+        normal * (2.0 * normal.dot(self)) - self
+    }
+
+    /**
+     * Rotate this vector by `angle` radians about `axis`, which must be a unit vector, turning
+     * anticlockwise when looking back along the axis
+     */
+    pub(crate) fn rotate(self, axis: Self, angle: f64) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        self * cos + axis.cross(self) * sin + axis * (axis.dot(self) * (1.0 - cos))
+    }
+
+    pub(crate) fn x(self) -> f64 {
+        self.x
+    }
+
+    pub(crate) fn y(self) -> f64 {
+        self.y
+    }
+
+    pub(crate) fn z(self) -> f64 {
+        self.z
+    }
+
     pub(crate) fn from_tuple(tuple: (f64, f64, f64)) -> Self {
         let (x, y, z) = tuple;
         Self { x, y, z }
@@ -69,6 +112,15 @@ impl Add<WorldCoordinate> for WorldCoordinate {
     }
 }
 
+impl Neg for WorldCoordinate {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        let Self { x, y, z } = self;
+        WorldCoordinate::new(-x, -y, -z)
+    }
+}
+
 impl Div<f64> for WorldCoordinate {
     type Output = Self;
 
@@ -0,0 +1,181 @@
+use super::error::{Diagnostic, Location};
+use super::parser::{Parser, SceneDefinition};
+use super::print::Printed;
+use std::path::Path;
+
+/**
+ * Formats that scene definitions can be written in besides scene files, laid out like the
+ * definition types, for tools that would rather generate data than .grfx
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum DataFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl DataFormat {
+    /**
+     * The format of a file with the given path's extension, if it isn't a scene file
+     */
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    /**
+     * Read a scene definition from `file`, then check it in the same way as a scene file, by
+     * writing it out as one and parsing that. Diagnostics give the field of `file` that each
+     * problem was found in. The definition is only returned if there were no errors.
+     */
+    pub(crate) fn parse(
+        self,
+        file: &str,
+        contents: &str,
+    ) -> Result<(Option<SceneDefinition>, Vec<Diagnostic>), String> {
+        let definition: SceneDefinition = match self {
+            Self::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            Self::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("Failed to read '{}': {}", file, e))?;
+
+        let printed = Printed::new(&definition);
+        let (definition, diagnostics) = Parser::new(file, &printed.source).parse();
+        let diagnostics = diagnostics
+            .into_iter()
+            .map(|diagnostic| match diagnostic.location {
+                Location::Source { line, .. } => Diagnostic {
+                    location: Location::Field(printed.field(line).into()),
+                    ..diagnostic
+                },
+                Location::Field(_) => diagnostic,
+            })
+            .collect();
+        Ok((definition, diagnostics))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> SceneDefinition {
+        let (definition, _) = Parser::new("scene.grfx", include_str!("../../scene.grfx")).parse();
+        definition.unwrap()
+    }
+
+    #[test]
+    fn the_example_scene_reads_back_from_every_format() {
+        let definition = example();
+        let written = [
+            (
+                DataFormat::Json,
+                serde_json::to_string(&definition).unwrap(),
+            ),
+            (DataFormat::Toml, toml::to_string(&definition).unwrap()),
+            (
+                DataFormat::Yaml,
+                serde_yaml::to_string(&definition).unwrap(),
+            ),
+        ];
+        for (format, contents) in written.iter() {
+            let (read, diagnostics) = format.parse("scene", contents).unwrap();
+            assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+            assert_eq!(read.as_ref(), Some(&definition), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn values_are_checked_as_in_scene_files() {
+        let json = r#"{
+            "spheres": [{
+                "material": {"inline": {"color": {"rgb": [1, 0, 0]}, "reflective": 2}},
+                "center": [0, 0, 3],
+                "radius": 1
+            }]
+        }"#;
+        let (read, diagnostics) = DataFormat::Json.parse("scene.json", json).unwrap();
+        assert_eq!(read, None);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "scene.json: spheres[0].material.inline.reflective: error: \
+             Expected reflective to be between 0 and 1 but got 2"
+        );
+    }
+
+    #[test]
+    fn problems_are_located_by_field() {
+        let toml = r#"
+            [[textures]]
+            name = "wood"
+            kind = {pattern = {pattern = "wood", primary = [0, 0, 0], secondary = [1, 1, 1]}}
+
+            [[textures]]
+            name = "wood"
+            kind = {image = {file = "wood.png"}}
+
+            [[materials]]
+            name = "oak"
+            material = {color = {texture = "wood"}, microfacet = [0, 2]}
+
+            [[spheres]]
+            material = {named = "oak"}
+            center = [0, 0, 3]
+            radius = 1
+
+            [[spheres]]
+            material = {named = "steel"}
+            center = [0, 0, 6]
+            radius = 1
+        "#;
+        let (_, diagnostics) = DataFormat::Toml.parse("scene.toml", toml).unwrap();
+        let fields: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| match &diagnostic.location {
+                Location::Field(field) => &field[..],
+                Location::Source { .. } => panic!("{}", diagnostic),
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "textures[1].name",
+                "materials[0].material.microfacet[1]",
+                "spheres[1].material.named"
+            ],
+            "{:?}",
+            diagnostics
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let yaml = "lights:\n- !point\n  intensity: 1\n  position: [0, 0, 0]\n  colour: red\n";
+        let error = DataFormat::Yaml.parse("scene.yaml", yaml).unwrap_err();
+        assert!(error.contains("unknown field `colour`"), "{}", error);
+    }
+
+    #[test]
+    fn formats_are_chosen_by_extension() {
+        assert_eq!(
+            DataFormat::of(Path::new("a/scene.json")),
+            Some(DataFormat::Json)
+        );
+        assert_eq!(
+            DataFormat::of(Path::new("scene.toml")),
+            Some(DataFormat::Toml)
+        );
+        assert_eq!(
+            DataFormat::of(Path::new("scene.yml")),
+            Some(DataFormat::Yaml)
+        );
+        assert_eq!(DataFormat::of(Path::new("scene.grfx")), None);
+        assert_eq!(DataFormat::of(Path::new("scene")), None);
+    }
+}
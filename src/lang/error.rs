@@ -0,0 +1,297 @@
+use std::fmt;
+use std::ops::Range;
+
+/**
+ * A range of byte offsets into a scene file
+ */
+pub(crate) type Span = Range<usize>;
+
+/**
+ * How serious a problem with a scene file is
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Severity {
+    /// The scene can't be loaded
+    Error,
+    /// The scene loads, but probably not as intended
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/**
+ * A problem with a scene file and the part of the source it was found in
+ */
+#[derive(Debug)]
+pub(crate) struct ParseError {
+    severity: Severity,
+    message: String,
+    span: Span,
+}
+
+impl ParseError {
+    pub(crate) fn new(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub(crate) fn warning(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::new(message, span)
+        }
+    }
+
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub(crate) fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /**
+     * Find the file, line and column of the error
+     */
+    pub(crate) fn locate(self, sources: &SourceMap) -> Diagnostic {
+        let file = sources.file_at(self.span.start);
+        let source = &file.source[..];
+        let span = (self.span.start - file.offset)..(self.span.end - file.offset);
+
+        // Errors at the end of the file point just after its last character rather than at an
+        // empty final line or a line break
+        let mut start = span.start.min(source.len());
+        if start == source.len() {
+            start = source.trim_end_matches(['\r', '\n']).len();
+        }
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let excerpt = source[line_start..line_end].trim_end_matches('\r');
+
+        let end = span.end.min(line_start + excerpt.len()).max(start);
+        Diagnostic {
+            severity: self.severity,
+            file: file.name.clone(),
+            location: Location::Source {
+                line: source[..line_start].matches('\n').count() + 1,
+                column: source[line_start..start].chars().count() + 1,
+                excerpt: excerpt.into(),
+                width: source[start..end].chars().count().max(1),
+            },
+            message: self.message,
+        }
+    }
+}
+
+/**
+ * The text of every file read while parsing a scene. Spans count bytes through all of the files in
+ * turn, so a span alone says which file it is in.
+ */
+#[derive(Default)]
+pub(crate) struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+struct SourceFile {
+    name: String,
+    source: String,
+    offset: usize,
+}
+
+impl SourceMap {
+    /**
+     * Where spans in the next file added will start
+     */
+    pub(crate) fn next_offset(&self) -> usize {
+        // Leave a gap so that the end of one file isn't the start of the next
+        self.files
+            .last()
+            .map_or(0, |file| file.offset + file.source.len() + 1)
+    }
+
+    pub(crate) fn add(&mut self, name: impl Into<String>, source: impl Into<String>) {
+        let offset = self.next_offset();
+        self.files.push(SourceFile {
+            name: name.into(),
+            source: source.into(),
+            offset,
+        });
+    }
+
+    fn file_at(&self, position: usize) -> &SourceFile {
+        self.files
+            .iter()
+            .rev()
+            .find(|file| file.offset <= position)
+            .expect("spans always lie within a file")
+    }
+}
+
+/**
+ * Attach a span to errors from the helpers that only know what went wrong, not where
+ */
+pub(crate) trait At<T> {
+    fn at(self, span: &Span) -> Result<T, ParseError>;
+}
+
+impl<T, E: Into<String>> At<T> for Result<T, E> {
+    fn at(self, span: &Span) -> Result<T, ParseError> {
+        self.map_err(|message| ParseError::new(message, span.clone()))
+    }
+}
+
+/**
+ * A problem with a scene file, located within the file
+ */
+#[derive(Debug)]
+pub(crate) struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub location: Location,
+    pub message: String,
+}
+
+/**
+ * Where in a file a problem is
+ */
+#[derive(Debug, PartialEq)]
+pub(crate) enum Location {
+    /// A line and column of a scene file, along with the line of source so that it can be shown
+    /// underlined
+    Source {
+        /// Counting from 1
+        line: usize,
+        /// Counting characters from 1
+        column: usize,
+        excerpt: String,
+        /// The number of characters underlined, starting at `column`
+        width: usize,
+    },
+    /// A field of a scene read from data, such as spheres[0].radius
+    Field(String),
+}
+
+impl fmt::Display for Diagnostic {
+    /**
+     * For example:
+     * scene.grfx:3:12: error: Expected = when parsing assignment but found '1'
+     *   |
+     * 3 |     radius 1
+     *   |            ^
+     *
+     * or, for a scene read from data:
+     * scene.json: spheres[0].material.inline.opacity: error: Expected opacity to be between 0 and
+     * 1 but got 3
+     */
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (line, column, excerpt, width) = match &self.location {
+            Location::Source {
+                line,
+                column,
+                excerpt,
+                width,
+            } => (line, column, excerpt, width),
+            Location::Field(field) => {
+                return write!(
+                    f,
+                    "{}: {}: {}: {}",
+                    self.file, field, self.severity, self.message
+                )
+            }
+        };
+        let gutter = " ".repeat(line.to_string().len());
+        writeln!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.file, line, column, self.severity, self.message
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line, excerpt)?;
+        // Tabs are kept so that the carets line up however wide the terminal shows them
+        let indent: String = excerpt
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(*width))
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locate(source: &str, span: Span) -> Diagnostic {
+        let mut sources = SourceMap::default();
+        sources.add("scene.grfx", source);
+        ParseError::new("problem", span).locate(&sources)
+    }
+
+    fn position(diagnostic: &Diagnostic) -> (usize, usize, usize) {
+        match diagnostic.location {
+            Location::Source {
+                line,
+                column,
+                width,
+                ..
+            } => (line, column, width),
+            Location::Field(_) => panic!("scene files have lines"),
+        }
+    }
+
+    fn excerpt(diagnostic: &Diagnostic) -> &str {
+        match &diagnostic.location {
+            Location::Source { excerpt, .. } => excerpt,
+            Location::Field(_) => panic!("scene files have lines"),
+        }
+    }
+
+    #[test]
+    fn errors_are_located_by_line_and_column() {
+        let diagnostic = locate("sphere {\n    radius 1\n}\n", 20..21);
+        assert_eq!(position(&diagnostic), (2, 12, 1));
+        assert_eq!(excerpt(&diagnostic), "    radius 1");
+    }
+
+    #[test]
+    fn the_end_of_the_file_is_after_its_last_line() {
+        let diagnostic = locate("sphere {\n  radius = 1\n", 23..23);
+        assert_eq!(position(&diagnostic), (2, 13, 1));
+        assert_eq!(excerpt(&diagnostic), "  radius = 1");
+    }
+
+    #[test]
+    fn files_ending_in_a_carriage_return() {
+        let diagnostic = locate("sphere {\r", 9..9);
+        assert_eq!(position(&diagnostic), (1, 9, 1));
+        assert_eq!(excerpt(&diagnostic), "sphere {");
+
+        let diagnostic = locate("sphere {\n  radius = 1\r", 22..22);
+        assert_eq!(position(&diagnostic), (2, 13, 1));
+        assert_eq!(excerpt(&diagnostic), "  radius = 1");
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let source = "sphere {\r\n  radius = 1\r\n";
+        assert_eq!(position(&locate(source, 24..24)), (2, 13, 1));
+        // The line break isn't underlined
+        let diagnostic = locate(source, 21..24);
+        assert_eq!(position(&diagnostic), (2, 12, 1));
+        assert_eq!(excerpt(&diagnostic), "  radius = 1");
+    }
+}
@@ -0,0 +1,332 @@
+use super::error::{ParseError, Span};
+use super::parser::Value;
+use crate::color::hsv_to_rgb;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+/**
+ * A value written in a scene file, which may need variables to be known before it can be worked out
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ExprKind {
+    Literal(Value),
+    /// A variable if one of that name has been bound, otherwise a word such as point or crimson
+    Name(String),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    /// Two to four numbers
+    Tuple(Vec<Expr>),
+    Array(Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    /// The remainder after dividing, which is never negative for a positive divisor
+    Remainder,
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Subtract => "-",
+            Self::Multiply => "*",
+            Self::Divide => "/",
+            Self::Remainder => "%",
+        }
+    }
+}
+
+/**
+ * Two numbers compared, as the condition of an if statement
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct Condition {
+    pub left: Expr,
+    pub op: CompareOp,
+    pub right: Expr,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CompareOp {
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+/**
+ * The result of evaluating an expression. An error of None means the expression used a variable
+ * whose own value had an error, which has already been reported.
+ */
+pub(crate) type Evaluated = Result<Value, Option<ParseError>>;
+
+/**
+ * The variables bound by let statements so far
+ */
+#[derive(Default, Clone)]
+pub(crate) struct Environment {
+    /// Variables whose values had errors are bound to None
+    variables: HashMap<String, Option<Value>>,
+}
+
+impl Environment {
+    pub(crate) fn bind(&mut self, name: String, value: Option<Value>) {
+        self.variables.insert(name, value);
+    }
+
+    pub(crate) fn evaluate(&self, expr: &Expr) -> Evaluated {
+        let error = |message: String| Err(Some(ParseError::new(message, expr.span.clone())));
+        match &expr.kind {
+            ExprKind::Literal(value) => Ok(value.clone()),
+            ExprKind::Name(name) => match self.variables.get(name) {
+                Some(Some(value)) => Ok(value.clone()),
+                Some(None) => Err(None),
+                None => Ok(Value::Identifier(name.clone())),
+            },
+            ExprKind::Negate(operand) => match self.operand(operand)? {
+                Value::Num(n) => Ok(Value::Num(-n)),
+                Value::Tuple(t) => Ok(Value::Tuple(t.iter().map(|n| -n).collect())),
+                value => error(format!("Can't negate {}", describe(&value))),
+            },
+            ExprKind::Binary(op, left, right) => {
+                let (left, right) = (self.operand(left)?, self.operand(right)?);
+                if let (BinaryOp::Divide | BinaryOp::Remainder, Value::Num(d)) = (op, &right) {
+                    if *d == 0.0 {
+                        return error("Division by zero".into());
+                    }
+                }
+                match binary(*op, &left, &right) {
+                    Some(value) => Ok(value),
+                    None => error(format!(
+                        "Can't work out {} {} {}",
+                        describe(&left),
+                        op.symbol(),
+                        describe(&right)
+                    )),
+                }
+            }
+            ExprKind::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.operand(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call(name, &args)
+                    .map_err(|message| Some(ParseError::new(message, expr.span.clone())))
+            }
+            ExprKind::Tuple(elements) => {
+                let number = |element: &Expr| match self.operand(element)? {
+                    Value::Num(n) => Ok(n),
+                    value => Err(Some(ParseError::new(
+                        format!(
+                            "Tuples can only contain numbers but got {}",
+                            describe(&value)
+                        ),
+                        element.span.clone(),
+                    ))),
+                };
+                Ok(Value::Tuple(
+                    elements.iter().map(number).collect::<Result<_, _>>()?,
+                ))
+            }
+            ExprKind::Array(elements) => Ok(Value::Array(
+                elements
+                    .iter()
+                    .map(|element| self.evaluate(element))
+                    .collect::<Result<_, _>>()?,
+            )),
+        }
+    }
+
+    /**
+     * Whether `condition` holds
+     */
+    pub(crate) fn test(&self, condition: &Condition) -> Result<bool, Option<ParseError>> {
+        match (
+            self.operand(&condition.left)?,
+            self.operand(&condition.right)?,
+        ) {
+            (Value::Num(a), Value::Num(b)) => Ok(match condition.op {
+                CompareOp::Less => a < b,
+                CompareOp::Greater => a > b,
+                CompareOp::LessEqual => a <= b,
+                CompareOp::GreaterEqual => a >= b,
+                CompareOp::Equal => a == b,
+                CompareOp::NotEqual => a != b,
+            }),
+            (a, b) => Err(Some(ParseError::new(
+                format!(
+                    "Conditions can only compare numbers, but got {} and {}",
+                    describe(&a),
+                    describe(&b)
+                ),
+                condition.span.clone(),
+            ))),
+        }
+    }
+
+    /**
+     * Evaluate an expression whose value is used in a calculation, where a name has to be a
+     * variable
+     */
+    fn operand(&self, expr: &Expr) -> Evaluated {
+        match self.evaluate(expr)? {
+            Value::Identifier(name) => Err(Some(ParseError::new(
+                format!("Unknown variable '{}'", name),
+                expr.span.clone(),
+            ))),
+            value => Ok(value),
+        }
+    }
+}
+
+/**
+ * Arithmetic on numbers, and vector math on tuples. None if the operation doesn't apply.
+ */
+fn binary(op: BinaryOp, left: &Value, right: &Value) -> Option<Value> {
+    let apply = |a: f64, b: f64| match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide => a / b,
+        BinaryOp::Remainder => a.rem_euclid(b),
+    };
+    let value = match (left, right) {
+        (Value::Num(a), Value::Num(b)) => Value::Num(apply(*a, *b)),
+        // Tuples combine component by component
+        (Value::Tuple(_), _) | (_, Value::Tuple(_)) if matches!(op, BinaryOp::Remainder) => {
+            return None
+        }
+        (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => {
+            Value::Tuple(a.iter().zip(b).map(|(a, b)| apply(*a, *b)).collect())
+        }
+        (Value::Tuple(a), Value::Num(b)) => match op {
+            BinaryOp::Multiply | BinaryOp::Divide => {
+                Value::Tuple(a.iter().map(|a| apply(*a, *b)).collect())
+            }
+            _ => return None,
+        },
+        (Value::Num(a), Value::Tuple(b)) => match op {
+            BinaryOp::Multiply => Value::Tuple(b.iter().map(|b| a * b).collect()),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+/**
+ * The built-in functions. Angles are in radians; deg converts from degrees.
+ */
+fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+    let numbers = |count: usize| -> Result<Vec<f64>, String> {
+        if args.len() != count {
+            return Err(format!(
+                "{} takes {} argument{} but got {}",
+                name,
+                count,
+                if count == 1 { "" } else { "s" },
+                args.len()
+            ));
+        }
+        args.iter()
+            .map(|arg| match arg {
+                Value::Num(n) => Ok(*n),
+                value => Err(format!(
+                    "Expected the arguments of {} to be numbers but got {}",
+                    name,
+                    describe(value)
+                )),
+            })
+            .collect()
+    };
+    let unit = |channel: f64| {
+        if (0.0..=1.0).contains(&channel) {
+            Ok(channel)
+        } else {
+            Err(format!(
+                "Expected the arguments of {} to be between 0 and 1 but got {}",
+                name, channel
+            ))
+        }
+    };
+
+    match name {
+        "sin" => Ok(Value::Num(numbers(1)?[0].sin())),
+        "cos" => Ok(Value::Num(numbers(1)?[0].cos())),
+        "sqrt" => match numbers(1)?[0] {
+            n if n < 0.0 => Err(format!("Can't take the square root of {}", n)),
+            n => Ok(Value::Num(n.sqrt())),
+        },
+        "deg" => Ok(Value::Num(numbers(1)?[0] * PI / 180.0)),
+        "normalize" => match args {
+            [Value::Tuple(t)] => {
+                let length = t.iter().map(|n| n * n).sum::<f64>().sqrt();
+                if length == 0.0 {
+                    return Err(format!("Can't normalize {}", describe(&args[0])));
+                }
+                Ok(Value::Tuple(t.iter().map(|n| n / length).collect()))
+            }
+            _ => Err("normalize takes a single tuple".into()),
+        },
+        "len" => match args {
+            [Value::Array(elements)] => Ok(Value::Num(elements.len() as f64)),
+            [Value::Tuple(t)] => Ok(Value::Num(t.len() as f64)),
+            _ => Err("len takes a single array or tuple".into()),
+        },
+        "hsv" => {
+            let hsv = numbers(3)?;
+            if !(0.0..=360.0).contains(&hsv[0]) {
+                return Err(format!(
+                    "Expected a hue between 0 and 360 degrees but got {}",
+                    hsv[0]
+                ));
+            }
+            Ok(Value::Color(hsv_to_rgb(
+                hsv[0],
+                unit(hsv[1])?,
+                unit(hsv[2])?,
+            )))
+        }
+        "rgbf" => {
+            let rgb = numbers(3)?;
+            Ok(Value::Color((unit(rgb[0])?, unit(rgb[1])?, unit(rgb[2])?)))
+        }
+        _ => Err(format!(
+            "Unknown function '{}'; expected one of \
+             [sin, cos, sqrt, deg, normalize, len, hsv, rgbf]",
+            name
+        )),
+    }
+}
+
+pub(crate) fn describe(value: &Value) -> String {
+    match value {
+        Value::Num(n) => format!("the number {}", n),
+        Value::VString(s) => format!("the string \"{}\"", s),
+        Value::Identifier(name) => format!("'{}'", name),
+        Value::Tuple(t) => format!(
+            "the tuple ({})",
+            t.iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Array(elements) => format!("an array of {} values", elements.len()),
+        Value::Color(_) => "a color".into(),
+    }
+}
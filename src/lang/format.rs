@@ -0,0 +1,353 @@
+use super::lexer::Token;
+use super::syntax::{comment, Block, SyntaxNode, SyntaxToken, SyntaxTree};
+
+const INDENT: &str = "    ";
+
+/**
+ * Lay out a scene file in the canonical style: four spaces of indentation per block, single
+ * spaces around = and operators, tuples written as (x, y, z), one blank line after each top-level
+ * block and no more than one blank line in a row anywhere else. Comments are kept, two spaces after
+ * the code on their line if they follow any.
+ *
+ * The file should be free of syntax errors, so that the result means the same thing.
+ */
+pub(crate) fn format(source: &str) -> String {
+    let tree = SyntaxTree::parse(source);
+    debug_assert_eq!(
+        tree.to_string(),
+        source,
+        "the syntax tree should keep all of the source"
+    );
+
+    let mut writer = Writer {
+        out: String::new(),
+        indent: 0,
+        in_line: false,
+        blank: false,
+        block_start: true,
+    };
+    writer.nodes(&tree.nodes);
+    writer.comment(comment(&tree.trailing));
+    writer.end_line();
+    writer.out
+}
+
+struct Writer {
+    out: String,
+    indent: usize,
+    /// Whether the current line has been started
+    in_line: bool,
+    /// Whether a blank line should come before the next line
+    blank: bool,
+    /// Whether nothing has been written since the start of the file or a block, where blank lines
+    /// are left out
+    block_start: bool,
+}
+
+impl Writer {
+    fn start_line(&mut self) {
+        self.end_line();
+        if self.blank && !self.block_start {
+            self.out.push('\n');
+        }
+        self.blank = false;
+        self.block_start = false;
+        self.out.push_str(&INDENT.repeat(self.indent));
+        self.in_line = true;
+    }
+
+    fn end_line(&mut self) {
+        if self.in_line {
+            self.out.push('\n');
+            self.in_line = false;
+        }
+    }
+
+    /**
+     * A comment after whatever is on the current line, or on a line of its own
+     */
+    fn comment(&mut self, comment: Option<&str>) {
+        if let Some(comment) = comment {
+            if self.in_line {
+                self.out.push_str("  ");
+            } else {
+                self.start_line();
+            }
+            self.out.push_str(comment);
+        }
+    }
+
+    fn new_line(&mut self, token: &SyntaxToken) {
+        self.comment(token.comment());
+        self.end_line();
+        if token.line_breaks() > 1 {
+            self.blank = true;
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[SyntaxNode]) {
+        // An else follows the closing brace of its if on the same line
+        let mut after_block = false;
+        for node in nodes {
+            match node {
+                SyntaxNode::NewLine(token) => {
+                    self.new_line(token);
+                    after_block = false;
+                    continue;
+                }
+                _ if after_block => self.out.push(' '),
+                _ => self.start_line(),
+            }
+            match node {
+                SyntaxNode::Line(tokens) => self.tokens(tokens),
+                SyntaxNode::Block(block) => {
+                    self.block(block);
+                    after_block = true;
+                    // Top-level definitions and statements are set apart by a blank line
+                    if self.indent == 0 {
+                        self.blank = true;
+                    }
+                }
+                SyntaxNode::NewLine(_) => unreachable!(),
+            }
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        if !block.header.is_empty() {
+            self.tokens(&block.header);
+            self.out.push(' ');
+        }
+        self.out.push('{');
+        self.block_start = true;
+        self.indent += 1;
+        self.nodes(&block.body);
+        self.indent -= 1;
+        self.blank = false;
+        if block.close.is_some() {
+            self.start_line();
+            self.out.push('}');
+        }
+    }
+
+    /**
+     * Tokens on one line, except for arrays that were written over several lines, which are kept
+     * that way with one element per line
+     */
+    fn tokens(&mut self, tokens: &[SyntaxToken]) {
+        let mut before: Option<&Token> = None;
+        let mut previous: Option<&Token> = None;
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i].token;
+            if let Some(previous) = previous {
+                if spaced(before, previous, token) {
+                    self.out.push(' ');
+                }
+            }
+            if *token == Token::LBracket {
+                let end = closing_bracket(tokens, i);
+                let inside = &tokens[i + 1..end];
+                if inside.iter().any(|t| t.token == Token::NewLine)
+                    && inside
+                        .iter()
+                        .any(|t| t.token != Token::NewLine || t.comment().is_some())
+                {
+                    self.array(inside);
+                    before = Some(&Token::LBracket);
+                    previous = tokens.get(end).map(|t| &t.token);
+                    i = end + 1;
+                    continue;
+                }
+            }
+            if *token != Token::NewLine {
+                self.out.push_str(&tokens[i].text);
+                before = previous;
+                previous = Some(token);
+            }
+            i += 1;
+        }
+    }
+
+    /**
+     * The elements of an array, one to a line with a comma after each
+     */
+    fn array(&mut self, inside: &[SyntaxToken]) {
+        self.out.push('[');
+        self.block_start = true;
+        self.indent += 1;
+        let mut start = 0;
+        let mut depth = 0;
+        for (i, token) in inside.iter().enumerate() {
+            match token.token {
+                Token::LParen | Token::LBracket => depth += 1,
+                Token::RParen | Token::RBracket => depth -= 1,
+                Token::Comma | Token::NewLine if depth == 0 => {
+                    self.element(&inside[start..i]);
+                    start = i + 1;
+                    if token.token == Token::NewLine {
+                        self.comment(token.comment());
+                        self.end_line();
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.element(&inside[start..]);
+        self.indent -= 1;
+        self.blank = false;
+        self.start_line();
+        self.out.push(']');
+    }
+
+    fn element(&mut self, tokens: &[SyntaxToken]) {
+        if !tokens.is_empty() {
+            self.start_line();
+            self.tokens(tokens);
+            self.out.push(',');
+        }
+    }
+}
+
+/**
+ * The index of the bracket closing the one at `open`
+ */
+fn closing_bracket(tokens: &[SyntaxToken], open: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.token {
+            Token::LBracket => depth += 1,
+            Token::RBracket if depth == 1 => return i,
+            Token::RBracket => depth -= 1,
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/**
+ * Whether a space goes between `previous` and `next`, where `before` came before `previous`
+ */
+fn spaced(before: Option<&Token>, previous: &Token, next: &Token) -> bool {
+    match (previous, next) {
+        (_, Token::RParen | Token::RBracket | Token::Comma) => false,
+        (Token::LParen | Token::LBracket, _) => false,
+        (Token::DotDot, _) | (_, Token::DotDot) => false,
+        // A function call, unlike if (a + b) < c
+        (Token::Identifier(word), Token::LParen) => is_keyword(word),
+        (Token::Minus, _) => !is_negation(before),
+        _ => true,
+    }
+}
+
+/**
+ * Whether a minus after `before` negates what follows rather than subtracting it
+ */
+fn is_negation(before: Option<&Token>) -> bool {
+    match before {
+        None => true,
+        Some(Token::Identifier(word)) => is_keyword(word),
+        Some(
+            Token::Number(_)
+            | Token::VString(_)
+            | Token::HexColor(_)
+            | Token::RParen
+            | Token::RBracket,
+        ) => false,
+        Some(_) => true,
+    }
+}
+
+fn is_keyword(word: &str) -> bool {
+    matches!(word, "let" | "include" | "for" | "in" | "if" | "else")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spacing_and_indentation() {
+        assert_eq!(
+            format("sphere {\ncenter=(0,-1,3)\n  radius=2*-1\n}\n"),
+            "sphere {\n    center = (0, -1, 3)\n    radius = 2 * -1\n}\n"
+        );
+    }
+
+    #[test]
+    fn trailing_comments_follow_two_spaces() {
+        assert_eq!(
+            format("let a = 1    # one\nlet b = 2# two\n"),
+            "let a = 1  # one\nlet b = 2  # two\n"
+        );
+    }
+
+    #[test]
+    fn comment_only_lines_are_indented() {
+        assert_eq!(
+            format("# scene\nsphere {\n# size\n        radius = 1\n  // done\n}\n"),
+            "# scene\nsphere {\n    # size\n    radius = 1\n    // done\n}\n"
+        );
+    }
+
+    #[test]
+    fn runs_of_blank_lines_become_one() {
+        assert_eq!(
+            format("let a = 1\n\n\n\nlet b = 2\n"),
+            "let a = 1\n\nlet b = 2\n"
+        );
+    }
+
+    #[test]
+    fn blank_lines_can_hold_spaces_and_tabs() {
+        assert_eq!(
+            format("let a = 1\n  \n\t\nlet b = 2\r\n \r\nlet c = 3\n"),
+            "let a = 1\n\nlet b = 2\n\nlet c = 3\n"
+        );
+    }
+
+    #[test]
+    fn blank_lines_at_the_start_and_end_of_blocks_are_dropped() {
+        assert_eq!(
+            format("\n\nsphere {\n\n    radius = 1\n\n}\n\n\n"),
+            "sphere {\n    radius = 1\n}\n"
+        );
+    }
+
+    #[test]
+    fn top_level_blocks_are_set_apart_by_one_blank_line() {
+        assert_eq!(
+            format("sphere {\n    radius = 1\n}\n# lights\nlight {\n    intensity = 1\n}\n\n\n\nlet a = 1\n"),
+            "sphere {\n    radius = 1\n}\n\n# lights\nlight {\n    intensity = 1\n}\n\nlet a = 1\n"
+        );
+    }
+
+    #[test]
+    fn arrays_over_several_lines_keep_one_element_per_line() {
+        assert_eq!(
+            format("let a = [1,\n  (2,3), # pair\n4]\n"),
+            "let a = [\n    1,\n    (2, 3),  # pair\n    4,\n]\n"
+        );
+        assert_eq!(format("let a = [1,2,3]\n"), "let a = [1, 2, 3]\n");
+    }
+
+    #[test]
+    fn else_follows_the_closing_brace_of_its_if() {
+        assert_eq!(
+            format("if 1<2 {\nlet a = 1\n}   else{\nlet a = 2\n}\nlet b = 3\n"),
+            "if 1 < 2 {\n    let a = 1\n} else {\n    let a = 2\n}\n\nlet b = 3\n"
+        );
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing_more() {
+        let sources = [
+            include_str!("../../scene.grfx"),
+            "# a\nlet n=3 # count\n\n \nfor i in 0..n {\nif i%2==0 {\nsphere { center=(i,0,5)\n\nradius=.5\n}\n} else {\nlet c = [\n#f00, # red\n#0f0\n]\n}\n}\n# end",
+        ];
+        for source in sources.iter() {
+            let once = format(source);
+            assert_eq!(format(&once), once);
+        }
+    }
+}
@@ -1,26 +1,61 @@
 use logos::Logos;
+use std::ops::Range;
 
 #[derive(Logos, Debug, PartialEq)]
 pub(crate) enum Token {
-    #[regex("[a-zA-Z]+", |lex| String::from(lex.slice()))]
+    #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| String::from(lex.slice()))]
     Identifier(String),
-    #[regex("-?[0-9]+(.[0-9]+)?", |lex| lex.slice().parse())]
+    /// Such as 2, 0.5, .5 or 1e-3. A trailing dot isn't allowed, so that 0..10 is a range.
+    #[regex(r"([0-9]+(\.[0-9]+)?|\.[0-9]+)([eE][+-]?[0-9]+)?", |lex| lex.slice().parse())]
     Number(f64),
 
-    #[regex(r#""[^"]*""#, |lex| String::from(lex.slice()))]
+    /// Still quoted, with any escapes left in; see `unescape`
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| String::from(lex.slice()))]
     VString(String),
 
-    #[regex("[\r\n]+")]
-    #[regex(r"#[^\n]*\n")]
+    /// A color written as #rrggbb or #rgb
+    #[regex("#[0-9a-fA-F]+", |lex| String::from(&lex.slice()[1..]))]
+    HexColor(String),
+
+    /// One or more line breaks, along with the spaces on any blank lines between them
+    #[regex(r"[\r\n]([ \t\f]*[\r\n])*")]
     NewLine,
     #[token("=")]
     Equal,
     #[token(",")]
     Comma,
+    #[token("+")]
+    Plus,
+    #[token("-")]
+    Minus,
+    #[token("*")]
+    Star,
+    #[token("/")]
+    Slash,
+    #[token("%")]
+    Percent,
+    #[token("..")]
+    DotDot,
+    #[token("<")]
+    Less,
+    #[token(">")]
+    Greater,
+    #[token("<=")]
+    LessEqual,
+    #[token(">=")]
+    GreaterEqual,
+    #[token("==")]
+    EqualEqual,
+    #[token("!=")]
+    NotEqual,
     #[token("(")]
     LParen,
     #[token(")")]
     RParen,
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
     #[token("{")]
     LBrace,
     #[token("}")]
@@ -28,5 +63,283 @@ pub(crate) enum Token {
 
     #[error]
     #[regex(r"[ \t\f]", logos::skip)]
+    // Comments starting with hex digits are lexed as colors at first, and skipped by `lex`
+    #[regex(r"#([^0-9a-fA-F\r\n][^\r\n]*)?", logos::skip)]
+    #[regex(r"//[^\r\n]*", logos::skip)]
     Error,
 }
+
+/**
+ * The tokens of `source` and their spans. A # followed by hex digits is a color where a value is
+ * expected, after an =, a comma, an operator or an opening bracket, including at the start of a
+ * line inside an array, and anywhere else starts a comment, so that comments such as
+ * "#add a light" aren't mistaken for colors.
+ */
+pub(crate) fn lex(source: &str) -> Vec<(Token, Range<usize>)> {
+    let mut lexer = Token::lexer(source);
+    let mut tokens: Vec<(Token, Range<usize>)> = vec![];
+    let mut depth = 0usize;
+    while let Some(token) = lexer.next() {
+        if let Token::HexColor(_) = token {
+            // Arrays can span lines, so inside brackets look back past line breaks
+            let previous = tokens
+                .iter()
+                .rev()
+                .map(|(token, _)| token)
+                .find(|token| depth == 0 || **token != Token::NewLine);
+            if !previous.is_some_and(expects_value) {
+                // The rest of the line is a comment, leaving the line break to end it
+                let rest = lexer.remainder();
+                lexer.bump(rest.find(&['\r', '\n'][..]).unwrap_or(rest.len()));
+                continue;
+            }
+        }
+        match token {
+            Token::LBracket => depth += 1,
+            Token::RBracket => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        tokens.push((token, lexer.span()));
+    }
+    tokens
+}
+
+/**
+ * Whether a value can follow `token`
+ */
+fn expects_value(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Equal
+            | Token::Comma
+            | Token::Plus
+            | Token::Minus
+            | Token::Star
+            | Token::Slash
+            | Token::Percent
+            | Token::DotDot
+            | Token::Less
+            | Token::Greater
+            | Token::LessEqual
+            | Token::GreaterEqual
+            | Token::EqualEqual
+            | Token::NotEqual
+            | Token::LParen
+            | Token::LBracket
+    )
+}
+
+/**
+ * The text of a string token without its quotes, with the escapes \", \\, \n and \t replaced
+ */
+pub(crate) fn unescape(quoted: &str) -> Result<String, String> {
+    let mut text = String::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => text.push('"'),
+            Some('\\') => text.push('\\'),
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some(other) => {
+                return Err(format!(
+                    "Unknown escape '\\{}' in string; expected one of [\\\", \\\\, \\n, \\t]",
+                    other
+                ))
+            }
+            None => unreachable!("the lexer only matches strings with complete escapes"),
+        }
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        lex(source).into_iter().map(|(token, _)| token).collect()
+    }
+
+    fn identifier(name: &str) -> Token {
+        Token::Identifier(name.into())
+    }
+
+    #[test]
+    fn hex_colors_are_lexed_where_a_value_is_expected() {
+        assert_eq!(
+            tokens("c = #f80\nt = hsv(#fface0, #abc)"),
+            vec![
+                identifier("c"),
+                Token::Equal,
+                Token::HexColor("f80".into()),
+                Token::NewLine,
+                identifier("t"),
+                Token::Equal,
+                identifier("hsv"),
+                Token::LParen,
+                Token::HexColor("fface0".into()),
+                Token::Comma,
+                Token::HexColor("abc".into()),
+                Token::RParen
+            ]
+        );
+    }
+
+    #[test]
+    fn hex_colors_can_start_the_lines_of_an_array() {
+        assert_eq!(
+            tokens("[\n#f00,\n#0f0\n]"),
+            vec![
+                Token::LBracket,
+                Token::NewLine,
+                Token::HexColor("f00".into()),
+                Token::Comma,
+                Token::NewLine,
+                Token::HexColor("0f0".into()),
+                Token::NewLine,
+                Token::RBracket
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_starting_with_hex_digits_are_comments() {
+        assert_eq!(tokens("#add a comment\n"), vec![Token::NewLine]);
+        assert_eq!(
+            tokens("light {\n#face the sphere\n}"),
+            vec![
+                identifier("light"),
+                Token::LBrace,
+                Token::NewLine,
+                Token::NewLine,
+                Token::RBrace
+            ]
+        );
+        assert_eq!(
+            tokens("radius = 1 #1st light"),
+            vec![identifier("radius"), Token::Equal, Token::Number(1.0)]
+        );
+    }
+
+    #[test]
+    fn identifiers_can_contain_digits_and_underscores() {
+        assert_eq!(
+            tokens("max_depth light2 _hidden"),
+            vec![
+                identifier("max_depth"),
+                identifier("light2"),
+                identifier("_hidden")
+            ]
+        );
+    }
+
+    #[test]
+    fn identifiers_cannot_start_with_a_digit() {
+        assert_eq!(tokens("2nd"), vec![Token::Number(2.0), identifier("nd")]);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(
+            tokens("7 0.25 .5"),
+            vec![Token::Number(7.0), Token::Number(0.25), Token::Number(0.5)]
+        );
+    }
+
+    #[test]
+    fn scientific_notation() {
+        assert_eq!(
+            tokens("1e-3 2.5E2 .5e+1"),
+            vec![
+                Token::Number(0.001),
+                Token::Number(250.0),
+                Token::Number(5.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_numbers_are_a_minus_and_a_number() {
+        assert_eq!(tokens("-1.5"), vec![Token::Minus, Token::Number(1.5)]);
+    }
+
+    #[test]
+    fn ranges_are_not_decimals() {
+        assert_eq!(
+            tokens("0..10"),
+            vec![Token::Number(0.0), Token::DotDot, Token::Number(10.0)]
+        );
+        assert_eq!(
+            tokens("0...5"),
+            vec![Token::Number(0.0), Token::DotDot, Token::Number(0.5)]
+        );
+    }
+
+    #[test]
+    fn strings_keep_their_escapes_until_unescaped() {
+        let source = r#""say \"hi\"\n""#;
+        assert_eq!(tokens(source), vec![Token::VString(source.into())]);
+        assert_eq!(unescape(source), Ok("say \"hi\"\n".into()));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape(r#""a\\b\tc""#), Ok("a\\b\tc".into()));
+        assert!(unescape(r#""\q""#).is_err());
+    }
+
+    #[test]
+    fn an_escaped_quote_does_not_end_a_string() {
+        assert_eq!(
+            tokens(r#""a\"" b"#),
+            vec![Token::VString(r#""a\"""#.into()), identifier("b")]
+        );
+    }
+
+    #[test]
+    fn blank_lines_are_part_of_one_newline() {
+        let source = "a\n  \n\t\r\nb";
+        assert_eq!(
+            lex(source),
+            vec![
+                (identifier("a"), 0..1),
+                (Token::NewLine, 1..8),
+                (identifier("b"), 8..9)
+            ]
+        );
+    }
+
+    #[test]
+    fn hash_comments_are_skipped() {
+        assert_eq!(
+            tokens("a # a comment\nb"),
+            vec![identifier("a"), Token::NewLine, identifier("b")]
+        );
+    }
+
+    #[test]
+    fn slash_comments_are_skipped() {
+        assert_eq!(
+            tokens("a // a comment\nb / c"),
+            vec![
+                identifier("a"),
+                Token::NewLine,
+                identifier("b"),
+                Token::Slash,
+                identifier("c")
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_at_the_end_of_the_file_need_no_newline() {
+        assert_eq!(tokens("a # the end"), vec![identifier("a")]);
+        assert_eq!(tokens("a // the end"), vec![identifier("a")]);
+        assert_eq!(tokens("a #"), vec![identifier("a")]);
+    }
+}
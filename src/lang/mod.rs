@@ -1,2 +1,8 @@
+pub(crate) mod data;
+pub(crate) mod error;
+pub(crate) mod expr;
+pub(crate) mod format;
 pub(crate) mod lexer;
 pub(crate) mod parser;
+pub(crate) mod print;
+pub(crate) mod syntax;
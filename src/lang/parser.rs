@@ -1,58 +1,339 @@
-use super::lexer::Token;
-use logos::Logos;
+use super::error::{At, Diagnostic, ParseError, SourceMap, Span};
+use super::expr::{describe, BinaryOp, CompareOp, Condition, Environment, Expr, ExprKind};
+use super::lexer::{lex, unescape, Token};
+use crate::color::{Color, ColorSpace};
+use crate::coord::WorldCoordinate;
+use crate::raster::{Filter, Wrap};
+use crate::scene::texture::Pattern;
+use crate::scene::Integrator;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 /**
  * Grammar:
- * scene := definition +
- * definition := identifier "{"
- *  assignment+
+ * scene := (statement | include) +
+ * statement := definition | let | for | if
+ * let := "let" identifier "=" expression
+ * include := "include" string
+ * for := "for" identifier "in" expression [".." expression] body
+ * if := "if" condition body ["else" (if | body)]
+ * condition := expression ("<" | ">" | "<=" | ">=" | "==" | "!=") expression
+ * body := "{" statement* "}"
+ * definition := identifier [string] "{"
+ *  (assignment | block)+
  * "}"
- * assignment = identifier "=" value
- * identifier = [a-zA-Z]+
- * value = f64 | String | (f64, f64, f64)
+ * block := identifier "{" (assignment | block)+ "}"
+ * assignment = identifier "=" expression
+ * identifier = [a-zA-Z_][a-zA-Z0-9_]*
+ * expression = term (("+" | "-") term)*
+ * term = unary (("*" | "/" | "%") unary)*
+ * unary = "-" unary | value
+ * value = number | string | identifier | call | "(" expression ")" | tuple | array | color
+ * call = identifier "(" [expression ("," expression)*] ")"
+ * tuple = "(" expression ("," expression){1,3} ")"
+ * array = "[" [expression ("," expression)* [","]] "]"
+ * color = #rrggbb | #rgb
+ * number = ([0-9]+ ["." [0-9]+] | "." [0-9]+) [("e" | "E") ["+" | "-"] [0-9]+]
+ * string = "\"" ([^"\\] | "\\" ("\"" | "\\" | "n" | "t"))* "\""
+ * comment = ("#" | "//") [^\n]*
+ *
+ * Arrays are only looped over by for; no property takes one.
  */
-
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub(crate) struct SceneDefinition {
     pub window: Option<WindowDefinition>,
+    pub render: Option<RenderDefinition>,
+    pub camera: Option<CameraDefinition>,
+    pub background: Option<BackgroundDefinition>,
+    pub post: Option<Vec<PassDefinition>>,
+    pub fog: Option<FogDefinition>,
+    pub textures: Vec<TextureDefinition>,
+    pub materials: Vec<NamedMaterialDefinition>,
     pub lights: Vec<LightDefinition>,
     pub spheres: Vec<SphereDefinition>,
 }
 
+/**
+ * The types of definition a scene can contain
+ */
+const DEFINITION_TYPES: &[&str] = &[
+    "window",
+    "render",
+    "camera",
+    "background",
+    "post",
+    "fog",
+    "light",
+    "material",
+    "texture",
+    "sphere",
+];
+
+/**
+ * The range of atmospheric turbidity the sky model is fitted for
+ */
+const MIN_TURBIDITY: f64 = 1.7;
+const MAX_TURBIDITY: f64 = 10.0;
+
+/**
+ * The roughness of materials that set metallic but not roughness
+ */
+const DEFAULT_ROUGHNESS: f64 = 0.5;
+
 impl SceneDefinition {
-    fn from_raw(raw: Vec<RawDefinition>) -> Result<Self, String> {
+    /**
+     * Check and convert the parsed definitions, adding any problems found to `problems`.
+     * `unparsed` names the definitions that had syntax errors, which may still be referred to.
+     * Spans from `root_end` on are in included files.
+     * The definition returned leaves out anything with errors, so it is only complete if no errors
+     * were found.
+     */
+    fn from_raw(
+        raw: Vec<RawDefinition>,
+        unparsed: &[String],
+        root_end: usize,
+        problems: &mut Vec<ParseError>,
+    ) -> Self {
         let mut window = None;
+        let mut render = None;
+        let mut camera = None;
+        let mut background = None;
+        let mut post = None;
+        let mut fog = None;
+        let mut textures: Vec<TextureDefinition> = vec![];
+        let mut materials: Vec<NamedMaterialDefinition> = vec![];
         let mut lights = vec![];
         let mut spheres = vec![];
+        let mut defined: Vec<(String, String, Span)> = vec![];
+        let mut material_references = vec![];
+        let mut texture_references = vec![];
+        let mut singletons: Vec<String> = vec![];
+        for defn in raw.iter() {
+            Self::check_repeated_properties(defn, problems);
+            if let Some(name) = &defn.name {
+                if defined
+                    .iter()
+                    .any(|(n, t, _)| n == name && *t == defn.def_type)
+                {
+                    problems.push(ParseError::new(
+                        format!("Duplicate {} definition: '{}'", defn.def_type, name),
+                        defn.span.clone(),
+                    ));
+                }
+                defined.push((name.clone(), defn.def_type.clone(), defn.span.clone()));
+            }
+            Self::find_references(defn, &mut material_references, &mut texture_references);
+        }
+
         for defn in raw {
-            match &defn.def_type[..] {
-                "window" => window = Some(Self::window_from_raw(defn)?),
-                "light" => lights.push(Self::light_from_raw(defn)?),
-                "sphere" => spheres.push(Self::sphere_from_raw(defn)?),
-                t => return Err(format!("Unsupported definition type: {}", t)),
+            let span = defn.span.clone();
+            if defn.name.is_some() && defn.def_type != "material" && defn.def_type != "texture" {
+                problems.push(ParseError::new(
+                    format!(
+                        "Only material and texture definitions can be named, but got a named {}",
+                        defn.def_type
+                    ),
+                    span,
+                ));
+                continue;
+            }
+            if let "window" | "render" | "camera" | "background" | "post" | "fog" =
+                &defn.def_type[..]
+            {
+                if singletons.contains(&defn.def_type) {
+                    problems.push(ParseError::warning(
+                        format!("Only the last {} definition is used", defn.def_type),
+                        span.clone(),
+                    ));
+                }
+                singletons.push(defn.def_type.clone());
+            }
+            let allowed: &[&str] = match &defn.def_type[..] {
+                "sphere" => &["transform"],
+                _ => &[],
+            };
+            if let Some(block) = defn
+                .blocks
+                .iter()
+                .find(|block| !allowed.contains(&&block.def_type[..]))
+            {
+                problems.push(Self::unknown_block(&defn.def_type, allowed, block));
+                continue;
+            }
+            let result = match &defn.def_type[..] {
+                "window" => Self::window_from_raw(defn).map(|d| window = Some(d)),
+                "render" => Self::render_from_raw(defn).map(|d| render = Some(d)),
+                "camera" => Self::camera_from_raw(defn).map(|d| camera = Some(d)),
+                "background" => Self::background_from_raw(defn).map(|d| background = Some(d)),
+                "post" => Self::post_from_raw(defn).map(|d| post = Some(d)),
+                "fog" => Self::fog_from_raw(defn).map(|d| fog = Some(d)),
+                "light" => Self::light_from_raw(defn).map(|d| lights.push(d)),
+                "material" => Self::material_from_raw(defn).map(|d| materials.push(d)),
+                "texture" => Self::texture_from_raw(defn).map(|d| textures.push(d)),
+                "sphere" => Self::sphere_from_raw(defn).map(|d| spheres.push(d)),
+                t => Err(ParseError::new(
+                    format!("Unsupported definition type: {}", t),
+                    span,
+                )),
+            };
+            if let Err(problem) = result {
+                problems.push(problem);
+            }
+        }
+
+        // Materials and textures may be used before they are defined, so references can only be
+        // checked at the end
+        let is_defined = |name: &str, def_type: &str| {
+            defined.iter().any(|(n, t, _)| n == name && *t == def_type)
+                || unparsed.iter().any(|n| n == name)
+        };
+        for (name, span) in material_references.iter() {
+            if !is_defined(name, "material") {
+                problems.push(ParseError::new(
+                    format!("Undefined material: '{}'", name),
+                    span.clone(),
+                ));
+            }
+        }
+        for (name, span) in texture_references.iter() {
+            if !is_defined(name, "texture") {
+                problems.push(ParseError::new(
+                    format!("Undefined texture: '{}'", name),
+                    span.clone(),
+                ));
+            }
+        }
+        // Included files are often libraries of materials and textures, most of which any one scene
+        // leaves unused
+        let in_root = defined.iter().filter(|(_, _, span)| span.start < root_end);
+        for (name, def_type, span) in in_root {
+            let references = match &def_type[..] {
+                "material" => &material_references,
+                "texture" => &texture_references,
+                _ => continue,
+            };
+            if !references.iter().any(|(n, _)| n == name) {
+                problems.push(ParseError::warning(
+                    format!("The {} '{}' is never used", def_type, name),
+                    span.clone(),
+                ));
             }
         }
 
-        Ok(Self {
+        Self {
             window,
+            render,
+            camera,
+            background,
+            post,
+            fog,
+            textures,
+            materials,
             lights,
             spheres,
-        })
+        }
+    }
+
+    /**
+     * Warn about properties set more than once in a definition, as only the last value counts.
+     * Post-processing passes can be repeated, since each one is applied in turn.
+     */
+    fn check_repeated_properties(defn: &RawDefinition, problems: &mut Vec<ParseError>) {
+        if defn.def_type == "post" {
+            return;
+        }
+        for (i, assignment) in defn.assignments.iter().enumerate() {
+            if defn.assignments[i + 1..]
+                .iter()
+                .any(|a| a.name == assignment.name)
+            {
+                problems.push(ParseError::warning(
+                    format!(
+                        "{} is set more than once; only the last value is used",
+                        assignment.name
+                    ),
+                    assignment.name_span.clone(),
+                ));
+            }
+        }
+        for (i, block) in defn.blocks.iter().enumerate() {
+            if defn.blocks[i + 1..]
+                .iter()
+                .any(|b| b.def_type == block.def_type)
+            {
+                problems.push(ParseError::warning(
+                    format!(
+                        "{} is given more than once; only the last one is used",
+                        block.def_type
+                    ),
+                    block.span.clone(),
+                ));
+            }
+        }
+    }
+
+    /**
+     * Record the names of the materials and textures that `defn` refers to, and where
+     */
+    fn find_references(
+        defn: &RawDefinition,
+        materials: &mut Vec<(String, Span)>,
+        textures: &mut Vec<(String, Span)>,
+    ) {
+        if !matches!(
+            &defn.def_type[..],
+            "sphere" | "material" | "background" | "fog"
+        ) {
+            return;
+        }
+        for assignment in defn.assignments.iter() {
+            let name = match (&assignment.name[..], &assignment.value) {
+                // Quoted strings in color properties are texture names, anything else is a color
+                ("color" | "emission" | "top" | "bottom", Value::VString(name)) => name,
+                ("normal", Value::VString(name) | Value::Identifier(name))
+                | ("bump", Value::VString(name) | Value::Identifier(name)) => name,
+                ("material", Value::VString(name) | Value::Identifier(name))
+                    if defn.def_type == "sphere" =>
+                {
+                    materials.push((name.clone(), assignment.span.clone()));
+                    continue;
+                }
+                _ => continue,
+            };
+            textures.push((name.clone(), assignment.span.clone()));
+        }
     }
 
-    fn window_from_raw(raw: RawDefinition) -> Result<WindowDefinition, String> {
+    fn window_from_raw(raw: RawDefinition) -> Result<WindowDefinition, ParseError> {
         let mut title = None;
         let mut width = None;
         let mut height = None;
 
         for assignment in raw.assignments {
             match &assignment.name[..] {
-                "width" => width = Some(Self::numeric_value(assignment.value, "width")?),
-                "height" => height = Some(Self::numeric_value(assignment.value, "height")?),
-                "title" => title = Some(Self::string_value(assignment.value, "title")?),
-                s => {
-                    return Err(format!(
-                        "Expected properties: [width, height, title] but got: '{}'",
-                        s
+                "width" => {
+                    width =
+                        Some(Self::numeric_value(assignment.value, "width").at(&assignment.span)?)
+                }
+                "height" => {
+                    height =
+                        Some(Self::numeric_value(assignment.value, "height").at(&assignment.span)?)
+                }
+                "title" => {
+                    title =
+                        Some(Self::string_value(assignment.value, "title").at(&assignment.span)?)
+                }
+                "size" => {
+                    let size = Self::tuple_of::<2>(assignment.value, "size").at(&assignment.span)?;
+                    width = Some(size[0]);
+                    height = Some(size[1]);
+                }
+                _ => {
+                    return Err(Self::unknown_property(
+                        "width, height, size, title",
+                        &assignment,
                     ))
                 }
             }
@@ -65,7 +346,399 @@ impl SceneDefinition {
         })
     }
 
-    fn light_from_raw(raw: RawDefinition) -> Result<LightDefinition, String> {
+    fn camera_from_raw(raw: RawDefinition) -> Result<CameraDefinition, ParseError> {
+        let mut aperture = None;
+        let mut focal_distance = None;
+
+        for assignment in raw.assignments {
+            match &assignment.name[..] {
+                "aperture" => {
+                    let value =
+                        Self::numeric_value(assignment.value, "aperture").at(&assignment.span)?;
+                    if value < 0.0 {
+                        return Err(ParseError::new(
+                            format!("Expected aperture to be at least 0 but got {}", value),
+                            assignment.span.clone(),
+                        ));
+                    }
+                    aperture = Some(value)
+                }
+                "focal_distance" => {
+                    focal_distance = Some(
+                        Self::positive_value(assignment.value, "focal_distance")
+                            .at(&assignment.span)?,
+                    )
+                }
+                _ => {
+                    return Err(Self::unknown_property(
+                        "aperture, focal_distance",
+                        &assignment,
+                    ))
+                }
+            }
+        }
+
+        if aperture.is_some() && focal_distance.is_none() {
+            return Err(ParseError::new(
+                "camera definitions with an aperture require a focal_distance",
+                raw.span.clone(),
+            ));
+        }
+        Ok(CameraDefinition {
+            aperture,
+            focal_distance,
+        })
+    }
+
+    fn render_from_raw(raw: RawDefinition) -> Result<RenderDefinition, ParseError> {
+        let mut color_space = None;
+        let mut integrator = None;
+        let mut samples = None;
+        let mut depth = None;
+        let mut output = None;
+
+        for assignment in raw.assignments {
+            match &assignment.name[..] {
+                "colorspace" => {
+                    color_space =
+                        Some(Self::color_space_value(assignment.value).at(&assignment.span)?)
+                }
+                "integrator" => {
+                    let name =
+                        Self::string_value(assignment.value, "integrator").at(&assignment.span)?;
+                    integrator = Some(match &name[..] {
+                        "whitted" => Integrator::Whitted,
+                        "path" => Integrator::Path,
+                        s => {
+                            return Err(ParseError::new(
+                                format!(
+                                    "Expected integrator to be one of [whitted, path] \
+                                     but got: '{}'",
+                                    s
+                                ),
+                                assignment.span,
+                            ))
+                        }
+                    })
+                }
+                "samples" => {
+                    samples =
+                        Some(Self::count_value(assignment.value, "samples").at(&assignment.span)?)
+                }
+                "depth" => {
+                    depth = Some(Self::count_value(assignment.value, "depth").at(&assignment.span)?)
+                }
+                "output" => {
+                    output = Some(
+                        Self::path_value(assignment.value, "output", &raw.dir)
+                            .at(&assignment.span)?,
+                    )
+                }
+                _ => {
+                    return Err(Self::unknown_property(
+                        "colorspace, integrator, samples, depth, output",
+                        &assignment,
+                    ))
+                }
+            }
+        }
+
+        Ok(RenderDefinition {
+            color_space,
+            integrator,
+            samples,
+            depth,
+            output,
+        })
+    }
+
+    fn background_from_raw(raw: RawDefinition) -> Result<BackgroundDefinition, ParseError> {
+        let mut background_type = None;
+        let mut color = None;
+        let mut top = None;
+        let mut bottom = None;
+        let mut file = None;
+        let mut sun = None;
+        let mut turbidity = None;
+        let mut intensity = None;
+        let mut lighting = None;
+        let mut transparent = None;
+
+        for assignment in raw.assignments {
+            match &assignment.name[..] {
+                "type" => {
+                    background_type =
+                        Some(Self::string_value(assignment.value, "type").at(&assignment.span)?)
+                }
+                "color" => {
+                    color = Some(Self::color_value(assignment.value, "color").at(&assignment.span)?)
+                }
+                "top" => {
+                    top = Some(Self::color_value(assignment.value, "top").at(&assignment.span)?)
+                }
+                "bottom" => {
+                    bottom =
+                        Some(Self::color_value(assignment.value, "bottom").at(&assignment.span)?)
+                }
+                "file" => {
+                    file = Some(
+                        Self::path_value(assignment.value, "file", &raw.dir).at(&assignment.span)?,
+                    )
+                }
+                "sun" => {
+                    sun = Some(Self::tuple_value(assignment.value, "sun").at(&assignment.span)?)
+                }
+                "turbidity" => {
+                    turbidity = Some(
+                        Self::numeric_value(assignment.value, "turbidity").at(&assignment.span)?,
+                    )
+                }
+                "intensity" => {
+                    intensity = Some(
+                        Self::numeric_value(assignment.value, "intensity").at(&assignment.span)?,
+                    )
+                }
+                "lighting" => {
+                    lighting =
+                        Some(Self::flag_value(assignment.value, "lighting").at(&assignment.span)?)
+                }
+                "transparent" => {
+                    transparent = Some(
+                        Self::flag_value(assignment.value, "transparent").at(&assignment.span)?,
+                    )
+                }
+                _ => {
+                    let expected = "type, color, top, bottom, file, sun, turbidity, intensity, \
+                        lighting, transparent";
+                    return Err(Self::unknown_property(expected, &assignment));
+                }
+            }
+        }
+
+        let kind = match background_type.as_deref() {
+            None => {
+                return Err(ParseError::new(
+                    "background definitions require a type",
+                    raw.span.clone(),
+                ))
+            }
+            Some("color") => match (color, top, bottom, &file) {
+                (Some(color), None, None, None) => BackgroundKind::Color { color },
+                (None, _, _, _) => {
+                    return Err(ParseError::new(
+                        "color backgrounds require a color",
+                        raw.span.clone(),
+                    ))
+                }
+                _ => {
+                    return Err(ParseError::new(
+                        "color backgrounds only support the color property",
+                        raw.span.clone(),
+                    ))
+                }
+            },
+            Some("gradient") => match (top, bottom, color, &file) {
+                (Some(top), Some(bottom), None, None) => BackgroundKind::Gradient { top, bottom },
+                (None, _, _, _) | (_, None, _, _) => {
+                    return Err(ParseError::new(
+                        "gradient backgrounds require a top and a bottom",
+                        raw.span.clone(),
+                    ))
+                }
+                _ => {
+                    return Err(ParseError::new(
+                        "gradient backgrounds only support the top and bottom properties",
+                        raw.span.clone(),
+                    ))
+                }
+            },
+            Some("image") => match (file, color, top, bottom) {
+                (Some(file), None, None, None) => BackgroundKind::Image { file },
+                (None, _, _, _) => {
+                    return Err(ParseError::new(
+                        "image backgrounds require a file",
+                        raw.span.clone(),
+                    ))
+                }
+                _ => {
+                    return Err(ParseError::new(
+                        "image backgrounds only support the file property",
+                        raw.span.clone(),
+                    ))
+                }
+            },
+            Some("sky") => {
+                if color.is_some() || top.is_some() || bottom.is_some() || file.is_some() {
+                    return Err(ParseError::new(
+                        "sky backgrounds only support the sun and turbidity properties",
+                        raw.span.clone(),
+                    ));
+                }
+                let sun = sun
+                    .ok_or("sky backgrounds require a sun direction")
+                    .at(&raw.span)?;
+                if sun.1 <= 0.0 {
+                    return Err(ParseError::new(
+                        "sky backgrounds require the sun to be above the horizon",
+                        raw.span.clone(),
+                    ));
+                }
+                let turbidity = turbidity.unwrap_or(3.0);
+                if !(MIN_TURBIDITY..=MAX_TURBIDITY).contains(&turbidity) {
+                    return Err(ParseError::new(
+                        format!(
+                            "Expected turbidity between {} and {} but got {}",
+                            MIN_TURBIDITY, MAX_TURBIDITY, turbidity
+                        ),
+                        raw.span.clone(),
+                    ));
+                }
+                BackgroundKind::Sky { sun, turbidity }
+            }
+            Some(s) => {
+                return Err(ParseError::new(
+                    format!("Unsupported background type: {}", s),
+                    raw.span.clone(),
+                ))
+            }
+        };
+        if (sun.is_some() || turbidity.is_some()) && !matches!(kind, BackgroundKind::Sky { .. }) {
+            return Err(ParseError::new(
+                "only sky backgrounds support the sun and turbidity properties",
+                raw.span.clone(),
+            ));
+        }
+
+        Ok(BackgroundDefinition {
+            kind,
+            intensity,
+            lighting,
+            transparent,
+        })
+    }
+
+    fn fog_from_raw(raw: RawDefinition) -> Result<FogDefinition, ParseError> {
+        let mut color = None;
+        let mut density = None;
+        let mut falloff = None;
+
+        for assignment in raw.assignments {
+            match &assignment.name[..] {
+                "color" => {
+                    color = Some(Self::color_value(assignment.value, "color").at(&assignment.span)?)
+                }
+                "density" => {
+                    density = Some(
+                        Self::positive_value(assignment.value, "density").at(&assignment.span)?,
+                    )
+                }
+                "falloff" => {
+                    let value =
+                        Self::numeric_value(assignment.value, "falloff").at(&assignment.span)?;
+                    if value < 0.0 {
+                        return Err(ParseError::new(
+                            format!("Expected falloff to be at least 0 but got {}", value),
+                            assignment.span.clone(),
+                        ));
+                    }
+                    falloff = Some(value)
+                }
+                _ => {
+                    return Err(Self::unknown_property(
+                        "color, density, falloff",
+                        &assignment,
+                    ))
+                }
+            }
+        }
+
+        match (color, density) {
+            (Some(color), Some(density)) => Ok(FogDefinition {
+                color,
+                density,
+                falloff,
+            }),
+            _ => Err(ParseError::new(
+                "fog definitions require a color and a density",
+                raw.span.clone(),
+            )),
+        }
+    }
+
+    /**
+     * Post-processing passes, applied in the order they are written
+     */
+    fn post_from_raw(raw: RawDefinition) -> Result<Vec<PassDefinition>, ParseError> {
+        let mut passes = vec![];
+        let mut threshold = None;
+
+        for assignment in raw.assignments {
+            let pass = match &assignment.name[..] {
+                "exposure" => PassDefinition::Exposure(
+                    Self::numeric_value(assignment.value, "exposure").at(&assignment.span)?,
+                ),
+                "contrast" => PassDefinition::Contrast(
+                    Self::positive_value(assignment.value, "contrast").at(&assignment.span)?,
+                ),
+                "saturation" => {
+                    let saturation =
+                        Self::numeric_value(assignment.value, "saturation").at(&assignment.span)?;
+                    if saturation < 0.0 {
+                        return Err(ParseError::new(
+                            format!(
+                                "Expected saturation to be at least 0 but got {}",
+                                saturation
+                            ),
+                            assignment.span.clone(),
+                        ));
+                    }
+                    PassDefinition::Saturation(saturation)
+                }
+                "vignette" => PassDefinition::Vignette(
+                    Self::unit_value(assignment.value, "vignette").at(&assignment.span)?,
+                ),
+                "bloom" => PassDefinition::Bloom {
+                    strength: Self::positive_value(assignment.value, "bloom")
+                        .at(&assignment.span)?,
+                    threshold: None,
+                },
+                "threshold" => {
+                    threshold = Some(
+                        Self::numeric_value(assignment.value, "threshold").at(&assignment.span)?,
+                    );
+                    continue;
+                }
+                "lut" => PassDefinition::Lut(
+                    Self::path_value(assignment.value, "lut", &raw.dir).at(&assignment.span)?,
+                ),
+                _ => {
+                    return Err(Self::unknown_property(
+                        "exposure, contrast, saturation, vignette, bloom, threshold, lut",
+                        &assignment,
+                    ))
+                }
+            };
+            passes.push(pass);
+        }
+
+        if let Some(value) = threshold {
+            match passes
+                .iter_mut()
+                .find(|p| matches!(p, PassDefinition::Bloom { .. }))
+            {
+                Some(PassDefinition::Bloom { threshold, .. }) => *threshold = Some(value),
+                _ => {
+                    return Err(ParseError::new(
+                        "threshold is only supported alongside bloom",
+                        raw.span.clone(),
+                    ))
+                }
+            }
+        }
+        Ok(passes)
+    }
+
+    fn light_from_raw(raw: RawDefinition) -> Result<LightDefinition, ParseError> {
         let mut light_type = None;
         let mut intensity = None;
         let mut position = None;
@@ -73,208 +746,1821 @@ impl SceneDefinition {
 
         for assignment in raw.assignments {
             match &assignment.name[..] {
-                "type" => light_type = Some(Self::string_value(assignment.value, "type")?),
+                "type" => {
+                    light_type =
+                        Some(Self::string_value(assignment.value, "type").at(&assignment.span)?)
+                }
                 "intensity" => {
-                    intensity = Some(Self::numeric_value(assignment.value, "intensity")?)
+                    intensity = Some(
+                        Self::numeric_value(assignment.value, "intensity").at(&assignment.span)?,
+                    )
+                }
+                "position" => {
+                    position =
+                        Some(Self::tuple_value(assignment.value, "position").at(&assignment.span)?)
+                }
+                "direction" => {
+                    direction =
+                        Some(Self::tuple_value(assignment.value, "direction").at(&assignment.span)?)
+                }
+                _ => {
+                    return Err(Self::unknown_property(
+                        "type, intensity, position, direction",
+                        &assignment,
+                    ))
+                }
+            }
+        }
+        let (light_type, intensity) = match (light_type, intensity) {
+            (Some(light_type), Some(intensity)) => (light_type, intensity),
+            _ => {
+                return Err(ParseError::new(
+                    "light definitions require a type and an intensity",
+                    raw.span.clone(),
+                ))
+            }
+        };
+
+        match &light_type[..] {
+            "ambient" => {
+                if position.is_some() || direction.is_some() {
+                    return Err(ParseError::new(
+                        "Only type and intensity are supported for ambient lights",
+                        raw.span.clone(),
+                    ));
+                }
+                Ok(LightDefinition::Ambient { intensity })
+            }
+            "point" => match (position, direction) {
+                (None, _) => Err(ParseError::new(
+                    "point lights require a position",
+                    raw.span.clone(),
+                )),
+                (Some(_), Some(_)) => Err(ParseError::new(
+                    "point lights do not support the direction property",
+                    raw.span.clone(),
+                )),
+                (Some(position), None) => Ok(LightDefinition::Point {
+                    intensity,
+                    position,
+                }),
+            },
+            "directional" => match (direction, position) {
+                (None, _) => Err(ParseError::new(
+                    "directional lights require a direction",
+                    raw.span.clone(),
+                )),
+                (Some(_), Some(_)) => Err(ParseError::new(
+                    "directional lights do not support the position property",
+                    raw.span.clone(),
+                )),
+                (Some(direction), None) => Ok(LightDefinition::Direction {
+                    intensity,
+                    direction,
+                }),
+            },
+            s => Err(ParseError::new(
+                format!("Unsupported light type: {}", s),
+                raw.span.clone(),
+            )),
+        }
+    }
+
+    fn texture_from_raw(raw: RawDefinition) -> Result<TextureDefinition, ParseError> {
+        let name = raw
+            .name
+            .ok_or("texture definitions require a name, as in: texture \"name\" { ... }")
+            .at(&raw.span)?;
+        let mut texture_type = None;
+        let mut primary = None;
+        let mut secondary = None;
+        let mut scale = None;
+        let mut direction = None;
+        let mut octaves = None;
+        let mut turbulence = None;
+        let mut file = None;
+        let mut filter = None;
+        let mut wrap = None;
+        let mut color_space = None;
+
+        for assignment in raw.assignments {
+            match &assignment.name[..] {
+                "type" => {
+                    texture_type =
+                        Some(Self::string_value(assignment.value, "type").at(&assignment.span)?)
+                }
+                "primary" => {
+                    primary =
+                        Some(Self::color_literal(assignment.value, "primary").at(&assignment.span)?)
+                }
+                "secondary" => {
+                    secondary = Some(
+                        Self::color_literal(assignment.value, "secondary").at(&assignment.span)?,
+                    )
+                }
+                "scale" => {
+                    let value =
+                        Self::numeric_value(assignment.value, "scale").at(&assignment.span)?;
+                    if value <= 0.0 {
+                        return Err(ParseError::new(
+                            format!("Expected a positive scale but got {}", value),
+                            assignment.span.clone(),
+                        ));
+                    }
+                    scale = Some(value)
+                }
+                "direction" => {
+                    direction =
+                        Some(Self::tuple_value(assignment.value, "direction").at(&assignment.span)?)
+                }
+                "octaves" => {
+                    octaves =
+                        Some(Self::count_value(assignment.value, "octaves").at(&assignment.span)?)
+                }
+                "turbulence" => {
+                    turbulence = Some(
+                        Self::numeric_value(assignment.value, "turbulence").at(&assignment.span)?,
+                    )
+                }
+                "file" => {
+                    file = Some(
+                        Self::path_value(assignment.value, "file", &raw.dir).at(&assignment.span)?,
+                    )
+                }
+                "filter" => {
+                    let name =
+                        Self::string_value(assignment.value, "filter").at(&assignment.span)?;
+                    filter = Some(match &name[..] {
+                        "nearest" => Filter::Nearest,
+                        "bilinear" => Filter::Bilinear,
+                        s => {
+                            return Err(ParseError::new(
+                                format!(
+                                    "Expected filter to be one of [nearest, bilinear] \
+                                     but got: '{}'",
+                                    s
+                                ),
+                                assignment.span,
+                            ))
+                        }
+                    })
+                }
+                "wrap" => {
+                    let name = Self::string_value(assignment.value, "wrap").at(&assignment.span)?;
+                    wrap = Some(match &name[..] {
+                        "repeat" => Wrap::Repeat,
+                        "clamp" => Wrap::Clamp,
+                        s => {
+                            return Err(ParseError::new(
+                                format!(
+                                    "Expected wrap to be one of [repeat, clamp] but got: '{}'",
+                                    s
+                                ),
+                                assignment.span,
+                            ))
+                        }
+                    })
+                }
+                "colorspace" => {
+                    color_space =
+                        Some(Self::color_space_value(assignment.value).at(&assignment.span)?)
+                }
+                _ => {
+                    let expected = "type, primary, secondary, scale, direction, octaves, \
+                        turbulence, file, filter, wrap, colorspace";
+                    return Err(Self::unknown_property(expected, &assignment));
                 }
-                "position" => position = Some(Self::tuple_value(assignment.value, "position")?),
-                "direction" => direction = Some(Self::tuple_value(assignment.value, "direction")?),
-                s => {
-                    return Err(format!(
-                        "Expected properties: [type, intensity, position, direction] but got: '{}'",
+            }
+        }
+
+        let texture_type = texture_type
+            .ok_or("Texture definitions require a type")
+            .at(&raw.span)?;
+        if texture_type == "image" {
+            return match file {
+                Some(file) => Ok(TextureDefinition {
+                    name,
+                    kind: TextureKind::Image {
+                        file,
+                        filter,
+                        wrap,
+                        color_space,
+                    },
+                }),
+                None => Err(ParseError::new(
+                    "Image textures require a file",
+                    raw.span.clone(),
+                )),
+            };
+        }
+        if file.is_some() || filter.is_some() || wrap.is_some() || color_space.is_some() {
+            return Err(ParseError::new(
+                format!(
+                    "The properties [file, filter, wrap, colorspace] only apply to image textures, \
+                     but texture '{}' has type {}",
+                    name, texture_type
+                ),
+                raw.span.clone(),
+            ));
+        }
+
+        let pattern = match &texture_type[..] {
+            "checker" => Pattern::Checker,
+            "stripes" => Pattern::Stripes,
+            "rings" => Pattern::Rings,
+            "gradient" => Pattern::Gradient,
+            "noise" => Pattern::Noise,
+            "marble" => Pattern::Marble,
+            "wood" => Pattern::Wood,
+            s => {
+                return Err(ParseError::new(
+                    format!(
+                        "Expected texture type to be one of [checker, stripes, rings, gradient, \
+                         noise, marble, wood, image] but got: '{}'",
                         s
+                    ),
+                    raw.span.clone(),
+                ))
+            }
+        };
+        match (primary, secondary) {
+            (Some(primary), Some(secondary)) => Ok(TextureDefinition {
+                name,
+                kind: TextureKind::Pattern {
+                    pattern,
+                    primary,
+                    secondary,
+                    scale,
+                    direction,
+                    octaves,
+                    turbulence,
+                },
+            }),
+            _ => Err(ParseError::new(
+                "Pattern textures require [primary, secondary] but some values are missing",
+                raw.span.clone(),
+            )),
+        }
+    }
+
+    fn material_from_raw(raw: RawDefinition) -> Result<NamedMaterialDefinition, ParseError> {
+        let name = raw
+            .name
+            .ok_or("material definitions require a name, as in: material \"name\" { ... }")
+            .at(&raw.span)?;
+        let mut properties = MaterialProperties::default();
+
+        for assignment in raw.assignments {
+            if let Some(assignment) = properties.assign(assignment)? {
+                return Err(Self::unknown_property(
+                    MaterialProperties::NAMES,
+                    &assignment,
+                ));
+            }
+        }
+
+        Ok(NamedMaterialDefinition {
+            material: properties.build("material").at(&raw.span)?,
+            name,
+        })
+    }
+
+    fn sphere_from_raw(raw: RawDefinition) -> Result<SphereDefinition, ParseError> {
+        let mut material = None;
+        let mut properties = MaterialProperties::default();
+        let mut center = None;
+        let mut radius = None;
+        let transform = match raw.blocks.into_iter().last() {
+            Some(block) => Some(Self::transform_from_raw(block)?),
+            None => None,
+        };
+
+        for assignment in raw.assignments {
+            let assignment = match properties.assign(assignment)? {
+                Some(assignment) => assignment,
+                None => continue,
+            };
+            match &assignment.name[..] {
+                "material" => {
+                    material =
+                        Some(Self::string_value(assignment.value, "material").at(&assignment.span)?)
+                }
+                "center" => {
+                    center =
+                        Some(Self::tuple_value(assignment.value, "center").at(&assignment.span)?)
+                }
+                "radius" => {
+                    radius =
+                        Some(Self::numeric_value(assignment.value, "radius").at(&assignment.span)?)
+                }
+                _ => {
+                    let expected =
+                        format!("material, {}, center, radius", MaterialProperties::NAMES);
+                    return Err(Self::unknown_property(&expected, &assignment));
+                }
+            }
+        }
+
+        let material = match material {
+            Some(name) if properties.is_empty() => MaterialSource::Named(name),
+            Some(_) => {
+                return Err(ParseError::new(
+                    "Spheres take either a material or their own material properties, not both",
+                    raw.span.clone(),
+                ))
+            }
+            None => MaterialSource::Inline(Box::new(properties.build("sphere").at(&raw.span)?)),
+        };
+        match (center, radius) {
+            (Some(center), Some(radius)) => {
+                let (center, radius) = match transform {
+                    Some(transform) => transform.apply(center, radius),
+                    None => (center, radius),
+                };
+                Ok(SphereDefinition {
+                    material,
+                    center,
+                    radius,
+                })
+            }
+            _ => Err(ParseError::new(
+                "Sphere definitions require [center, radius] but some values are missing",
+                raw.span.clone(),
+            )),
+        }
+    }
+
+    fn transform_from_raw(raw: RawDefinition) -> Result<Transform, ParseError> {
+        if let Some(block) = raw.blocks.first() {
+            return Err(Self::unknown_block("transform", &[], block));
+        }
+        let mut transform = Transform::default();
+        for assignment in raw.assignments {
+            match &assignment.name[..] {
+                "translate" => {
+                    transform.translate =
+                        Self::tuple_value(assignment.value, "translate").at(&assignment.span)?
+                }
+                "scale" => {
+                    transform.scale =
+                        Self::positive_value(assignment.value, "scale").at(&assignment.span)?
+                }
+                "rotate" => {
+                    let [x, y, z, angle] =
+                        Self::tuple_of::<4>(assignment.value, "rotate").at(&assignment.span)?;
+                    if x == 0.0 && y == 0.0 && z == 0.0 {
+                        return Err(ParseError::new(
+                            "Expected the axis of rotate not to be (0, 0, 0)",
+                            assignment.span,
+                        ));
+                    }
+                    transform.rotate = Some((WorldCoordinate::new(x, y, z).normalize(), angle));
+                }
+                _ => {
+                    return Err(Self::unknown_property(
+                        "translate, rotate, scale",
+                        &assignment,
                     ))
                 }
             }
         }
-        if light_type.is_none() || intensity.is_none() {
-            return Err("light definitions require a type and an intensity".into());
+        Ok(transform)
+    }
+
+    /**
+     * An error for a sub-block that `def_type` definitions can't contain
+     */
+    fn unknown_block(def_type: &str, allowed: &[&str], block: &RawDefinition) -> ParseError {
+        let message = if allowed.is_empty() {
+            format!(
+                "{} definitions can't contain blocks, but got: '{}'",
+                def_type, block.def_type
+            )
+        } else {
+            format!(
+                "Expected blocks: [{}] in {} but got: '{}'",
+                allowed.join(", "),
+                def_type,
+                block.def_type
+            )
+        };
+        ParseError::new(message, block.span.clone())
+    }
+
+    /**
+     * An error for an assignment to a property that isn't one of the `expected` ones
+     */
+    fn unknown_property(expected: &str, assignment: &Assignment) -> ParseError {
+        ParseError::new(
+            format!(
+                "Expected properties: [{}] but got: '{}'",
+                expected, assignment.name
+            ),
+            assignment.name_span.clone(),
+        )
+    }
+
+    fn numeric_value(value: Value, property: &str) -> Result<f64, String> {
+        match value {
+            Value::Num(n) => Ok(n),
+            // Identifiers stand for themselves, but where a number is expected one was most
+            // likely meant to be a variable
+            Value::Identifier(name) => Err(format!("Unknown variable '{}'", name)),
+            _ => Err(format!(
+                "Expected number for property {} but got {}",
+                property,
+                describe(&value)
+            )),
+        }
+    }
+
+    /**
+     * A whole number of at least one, such as a sample count
+     */
+    fn count_value(value: Value, property: &str) -> Result<usize, String> {
+        let n = Self::numeric_value(value, property)?;
+        if n < 1.0 || n.fract() != 0.0 {
+            Err(format!(
+                "Expected a positive whole number for property {} but got {}",
+                property, n
+            ))
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    fn string_value(value: Value, property: &str) -> Result<String, String> {
+        match value {
+            Value::VString(s) | Value::Identifier(s) => Ok(s),
+            _ => Err(format!(
+                "Expected string for property {} but got {}",
+                property,
+                describe(&value)
+            )),
+        }
+    }
+
+    /**
+     * A file named relative to `dir`, the directory of the file the definition is written in
+     */
+    fn path_value(value: Value, property: &str, dir: &Path) -> Result<String, String> {
+        let path = Self::string_value(value, property)?;
+        Ok(dir.join(path).display().to_string())
+    }
+
+    fn positive_value(value: Value, property: &str) -> Result<f64, String> {
+        let n = Self::numeric_value(value, property)?;
+        if n > 0.0 {
+            Ok(n)
+        } else {
+            Err(format!(
+                "Expected a positive number for property {} but got {}",
+                property, n
+            ))
+        }
+    }
+
+    /**
+     * A number between 0 and 1 inclusive
+     */
+    fn unit_value(value: Value, prop_name: &str) -> Result<f64, String> {
+        let number = Self::numeric_value(value, prop_name)?;
+        if (0.0..=1.0).contains(&number) {
+            Ok(number)
+        } else {
+            Err(format!(
+                "Expected {} to be between 0 and 1 but got {}",
+                prop_name, number
+            ))
+        }
+    }
+
+    fn color_space_value(value: Value) -> Result<ColorSpace, String> {
+        match &Self::string_value(value, "colorspace")?[..] {
+            "srgb" => Ok(ColorSpace::Srgb),
+            "linear" => Ok(ColorSpace::Linear),
+            s => Err(format!(
+                "Expected colorspace to be one of [srgb, linear] but got: '{}'",
+                s
+            )),
+        }
+    }
+
+    /**
+     * An on/off switch, written as the identifier true or false
+     */
+    fn flag_value(value: Value, property: &str) -> Result<bool, String> {
+        match &Self::string_value(value, property)?[..] {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            s => Err(format!(
+                "Expected true or false for property {} but got {}",
+                property, s
+            )),
+        }
+    }
+
+    /**
+     * Either a literal color or the quoted name of a texture
+     */
+    fn color_value(value: Value, property: &str) -> Result<ColorDefinition, String> {
+        match value {
+            Value::VString(name) => Ok(ColorDefinition::Texture(name)),
+            value => Ok(ColorDefinition::Rgb(Self::color_literal(value, property)?)),
+        }
+    }
+
+    /**
+     * A color written as a (red, green, blue) tuple from 0 to 255, a CSS color name, a hex color or
+     * a color function, as channels from 0 to 1
+     */
+    fn color_literal(value: Value, property: &str) -> Result<(f64, f64, f64), String> {
+        match value {
+            Value::Tuple(channels) if channels.len() == 3 => {
+                let (red, green, blue) = (channels[0], channels[1], channels[2]);
+                for channel in [red, green, blue] {
+                    if !(0.0..=255.0).contains(&channel) {
+                        return Err(format!(
+                            "Expected color channels between 0 and 255 for property {} but got {}",
+                            property, channel
+                        ));
+                    }
+                }
+                Ok((red / 255.0, green / 255.0, blue / 255.0))
+            }
+            Value::Color(channels) => Ok(channels),
+            Value::Identifier(name) => match Color::named(&name) {
+                Some(color) => Ok((
+                    color.red() as f64 / 255.0,
+                    color.green() as f64 / 255.0,
+                    color.blue() as f64 / 255.0,
+                )),
+                None => Err(format!(
+                    "Unknown color name '{}' for property {}",
+                    name, property
+                )),
+            },
+            _ => Err(format!(
+                "Expected a color for property {} but got {}",
+                property,
+                describe(&value)
+            )),
+        }
+    }
+
+    fn tuple_value(value: Value, property: &str) -> Result<(f64, f64, f64), String> {
+        let [x, y, z] = Self::tuple_of::<3>(value, property)?;
+        Ok((x, y, z))
+    }
+
+    /**
+     * A tuple of exactly N numbers
+     */
+    fn tuple_of<const N: usize>(value: Value, property: &str) -> Result<[f64; N], String> {
+        match value {
+            Value::Tuple(t) if t.len() == N => {
+                let mut numbers = [0.0; N];
+                numbers.copy_from_slice(&t);
+                Ok(numbers)
+            }
+            _ => Err(format!(
+                "Expected a tuple of {} numbers for property {} but got {}",
+                N,
+                property,
+                describe(&value)
+            )),
+        }
+    }
+}
+
+/**
+ * Moves an object, applied in the order scale, rotate, translate. Scaling and rotating are about
+ * the origin, so they move the object's center as well as changing its size.
+ */
+struct Transform {
+    translate: (f64, f64, f64),
+    /// A unit axis and an angle in radians
+    rotate: Option<(WorldCoordinate, f64)>,
+    scale: f64,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translate: (0.0, 0.0, 0.0),
+            rotate: None,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Transform {
+    fn apply(&self, center: (f64, f64, f64), radius: f64) -> ((f64, f64, f64), f64) {
+        let mut center = WorldCoordinate::from_tuple(center) * self.scale;
+        if let Some((axis, angle)) = self.rotate {
+            center = center.rotate(axis, angle);
+        }
+        center = center + WorldCoordinate::from_tuple(self.translate);
+        ((center.x(), center.y(), center.z()), radius * self.scale)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WindowDefinition {
+    pub title: Option<String>,
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RenderDefinition {
+    #[serde(rename = "colorspace")]
+    pub color_space: Option<ColorSpace>,
+    pub integrator: Option<Integrator>,
+    pub samples: Option<usize>,
+    pub depth: Option<usize>,
+    /// A PNG file to save the image to once it is finished
+    pub output: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BackgroundDefinition {
+    pub kind: BackgroundKind,
+    pub intensity: Option<f64>,
+    pub lighting: Option<bool>,
+    /// Leave the background out of the image, giving those pixels zero alpha
+    pub transparent: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CameraDefinition {
+    pub aperture: Option<f64>,
+    pub focal_distance: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FogDefinition {
+    pub color: ColorDefinition,
+    pub density: f64,
+    pub falloff: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum PassDefinition {
+    Exposure(f64),
+    Contrast(f64),
+    Saturation(f64),
+    Vignette(f64),
+    Bloom {
+        strength: f64,
+        threshold: Option<f64>,
+    },
+    Lut(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum BackgroundKind {
+    Color {
+        color: ColorDefinition,
+    },
+    Gradient {
+        top: ColorDefinition,
+        bottom: ColorDefinition,
+    },
+    Image {
+        file: String,
+    },
+    Sky {
+        sun: (f64, f64, f64),
+        turbidity: f64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum LightDefinition {
+    Point {
+        intensity: f64,
+        position: (f64, f64, f64),
+    },
+    #[serde(rename = "directional")]
+    Direction {
+        intensity: f64,
+        direction: (f64, f64, f64),
+    },
+    Ambient {
+        intensity: f64,
+    },
+}
+
+/**
+ * A color property: either a fixed color or a reference to a named texture
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum ColorDefinition {
+    Rgb((f64, f64, f64)),
+    Texture(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TextureDefinition {
+    pub name: String,
+    pub kind: TextureKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum TextureKind {
+    Pattern {
+        pattern: Pattern,
+        primary: (f64, f64, f64),
+        secondary: (f64, f64, f64),
+        scale: Option<f64>,
+        direction: Option<(f64, f64, f64)>,
+        octaves: Option<usize>,
+        turbulence: Option<f64>,
+    },
+    Image {
+        file: String,
+        filter: Option<Filter>,
+        wrap: Option<Wrap>,
+        /// Overrides the scene's color space, e.g. so that normal maps are read as plain data
+        #[serde(rename = "colorspace")]
+        color_space: Option<ColorSpace>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MaterialDefinition {
+    pub color: ColorDefinition,
+    pub specular: Option<f64>,
+    pub reflective: Option<f64>,
+    pub emission: Option<ColorDefinition>,
+    pub strength: Option<f64>,
+    /// A texture holding a tangent-space normal map
+    pub normal: Option<String>,
+    /// A texture whose brightness gives the height of bumps on the surface
+    pub bump: Option<String>,
+    #[serde(rename = "bumpheight")]
+    pub bump_height: Option<f64>,
+    /// The metallic and roughness of the physically based model, if the material uses it
+    pub microfacet: Option<(f64, f64)>,
+    pub opacity: Option<f64>,
+    /// Makes objects of this material volumes rather than solid surfaces
+    pub density: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NamedMaterialDefinition {
+    pub name: String,
+    pub material: MaterialDefinition,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum MaterialSource {
+    Inline(Box<MaterialDefinition>),
+    Named(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SphereDefinition {
+    pub material: MaterialSource,
+    pub center: (f64, f64, f64),
+    pub radius: f64,
+}
+
+/**
+ * The surface properties shared by material definitions and objects with their own material
+ */
+#[derive(Default)]
+struct MaterialProperties {
+    color: Option<ColorDefinition>,
+    specular: Option<f64>,
+    reflective: Option<f64>,
+    emission: Option<ColorDefinition>,
+    strength: Option<f64>,
+    normal: Option<String>,
+    bump: Option<String>,
+    bumpheight: Option<f64>,
+    metallic: Option<f64>,
+    roughness: Option<f64>,
+    opacity: Option<f64>,
+    density: Option<f64>,
+}
+
+impl MaterialProperties {
+    const NAMES: &'static str = "color, specular, reflective, emission, strength, normal, bump, \
+                                 bumpheight, metallic, roughness, opacity, density";
+
+    /**
+     * Record the assignment if it sets a material property, otherwise hand it back
+     */
+    fn assign(&mut self, assignment: Assignment) -> Result<Option<Assignment>, ParseError> {
+        match &assignment.name[..] {
+            "color" => {
+                self.color = Some(
+                    SceneDefinition::color_value(assignment.value, "color").at(&assignment.span)?,
+                )
+            }
+            "specular" => {
+                let specular = SceneDefinition::numeric_value(assignment.value, "specular")
+                    .at(&assignment.span)?;
+                if specular <= 0.0 {
+                    return Err(ParseError::new(
+                        format!("Expected a positive specular exponent but got {}", specular),
+                        assignment.span.clone(),
+                    ));
+                }
+                self.specular = Some(specular)
+            }
+            "reflective" => {
+                self.reflective = Some(
+                    SceneDefinition::unit_value(assignment.value, "reflective")
+                        .at(&assignment.span)?,
+                )
+            }
+            "emission" => {
+                self.emission = Some(
+                    SceneDefinition::color_value(assignment.value, "emission")
+                        .at(&assignment.span)?,
+                )
+            }
+            "strength" => {
+                self.strength = Some(
+                    SceneDefinition::numeric_value(assignment.value, "strength")
+                        .at(&assignment.span)?,
+                )
+            }
+            "normal" => {
+                self.normal = Some(
+                    SceneDefinition::string_value(assignment.value, "normal")
+                        .at(&assignment.span)?,
+                )
+            }
+            "bump" => {
+                self.bump = Some(
+                    SceneDefinition::string_value(assignment.value, "bump").at(&assignment.span)?,
+                )
+            }
+            "bumpheight" => {
+                self.bumpheight = Some(
+                    SceneDefinition::numeric_value(assignment.value, "bumpheight")
+                        .at(&assignment.span)?,
+                )
+            }
+            "metallic" => {
+                self.metallic = Some(
+                    SceneDefinition::unit_value(assignment.value, "metallic")
+                        .at(&assignment.span)?,
+                )
+            }
+            "roughness" => {
+                self.roughness = Some(
+                    SceneDefinition::unit_value(assignment.value, "roughness")
+                        .at(&assignment.span)?,
+                )
+            }
+            "opacity" => {
+                self.opacity = Some(
+                    SceneDefinition::unit_value(assignment.value, "opacity").at(&assignment.span)?,
+                )
+            }
+            "density" => {
+                self.density = Some(
+                    SceneDefinition::positive_value(assignment.value, "density")
+                        .at(&assignment.span)?,
+                )
+            }
+            _ => return Ok(Some(assignment)),
+        }
+        Ok(None)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.color.is_none()
+            && self.specular.is_none()
+            && self.reflective.is_none()
+            && self.emission.is_none()
+            && self.strength.is_none()
+            && self.normal.is_none()
+            && self.bump.is_none()
+            && self.bumpheight.is_none()
+            && self.metallic.is_none()
+            && self.roughness.is_none()
+            && self.opacity.is_none()
+            && self.density.is_none()
+    }
+
+    fn build(self, def_type: &str) -> Result<MaterialDefinition, String> {
+        if self.emission.is_none() && self.strength.is_some() {
+            return Err("strength is only supported for materials with an emission".into());
+        }
+        if self.bump.is_none() && self.bumpheight.is_some() {
+            return Err("bumpheight is only supported for materials with a bump texture".into());
+        }
+        let microfacet = if self.metallic.is_some() || self.roughness.is_some() {
+            if self.specular.is_some() {
+                return Err(
+                    "specular cannot be combined with metallic or roughness; use roughness to \
+                     control highlights"
+                        .into(),
+                );
+            }
+            Some((
+                self.metallic.unwrap_or(0.0),
+                self.roughness.unwrap_or(DEFAULT_ROUGHNESS),
+            ))
+        } else {
+            None
+        };
+        let color = self
+            .color
+            .ok_or_else(|| format!("{} definitions require a color", def_type))?;
+
+        Ok(MaterialDefinition {
+            color,
+            specular: self.specular,
+            reflective: self.reflective,
+            emission: self.emission,
+            strength: self.strength,
+            normal: self.normal,
+            bump: self.bump,
+            bump_height: self.bumpheight,
+            microfacet,
+            opacity: self.opacity,
+            density: self.density,
+        })
+    }
+}
+
+/**
+ * A statement at the top level of a scene file, before any expressions in it are evaluated
+ */
+enum Statement {
+    Let {
+        name: String,
+        value: Expr,
+    },
+    /// Replaced by the statements of the file at `path` before anything is evaluated
+    Include {
+        path: String,
+        span: Span,
+    },
+    /// Runs `body` with `variable` set to each value in `values`
+    For {
+        variable: String,
+        values: Iterable,
+        body: Vec<Statement>,
+        span: Span,
+    },
+    If {
+        condition: Condition,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+    },
+    Definition(RawDefinition<Expr>),
+}
+
+/**
+ * What a for loop runs over
+ */
+enum Iterable {
+    /// Each whole number from the first up to but not including the second
+    Range(Expr, Expr),
+    /// Each element of an array
+    Array(Expr),
+}
+
+struct RawDefinition<V = Value> {
+    def_type: String,
+    name: Option<String>,
+    assignments: Vec<Assignment<V>>,
+    /// Sub-blocks such as a sphere's transform, whose def_type is the word before their brace
+    blocks: Vec<RawDefinition<V>>,
+    /// The definition's type and name
+    span: Span,
+    /// The directory of the file the definition is written in, relative to the scene file's, which
+    /// any files it names are found in
+    dir: PathBuf,
+}
+
+struct Assignment<V = Value> {
+    name: String,
+    value: V,
+    name_span: Span,
+    /// The value assigned
+    span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
+    Num(f64),
+    VString(String),
+    Identifier(String),
+    /// Two to four numbers
+    Tuple(Vec<f64>),
+    Array(Vec<Value>),
+    /// Red, green and blue channels between 0 and 1
+    Color((f64, f64, f64)),
+}
+
+/**
+ * Replace each include statement with the statements in the file it names, found relative to
+ * `file`, the file the statements are from. `dir` is the directory of `file` relative to the scene
+ * file's, and is recorded in each definition. `including` holds the files being included, starting
+ * with the scene file, so that a file that ends up including itself is caught.
+ */
+fn expand_includes(
+    statements: Vec<Statement>,
+    file: &Path,
+    dir: &Path,
+    sources: &mut SourceMap,
+    including: &mut Vec<PathBuf>,
+    problems: &mut Vec<ParseError>,
+    unparsed: &mut Vec<String>,
+) -> Vec<Statement> {
+    let mut expanded = vec![];
+    for statement in statements {
+        let (path, span) = match statement {
+            Statement::Include { path, span } => (path, span),
+            mut statement => {
+                record_dir(&mut statement, dir);
+                expanded.push(statement);
+                continue;
+            }
+        };
+
+        let included = file.parent().unwrap_or_else(|| Path::new("")).join(&path);
+        let canonical_path = canonical(&included);
+        if let Some(start) = including.iter().position(|p| *p == canonical_path) {
+            let cycle: Vec<String> = including[start..]
+                .iter()
+                .chain(std::iter::once(&canonical_path))
+                .map(|p| p.display().to_string())
+                .collect();
+            problems.push(ParseError::new(
+                format!(
+                    "Files include each other in a cycle: {}",
+                    cycle.join(" -> ")
+                ),
+                span,
+            ));
+            continue;
+        }
+        let source = match fs::read_to_string(&included) {
+            Ok(source) => source,
+            Err(e) => {
+                problems.push(ParseError::new(
+                    format!("Failed to read '{}': {}", included.display(), e),
+                    span,
+                ));
+                continue;
+            }
+        };
+
+        let name = included.display().to_string();
+        let statements = Parser::at_offset(&name, &source, sources.next_offset())
+            .parse_statements(problems, unparsed);
+        sources.add(name, source);
+        including.push(canonical_path);
+        let included_dir = dir.join(&path);
+        let included_dir = included_dir.parent().unwrap_or_else(|| Path::new(""));
+        expanded.extend(expand_includes(
+            statements,
+            &included,
+            included_dir,
+            sources,
+            including,
+            problems,
+            unparsed,
+        ));
+        including.pop();
+    }
+    expanded
+}
+
+/**
+ * Record `dir` in every definition in `statement`, including those in the bodies of loops and
+ * conditionals
+ */
+fn record_dir(statement: &mut Statement, dir: &Path) {
+    match statement {
+        Statement::Definition(definition) => definition.dir = dir.to_path_buf(),
+        Statement::For { body, .. } => body.iter_mut().for_each(|s| record_dir(s, dir)),
+        Statement::If {
+            then, otherwise, ..
+        } => then
+            .iter_mut()
+            .chain(otherwise)
+            .for_each(|s| record_dir(s, dir)),
+        Statement::Let { .. } | Statement::Include { .. } => {}
+    }
+}
+
+/**
+ * The canonical form of `path` for spotting the same file reached in different ways, or `path`
+ * itself if it can't be found
+ */
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/**
+ * The most definitions a scene can have once its loops have run, so that a mistake in a loop can't
+ * use up all the memory
+ */
+const MAX_DEFINITIONS: usize = 10_000;
+
+/**
+ * The most loop iterations each top-level statement of a scene can run, including those of loops
+ * that don't define anything
+ */
+const MAX_ITERATIONS: usize = 1_000_000;
+
+/**
+ * Run the statements, working out the value of every assignment in the order they are written so
+ * that each can use the variables bound before it, and running loops and conditionals. Definitions
+ * with errors are left out, and their names added to `unparsed`.
+ */
+fn evaluate(
+    statements: Vec<Statement>,
+    problems: &mut Vec<ParseError>,
+    unparsed: &mut Vec<String>,
+) -> Vec<RawDefinition> {
+    let mut evaluator = Evaluator {
+        problems,
+        unparsed,
+        definitions: vec![],
+        iterations: 0,
+    };
+    let mut environment = Environment::default();
+    for statement in statements.iter() {
+        evaluator.iterations = 0;
+        // Reaching a limit has already been reported. A loop that runs too long only stops the
+        // statement it is part of, but no more definitions fit once there are too many.
+        match evaluator.run(std::slice::from_ref(statement), &mut environment) {
+            Ok(()) | Err(LimitReached::Iterations) => {}
+            Err(LimitReached::Definitions) => break,
+        }
+    }
+    evaluator.definitions
+}
+
+/**
+ * One of the limits on how much a scene can generate has been reached
+ */
+enum LimitReached {
+    Iterations,
+    Definitions,
+}
+
+struct Evaluator<'p> {
+    problems: &'p mut Vec<ParseError>,
+    unparsed: &'p mut Vec<String>,
+    definitions: Vec<RawDefinition>,
+    iterations: usize,
+}
+
+impl Evaluator<'_> {
+    fn run(
+        &mut self,
+        statements: &[Statement],
+        environment: &mut Environment,
+    ) -> Result<(), LimitReached> {
+        for statement in statements {
+            match statement {
+                Statement::Let { name, value } => {
+                    let value = self.value(environment, value);
+                    environment.bind(name.clone(), value);
+                }
+                Statement::Include { .. } => {
+                    unreachable!("includes are expanded before evaluation")
+                }
+                Statement::Definition(defn) => self.define(defn, environment)?,
+                Statement::For {
+                    variable,
+                    values,
+                    body,
+                    span,
+                } => {
+                    let values = match self.iterable(values, environment, span) {
+                        Some(values) => values,
+                        None => continue,
+                    };
+
+                    let problems_before = self.problems.len();
+                    for value in values {
+                        self.iterations += 1;
+                        if self.iterations > MAX_ITERATIONS {
+                            self.problems.push(ParseError::new(
+                                format!("The loops here run more than {} times", MAX_ITERATIONS),
+                                span.clone(),
+                            ));
+                            return Err(LimitReached::Iterations);
+                        }
+                        let mut inner = environment.clone();
+                        inner.bind(variable.clone(), Some(value));
+                        self.run(body, &mut inner)?;
+                        // Later iterations would most likely report the same problems again
+                        if self.problems.len() > problems_before {
+                            break;
+                        }
+                    }
+                }
+                Statement::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => match environment.test(condition) {
+                    Ok(true) => self.run(then, &mut environment.clone())?,
+                    Ok(false) => self.run(otherwise, &mut environment.clone())?,
+                    Err(problem) => self.problems.extend(problem),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * The values a for loop runs over, or None if they have errors
+     */
+    fn iterable(
+        &mut self,
+        values: &Iterable,
+        environment: &Environment,
+        span: &Span,
+    ) -> Option<Box<dyn Iterator<Item = Value>>> {
+        match values {
+            Iterable::Range(start, end) => {
+                match (
+                    self.value(environment, start)?,
+                    self.value(environment, end)?,
+                ) {
+                    (Value::Num(start), Value::Num(end))
+                        if start.fract() == 0.0 && end.fract() == 0.0 =>
+                    {
+                        // Counted as floats, so that huge ranges reach the iteration limit rather
+                        // than overflowing
+                        let numbers = (0..).map(move |i| start + i as f64);
+                        Some(Box::new(
+                            numbers.take_while(move |&i| i < end).map(Value::Num),
+                        ))
+                    }
+                    _ => {
+                        self.problems.push(ParseError::new(
+                            "Expected the range of a for loop to be whole numbers",
+                            span.clone(),
+                        ));
+                        None
+                    }
+                }
+            }
+            Iterable::Array(array) => match self.value(environment, array)? {
+                Value::Array(elements) => Some(Box::new(elements.into_iter())),
+                value => {
+                    self.problems.push(ParseError::new(
+                        format!(
+                            "Expected a range or an array to loop over but got {}",
+                            describe(&value)
+                        ),
+                        array.span.clone(),
+                    ));
+                    None
+                }
+            },
+        }
+    }
+
+    /**
+     * The value of `expr`, or None if it has errors
+     */
+    fn value(&mut self, environment: &Environment, expr: &Expr) -> Option<Value> {
+        environment
+            .evaluate(expr)
+            .map_err(|problem| self.problems.extend(problem))
+            .ok()
+    }
+
+    fn define(
+        &mut self,
+        defn: &RawDefinition<Expr>,
+        environment: &Environment,
+    ) -> Result<(), LimitReached> {
+        if self.definitions.len() >= MAX_DEFINITIONS {
+            self.problems.push(ParseError::new(
+                format!("The scene has more than {} definitions", MAX_DEFINITIONS),
+                defn.span.clone(),
+            ));
+            return Err(LimitReached::Definitions);
+        }
+
+        match self.evaluate_definition(defn, environment) {
+            Some(defn) => self.definitions.push(defn),
+            None => self.unparsed.extend(defn.name.clone()),
+        }
+        Ok(())
+    }
+
+    /**
+     * The definition with the values of all of its assignments and those of its sub-blocks, or
+     * None if any of them have errors
+     */
+    fn evaluate_definition(
+        &mut self,
+        defn: &RawDefinition<Expr>,
+        environment: &Environment,
+    ) -> Option<RawDefinition> {
+        let mut assignments = vec![];
+        for assignment in defn.assignments.iter() {
+            if let Some(value) = self.value(environment, &assignment.value) {
+                assignments.push(Assignment {
+                    name: assignment.name.clone(),
+                    value,
+                    name_span: assignment.name_span.clone(),
+                    span: assignment.span.clone(),
+                });
+            }
+        }
+        let blocks: Vec<_> = defn
+            .blocks
+            .iter()
+            .filter_map(|block| self.evaluate_definition(block, environment))
+            .collect();
+
+        if assignments.len() < defn.assignments.len() || blocks.len() < defn.blocks.len() {
+            return None;
+        }
+        Some(RawDefinition {
+            def_type: defn.def_type.clone(),
+            name: defn.name.clone(),
+            assignments,
+            blocks,
+            span: defn.span.clone(),
+            dir: defn.dir.clone(),
+        })
+    }
+}
+
+pub(crate) struct Parser<'a> {
+    file: &'a str,
+    source: &'a str,
+    /// Where spans in this file start, as spans in included files follow on from those before
+    offset: usize,
+    src: Vec<(Token, Span)>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    /**
+     * A parser for `source`, the contents of `file`. Included files are found relative to `file`.
+     */
+    pub(crate) fn new(file: &'a str, source: &'a str) -> Self {
+        Self::at_offset(file, source, 0)
+    }
+
+    fn at_offset(file: &'a str, source: &'a str, offset: usize) -> Self {
+        let lexemes = lex(source)
+            .into_iter()
+            .map(|(token, span)| (token, span.start + offset..span.end + offset));
+        Self {
+            file,
+            source,
+            offset,
+            src: lexemes.collect(),
+            position: 0,
         }
+    }
 
-        match &light_type.unwrap()[..] {
-            "ambient" => {
-                if position.is_some() || direction.is_some() {
-                    return Err("Only type and intensity are supported for ambient lights".into());
-                }
-                Ok(LightDefinition::AmbientLight {
-                    intensity: intensity.unwrap(),
-                })
-            }
-            "point" => {
-                if position.is_none() {
-                    Err("point lights require a position".into())
-                } else if direction.is_some() {
-                    Err("point lights do not support the direction property".into())
-                } else {
-                    Ok(LightDefinition::PointLight {
-                        intensity: intensity.unwrap(),
-                        position: position.unwrap(),
-                    })
-                }
-            }
-            "directional" => {
-                if direction.is_none() {
-                    Err("directional lights require a direction".into())
-                } else if position.is_some() {
-                    Err("directional lights do not support the position property".into())
-                } else {
-                    Ok(LightDefinition::DirectionLight {
-                        intensity: intensity.unwrap(),
-                        direction: direction.unwrap(),
-                    })
-                }
+    fn peek(&self) -> Option<&Token> {
+        self.src.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let (token, _) = self.src.get(self.position)?;
+        self.position += 1;
+        Some(token)
+    }
+
+    /**
+     * The span of the next token, or of the end of the file
+     */
+    fn span(&self) -> Span {
+        match self.src.get(self.position) {
+            Some((_, span)) => span.clone(),
+            None => {
+                let end = self.offset + self.source.len();
+                end..end
             }
-            s => Err(format!("Unsupported light type: {}", s)),
         }
     }
 
-    fn sphere_from_raw(raw: RawDefinition) -> Result<SphereDefinition, String> {
-        let mut color = None;
-        let mut center = None;
-        let mut radius = None;
+    /**
+     * The span of the token most recently consumed
+     */
+    fn last_span(&self) -> Span {
+        match self.position.checked_sub(1).and_then(|i| self.src.get(i)) {
+            Some((_, span)) => span.clone(),
+            None => self.offset..self.offset,
+        }
+    }
 
-        for assignment in raw.assignments {
-            match &assignment.name[..] {
-                "color" => color = Some(Self::tuple_value(assignment.value, "color")?),
-                "center" => center = Some(Self::tuple_value(assignment.value, "center")?),
-                "radius" => radius = Some(Self::numeric_value(assignment.value, "radius")?),
-                s => {
-                    return Err(format!(
-                        "Expected properties: [color, center, radius] but got: '{}'",
-                        s
-                    ))
+    /**
+     * An error about the token most recently consumed
+     */
+    fn error_at_last(&self, message: String) -> ParseError {
+        let span = self.last_span();
+        let found = match &self.source[span.start - self.offset..span.end - self.offset] {
+            s if s.starts_with(['\r', '\n']) => "the end of the line".into(),
+            s => format!("'{}'", s),
+        };
+        ParseError::new(format!("{} but found {}", message, found), span)
+    }
+
+    /**
+     * Parse and check the whole file, carrying on after errors to find as many problems as
+     * possible. The scene definition is only given if there were no errors, but the diagnostics
+     * can include warnings either way.
+     */
+    pub(crate) fn parse(&mut self) -> (Option<SceneDefinition>, Vec<Diagnostic>) {
+        let mut problems: Vec<ParseError> = vec![];
+        let mut unparsed = vec![];
+        let mut sources = SourceMap::default();
+        sources.add(self.file, self.source);
+        let statements = self.parse_statements(&mut problems, &mut unparsed);
+        let mut including = vec![canonical(Path::new(self.file))];
+        let statements = expand_includes(
+            statements,
+            Path::new(self.file),
+            Path::new(""),
+            &mut sources,
+            &mut including,
+            &mut problems,
+            &mut unparsed,
+        );
+        let raw = evaluate(statements, &mut problems, &mut unparsed);
+        let definition = SceneDefinition::from_raw(
+            raw,
+            &unparsed,
+            self.offset + self.source.len(),
+            &mut problems,
+        );
+
+        let failed = problems.iter().any(ParseError::is_error);
+        // Spans run through the files in the order they were read, so this also groups by file
+        problems.sort_by_key(|problem| problem.span().start);
+        let diagnostics = problems
+            .into_iter()
+            .map(|problem| problem.locate(&sources))
+            .collect();
+        (if failed { None } else { Some(definition) }, diagnostics)
+    }
+
+    /**
+     * Only check the file's syntax, without following includes or checking what it defines
+     */
+    pub(crate) fn syntax_errors(&mut self) -> Vec<Diagnostic> {
+        let mut problems = vec![];
+        self.parse_statements(&mut problems, &mut vec![]);
+        let mut sources = SourceMap::default();
+        sources.add(self.file, self.source);
+        problems
+            .into_iter()
+            .map(|problem| problem.locate(&sources))
+            .collect()
+    }
+
+    /**
+     * Parse every statement that is free of syntax errors. The names of definitions with errors are
+     * added to `unparsed`.
+     */
+    fn parse_statements(
+        &mut self,
+        problems: &mut Vec<ParseError>,
+        unparsed: &mut Vec<String>,
+    ) -> Vec<Statement> {
+        let mut statements = vec![];
+        self.munch_newlines();
+        while self.peek().is_some() {
+            let name = match (self.src.get(self.position), self.src.get(self.position + 1)) {
+                (Some((Token::Identifier(_), _)), Some((Token::VString(name), _))) => {
+                    unescape(name).ok()
+                }
+                _ => None,
+            };
+            let start = self.position;
+            let statement = if self.at_include() {
+                self.parse_include()
+            } else {
+                self.parse_statement()
+            };
+            match statement {
+                Ok(statement) => statements.push(statement),
+                Err(problem) => {
+                    problems.push(problem);
+                    unparsed.extend(name);
+                    if self.at_keyword(start, "for") || self.at_keyword(start, "if") {
+                        self.skip_block(start);
+                    } else {
+                        self.recover(start);
+                    }
                 }
             }
+            self.munch_newlines();
         }
-        if color.is_none() || center.is_none() || radius.is_none() {
-            Err(
-                "Sphere definitions require [color, center, radius] but some values are missing"
-                    .into(),
-            )
+        statements
+    }
+
+    /**
+     * A let statement, a loop, a conditional or a definition
+     */
+    fn parse_statement(&mut self) -> Result<Statement, ParseError> {
+        if self.at_let() {
+            self.parse_let()
+        } else if self.at_keyword(self.position, "for") {
+            self.parse_for()
+        } else if self.at_keyword(self.position, "if") {
+            self.parse_if()
         } else {
-            Ok(SphereDefinition {
-                color: color.unwrap(),
-                center: center.unwrap(),
-                radius: radius.unwrap(),
-            })
+            self.parse_raw_definition().map(Statement::Definition)
         }
     }
 
-    fn numeric_value(value: Value, property: &str) -> Result<f64, String> {
-        match value {
-            Value::Num(n) => Ok(n),
-            _ => Err(format!(
-                "Expected number for property {} but got {:?}",
-                property, value
-            )),
+    /**
+     * Whether the token at `position` is the identifier `keyword`, used as a keyword rather than a
+     * definition type
+     */
+    fn at_keyword(&self, position: usize, keyword: &str) -> bool {
+        let token = |offset: usize| self.src.get(position + offset).map(|(t, _)| t);
+        match (token(0), token(1)) {
+            (Some(Token::Identifier(word)), next) => {
+                word == keyword && !matches!(next, Some(Token::LBrace | Token::VString(_)))
+            }
+            _ => false,
         }
     }
 
-    fn string_value(value: Value, property: &str) -> Result<String, String> {
-        match value {
-            Value::VString(s) => Ok(s),
-            _ => Err(format!(
-                "Expected string for property {} but got {:?}",
-                property, value
-            )),
+    /**
+     * Skip past the loop or conditional starting at `start`, up to its closing brace
+     */
+    fn skip_block(&mut self, start: usize) {
+        self.position = start;
+        let mut depth = 0;
+        while let Some(token) = self.next() {
+            match token {
+                Token::LBrace => depth += 1,
+                Token::RBrace if depth <= 1 => return,
+                Token::RBrace => depth -= 1,
+                _ => {}
+            }
         }
     }
 
-    fn tuple_value(value: Value, property: &str) -> Result<(f64, f64, f64), String> {
-        match value {
-            Value::Tuple(t) => Ok(t),
-            _ => Err(format!(
-                "Expected tuple for property {} but got {:?}",
-                property, value
-            )),
+    fn parse_for(&mut self) -> Result<Statement, ParseError> {
+        let start = self.span().start;
+        self.next();
+        let variable = self.expect_ident("Expected a variable name after for")?;
+        match self.next() {
+            Some(Token::Identifier(word)) if word == "in" => {}
+            _ => {
+                return Err(
+                    self.error_at_last("Expected in after the variable of a for loop".into())
+                )
+            }
         }
-    }
-}
+        let first = self.parse_expr()?;
+        let values = if self.peek() == Some(&Token::DotDot) {
+            self.next();
+            Iterable::Range(first, self.parse_expr()?)
+        } else {
+            Iterable::Array(first)
+        };
+        let span = start..self.last_span().end;
+        let body = self.parse_block()?;
 
-pub(crate) struct WindowDefinition {
-    pub title: Option<String>,
-    pub width: Option<f64>,
-    pub height: Option<f64>,
-}
+        Ok(Statement::For {
+            variable,
+            values,
+            body,
+            span,
+        })
+    }
 
-pub(crate) enum LightDefinition {
-    PointLight {
-        intensity: f64,
-        position: (f64, f64, f64),
-    },
-    DirectionLight {
-        intensity: f64,
-        direction: (f64, f64, f64),
-    },
-    AmbientLight {
-        intensity: f64,
-    },
-}
+    fn parse_if(&mut self) -> Result<Statement, ParseError> {
+        self.next();
+        let condition = self.parse_condition()?;
+        let then = self.parse_block()?;
+        let otherwise = match self.peek() {
+            Some(Token::Identifier(word)) if word == "else" => {
+                self.next();
+                if self.at_keyword(self.position, "if") {
+                    vec![self.parse_if()?]
+                } else {
+                    self.parse_block()?
+                }
+            }
+            _ => vec![],
+        };
 
-pub(crate) struct SphereDefinition {
-    pub color: (f64, f64, f64),
-    pub center: (f64, f64, f64),
-    pub radius: f64,
-}
+        Ok(Statement::If {
+            condition,
+            then,
+            otherwise,
+        })
+    }
 
-struct RawDefinition {
-    def_type: String,
-    assignments: Vec<Assignment>,
-}
+    /**
+     * Two expressions compared with one of < > <= >= == !=
+     */
+    fn parse_condition(&mut self) -> Result<Condition, ParseError> {
+        let left = self.parse_expr()?;
+        let op = match self.next() {
+            Some(Token::Less) => CompareOp::Less,
+            Some(Token::Greater) => CompareOp::Greater,
+            Some(Token::LessEqual) => CompareOp::LessEqual,
+            Some(Token::GreaterEqual) => CompareOp::GreaterEqual,
+            Some(Token::EqualEqual) => CompareOp::Equal,
+            Some(Token::NotEqual) => CompareOp::NotEqual,
+            _ => {
+                return Err(self.error_at_last(
+                    "Expected a comparison (< > <= >= == !=) in the condition".into(),
+                ))
+            }
+        };
+        let right = self.parse_expr()?;
+        Ok(Condition {
+            span: left.span.start..right.span.end,
+            left,
+            op,
+            right,
+        })
+    }
 
-struct Assignment {
-    name: String,
-    value: Value,
-}
+    /**
+     * Statements between curly braces, as the body of a loop or conditional
+     */
+    fn parse_block(&mut self) -> Result<Vec<Statement>, ParseError> {
+        self.expect(&Token::LBrace, "Expected a curly brace '{' to open a block")?;
+        let mut statements = vec![];
+        loop {
+            self.munch_newlines();
+            match self.peek() {
+                Some(Token::RBrace) => {
+                    self.next();
+                    return Ok(statements);
+                }
+                None => {
+                    return Err(ParseError::new(
+                        "Unexpected end of file in a block; expected a closing brace '}'",
+                        self.span(),
+                    ))
+                }
+                Some(_) if self.at_include() => {
+                    return Err(ParseError::new(
+                        "Files can only be included at the top level",
+                        self.span(),
+                    ))
+                }
+                Some(_) => statements.push(self.parse_statement()?),
+            }
+        }
+    }
 
-#[derive(Debug)]
-enum Value {
-    Num(f64),
-    VString(String),
-    Tuple((f64, f64, f64)),
-}
+    /**
+     * Whether the next tokens start a let statement rather than a definition
+     */
+    fn at_let(&self) -> bool {
+        let token = |offset: usize| self.src.get(self.position + offset).map(|(t, _)| t);
+        matches!(
+            (token(0), token(1)),
+            (Some(Token::Identifier(keyword)), Some(Token::Identifier(_))) if keyword == "let"
+        )
+    }
 
-pub(crate) struct Parser {
-    src: Vec<Token>,
-    position: usize,
-}
+    /**
+     * Whether the next tokens are include and the quoted path of a file
+     */
+    fn at_include(&self) -> bool {
+        let token = |offset: usize| self.src.get(self.position + offset).map(|(t, _)| t);
+        matches!(
+            (token(0), token(1), token(2)),
+            (Some(Token::Identifier(keyword)), Some(Token::VString(_)), next)
+                if keyword == "include" && next != Some(&Token::LBrace)
+        )
+    }
 
-impl Parser {
-    pub(crate) fn new(src: &str) -> Self {
-        let lexemes = Token::lexer(src);
-        Self {
-            src: lexemes.collect(),
-            position: 0,
+    fn parse_include(&mut self) -> Result<Statement, ParseError> {
+        self.next();
+        let span = self.span();
+        let path = match self.next() {
+            Some(Token::VString(path)) => unescape(path).at(&span)?,
+            _ => unreachable!("at_include checks for the path"),
+        };
+        if self.peek().is_some() {
+            self.expect(
+                &Token::NewLine,
+                "Expect include statements to be terminated by newlines",
+            )?;
         }
+        Ok(Statement::Include { path, span })
     }
 
-    fn peek<'a>(&'a self) -> Option<&'a Token> {
-        if self.position >= self.src.len() {
-            None
-        } else {
-            Some(&self.src[self.position])
+    fn parse_let(&mut self) -> Result<Statement, ParseError> {
+        self.next();
+        let name = self.expect_ident("Expected a variable name after let")?;
+        self.expect(&Token::Equal, "Expected = when parsing let")?;
+        let value = self.parse_expr()?;
+        if self.peek().is_some() {
+            self.expect(
+                &Token::NewLine,
+                "Expect let statements to be terminated by newlines",
+            )?;
         }
+        Ok(Statement::Let { name, value })
     }
 
-    fn next<'a>(&'a mut self) -> Option<&'a Token> {
-        if self.position >= self.src.len() {
-            None
-        } else {
-            let res = Some(&self.src[self.position]);
-            self.position += 1;
-            res
+    /**
+     * Skip past the rest of a definition with a syntax error: either to just after its closing brace
+     * or, if it seems to be missing, to the start of the next definition
+     */
+    fn recover(&mut self, start: usize) {
+        // Sub-blocks have braces of their own, so count how deep in the definition the error is
+        let mut depth = 0;
+        let mut opened = false;
+        for (token, _) in self.src[start..self.position].iter() {
+            match token {
+                Token::LBrace => {
+                    depth += 1;
+                    opened = true;
+                }
+                Token::RBrace => depth -= 1,
+                _ => {}
+            }
+        }
+        // The error was at the definition's closing brace
+        if opened && depth <= 0 {
+            return;
+        }
+        loop {
+            match self.peek() {
+                None => return,
+                Some(Token::LBrace) => {
+                    self.next();
+                    depth += 1;
+                }
+                Some(Token::RBrace) => {
+                    self.next();
+                    depth -= 1;
+                    if depth <= 0 {
+                        return;
+                    }
+                }
+                Some(Token::NewLine) => {
+                    self.next();
+                    if self.at_definition_start() {
+                        return;
+                    }
+                }
+                Some(_) => {
+                    self.next();
+                }
+            }
         }
     }
 
-    pub(crate) fn parse(&mut self) -> Result<SceneDefinition, String> {
-        let mut definitions = vec![];
-        self.munch_newlines();
-        while let Some(x) = self.peek() {
-            definitions.push(self.parse_raw_definition()?);
-            self.munch_newlines();
+    /**
+     * Whether the next tokens are a definition type, optionally a name, and an opening brace, or
+     * the start of some other statement
+     */
+    fn at_definition_start(&self) -> bool {
+        if self.at_let()
+            || self.at_include()
+            || self.at_keyword(self.position, "for")
+            || self.at_keyword(self.position, "if")
+        {
+            return true;
+        }
+        // Sub-blocks look like definitions too, so only the types of definition count
+        let token = |offset: usize| self.src.get(self.position + offset).map(|(t, _)| t);
+        match (token(0), token(1), token(2)) {
+            (Some(Token::Identifier(def_type)), Some(Token::LBrace), _)
+            | (Some(Token::Identifier(def_type)), Some(Token::VString(_)), Some(Token::LBrace)) => {
+                DEFINITION_TYPES.contains(&&def_type[..])
+            }
+            _ => false,
         }
-
-        SceneDefinition::from_raw(definitions)
     }
 
     fn munch_newlines(&mut self) {
@@ -283,116 +2569,562 @@ impl Parser {
         }
     }
 
-    fn parse_raw_definition(&mut self) -> Result<RawDefinition, String> {
+    fn parse_raw_definition(&mut self) -> Result<RawDefinition<Expr>, ParseError> {
+        let start = self.span().start;
         let def_type =
             self.expect_ident("Object definitions should start with a definition type")?;
+        let name = match self.peek() {
+            Some(Token::VString(s)) => {
+                let name = unescape(s).at(&self.span())?;
+                self.next();
+                Some(name)
+            }
+            _ => None,
+        };
+        let span = start..self.last_span().end;
         self.expect(
             &Token::LBrace,
             "A definition should be opened by a curly brace '{'",
         )?;
         self.munch_newlines();
         let mut assignments = vec![];
-        assignments.push(self.parse_assignment()?);
+        let mut blocks = vec![];
+        self.parse_member(&mut assignments, &mut blocks)?;
         loop {
             match self.peek() {
-                None => return Err("Unexpected EOF when parsing definition".into()),
-                Some(Token::NewLine) => { self.next(); }
+                None => return Err(ParseError::new(
+                    "Unexpected end of file when parsing definition; expected a closing brace '}'",
+                    self.span(),
+                )),
+                Some(Token::NewLine) => {
+                    self.next();
+                }
                 Some(Token::RBrace) => {
                     self.next();
-                    break
-                },
-                Some(Token::Identifier(_)) => {
-                    assignments.push(self.parse_assignment()?)
+                    break;
+                }
+                Some(Token::Identifier(_)) => self.parse_member(&mut assignments, &mut blocks)?,
+                Some(_) => {
+                    self.next();
+                    return Err(self.error_at_last(
+                        "Expected an assignment or a closing brace when parsing definition".into(),
+                    ));
                 }
-                Some(tok) => return Err(format!("Unexpected token {:?} when parsing definition; expected assignment or closing brace", tok))
             }
         }
 
         Ok(RawDefinition {
             def_type,
+            name,
             assignments,
+            blocks,
+            span,
+            dir: PathBuf::new(),
         })
     }
 
-    fn parse_assignment(&mut self) -> Result<Assignment, String> {
+    /**
+     * Either an assignment or a sub-block, which is written like a definition without a name
+     */
+    fn parse_member(
+        &mut self,
+        assignments: &mut Vec<Assignment<Expr>>,
+        blocks: &mut Vec<RawDefinition<Expr>>,
+    ) -> Result<(), ParseError> {
+        let token = |offset: usize| self.src.get(self.position + offset).map(|(t, _)| t);
+        if let (Some(Token::Identifier(_)), Some(Token::LBrace)) = (token(0), token(1)) {
+            blocks.push(self.parse_raw_definition()?);
+        } else {
+            assignments.push(self.parse_assignment()?);
+        }
+        Ok(())
+    }
+
+    fn parse_assignment(&mut self) -> Result<Assignment<Expr>, ParseError> {
+        let name_span = self.span();
         let name = self.expect_ident("Assignments should start with identifiers")?;
         self.expect(&Token::Equal, "Expected = when parsing assignment")?;
-        let value = self.parse_value()?;
+        let value = self.parse_expr()?;
+        let span = value.span.clone();
         self.expect(
             &Token::NewLine,
             "Expect assignments to be terminated by newlines",
         )?;
 
-        Ok(Assignment { name, value })
+        Ok(Assignment {
+            name,
+            value,
+            name_span,
+            span,
+        })
     }
 
-    fn parse_value(&mut self) -> Result<Value, String> {
-        match self.next() {
-            None => Err("Unexpected EOF when parsing a value".into()),
-            Some(v) => match v {
-                Token::Number(n) => Ok(Value::Num(*n)),
-                Token::VString(s) => Ok(Value::VString(strip_speechmarks(s.clone()))),
-                Token::Identifier(s) => Ok(Value::VString(s.clone())),
-                Token::LParen => self.parse_tuple(),
-                _ => Err(format!("Invalid value: {:?}", v)),
-            },
+    /**
+     * An expression: terms joined by + and -
+     */
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.parse_term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => BinaryOp::Add,
+                Some(Token::Minus) => BinaryOp::Subtract,
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.parse_term()?;
+            left = binary_expr(op, left, right);
         }
     }
 
-    fn parse_tuple(&mut self) -> Result<Value, String> {
-        let num1 = self.expect_number("Tuples can only contain numbers")?;
-        self.expect(
-            &Token::Comma,
-            "Expected a comma to separate values in tuple",
-        )?;
-        let num2 = self.expect_number("Tuples can only contain numbers")?;
-        self.expect(
-            &Token::Comma,
-            "Expected a comma to separate values in tuple",
-        )?;
-        let num3 = self.expect_number("Tuples can only contain number")?;
-        self.expect(&Token::RParen, "Expected a right paren to close tuple")?;
+    /**
+     * Factors joined by *, / and %, which bind more tightly than + and -
+     */
+    fn parse_term(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => BinaryOp::Multiply,
+                Some(Token::Slash) => BinaryOp::Divide,
+                Some(Token::Percent) => BinaryOp::Remainder,
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.parse_unary()?;
+            left = binary_expr(op, left, right);
+        }
+    }
 
-        Ok(Value::Tuple((num1, num2, num3)))
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&Token::Minus) {
+            let start = self.span().start;
+            self.next();
+            let operand = self.parse_unary()?;
+            return Ok(Expr {
+                span: start..operand.span.end,
+                kind: ExprKind::Negate(Box::new(operand)),
+            });
+        }
+        self.parse_value()
     }
 
-    fn expect<'a>(&'a mut self, expected: &Token, failed_match: &str) -> Result<&'a Token, String> {
-        let actual = self
-            .next()
-            .ok_or(format!("Expected {:?} but got EOF", expected))?;
+    fn parse_value(&mut self) -> Result<Expr, ParseError> {
+        let span = self.span();
+        let kind = match self.next() {
+            None => {
+                return Err(ParseError::new(
+                    "Unexpected end of file when parsing a value",
+                    span,
+                ))
+            }
+            Some(Token::Number(n)) => ExprKind::Literal(Value::Num(*n)),
+            Some(Token::VString(s)) => ExprKind::Literal(Value::VString(unescape(s).at(&span)?)),
+            Some(Token::Identifier(s)) => {
+                let name = s.clone();
+                if self.peek() == Some(&Token::LParen) {
+                    self.next();
+                    ExprKind::Call(name, self.parse_arguments()?)
+                } else {
+                    ExprKind::Name(name)
+                }
+            }
+            Some(Token::HexColor(hex)) => {
+                ExprKind::Literal(Value::Color(parse_hex_color(hex).at(&span)?))
+            }
+            Some(Token::LParen) => {
+                let mut elements = self.parse_list(&Token::RParen, "tuple")?;
+                match elements.len() {
+                    1 => elements.remove(0).kind,
+                    2..=4 => ExprKind::Tuple(elements),
+                    n => {
+                        return Err(ParseError::new(
+                            format!("Tuples have from 2 to 4 values but this one has {}", n),
+                            span.start..self.last_span().end,
+                        ))
+                    }
+                }
+            }
+            Some(Token::LBracket) => ExprKind::Array(self.parse_list(&Token::RBracket, "array")?),
+            Some(_) => return Err(self.error_at_last("Expected a value".into())),
+        };
+        Ok(Expr {
+            kind,
+            span: span.start..self.last_span().end,
+        })
+    }
 
-        if actual == expected {
-            Ok(actual)
-        } else {
-            return Err(format!("Error: {} on token {:?}", failed_match, actual));
+    /**
+     * Comma separated expressions up to `close`, after the bracket that opened them. Arrays may
+     * be empty, may span several lines and may have a comma after their last element.
+     */
+    fn parse_list(&mut self, close: &Token, what: &str) -> Result<Vec<Expr>, ParseError> {
+        let multiline = *close == Token::RBracket;
+        let mut elements = vec![];
+        loop {
+            if multiline {
+                self.munch_newlines();
+                if self.peek() == Some(close) {
+                    self.next();
+                    return Ok(elements);
+                }
+            }
+            elements.push(self.parse_expr()?);
+            if multiline {
+                self.munch_newlines();
+            }
+            let span = self.span();
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(token) if token == close => return Ok(elements),
+                Some(_) => {
+                    return Err(
+                        self.error_at_last(format!("Expected a comma or the end of the {}", what))
+                    )
+                }
+                None => {
+                    return Err(ParseError::new(
+                        format!("Unexpected end of file in {}", what),
+                        span,
+                    ))
+                }
+            }
         }
     }
 
-    fn expect_number(&mut self, err: &str) -> Result<f64, String> {
-        let value = self
-            .next()
-            .ok_or(format!("Expected a number but got EOF"))?;
-
-        if let Token::Number(n) = value {
-            Ok(*n)
-        } else {
-            return Err(format!("Error: {} on token {:?}", err, value));
+    /**
+     * The comma separated arguments of a function call, after its opening paren
+     */
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut arguments = vec![];
+        if self.peek() == Some(&Token::RParen) {
+            self.next();
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.parse_expr()?);
+            let span = self.span();
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(Token::RParen) => return Ok(arguments),
+                Some(_) => {
+                    return Err(self.error_at_last(
+                        "Expected a comma or a right paren in the arguments of a function".into(),
+                    ))
+                }
+                None => {
+                    return Err(ParseError::new(
+                        "Unexpected end of file in the arguments of a function",
+                        span,
+                    ))
+                }
+            }
         }
     }
 
-    fn expect_ident(&mut self, err: &str) -> Result<String, String> {
-        let value = self
-            .next()
-            .ok_or(format!("Expected an ident but got EOF"))?;
+    fn expect(&mut self, expected: &Token, failed_match: &str) -> Result<(), ParseError> {
+        let span = self.span();
+        match self.next() {
+            None => Err(ParseError::new(
+                format!("{} but found the end of the file", failed_match),
+                span,
+            )),
+            Some(actual) if actual == expected => Ok(()),
+            Some(_) => Err(self.error_at_last(failed_match.into())),
+        }
+    }
 
-        if let Token::Identifier(s) = value {
-            Ok(s.clone())
-        } else {
-            return Err(format!("Error: {} on token {:?}", err, value));
+    fn expect_ident(&mut self, err: &str) -> Result<String, ParseError> {
+        let span = self.span();
+        match self.next() {
+            None => Err(ParseError::new(
+                format!("{} but found the end of the file", err),
+                span,
+            )),
+            Some(Token::Identifier(s)) => Ok(s.clone()),
+            Some(_) => Err(self.error_at_last(err.into())),
         }
     }
 }
 
-fn strip_speechmarks(src: String) -> String {
-    src.trim_matches('"').into()
+fn binary_expr(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+    Expr {
+        span: left.span.start..right.span.end,
+        kind: ExprKind::Binary(op, Box::new(left), Box::new(right)),
+    }
+}
+
+/**
+ * The channels of a color written as rrggbb or rgb, between 0 and 1
+ */
+fn parse_hex_color(hex: &str) -> Result<(f64, f64, f64), String> {
+    let digits: Vec<u32> = hex
+        .chars()
+        .map(|c| c.to_digit(16).ok_or(format!("Invalid hex color: #{}", hex)))
+        .collect::<Result<_, _>>()?;
+    let channel = |value: u32| value as f64 / 255.0;
+    match digits[..] {
+        [r1, r0, g1, g0, b1, b0] => Ok((
+            channel(r1 * 16 + r0),
+            channel(g1 * 16 + g0),
+            channel(b1 * 16 + b0),
+        )),
+        // Each digit is repeated, so #f80 is #ff8800
+        [r, g, b] => Ok((channel(r * 17), channel(g * 17), channel(b * 17))),
+        _ => Err(format!("Invalid hex color: #{}", hex)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::error::{Location, Severity};
+    use super::*;
+
+    fn messages(source: &str) -> Vec<String> {
+        let (_, diagnostics) = Parser::new("test.grfx", source).parse();
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    /**
+     * The line, column and number of characters underlined for each problem
+     */
+    fn positions(source: &str) -> Vec<(usize, usize, usize)> {
+        let (_, diagnostics) = Parser::new("test.grfx", source).parse();
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| match diagnostic.location {
+                Location::Source {
+                    line,
+                    column,
+                    width,
+                    ..
+                } => (line, column, width),
+                Location::Field(field) => panic!("scene files have lines, not {}", field),
+            })
+            .collect()
+    }
+
+    /**
+     * Write `files` into a new directory for the test called `test`, returning the path of the
+     * first
+     */
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("giraffics-{}-{}", test, std::process::id()));
+        for (name, source) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    fn parse_file(path: &Path) -> (Option<SceneDefinition>, Vec<Diagnostic>) {
+        let file = path.display().to_string();
+        let source = fs::read_to_string(path).unwrap();
+        Parser::new(&file, &source).parse()
+    }
+
+    #[test]
+    fn problems_underline_what_is_wrong() {
+        let source = "sphere {\n    radius = 1\n    center = (0, 0, 5)\n    colour = #fff\n}\n";
+        assert!(messages(source)[0].ends_with("but got: 'colour'"));
+        assert_eq!(positions(source), [(4, 5, 6)]);
+        // Just the value when it is the wrong kind
+        assert_eq!(
+            positions("sphere {\n    radius = \"big\"\n    center = (0, 0, 5)\n}\n"),
+            [(2, 14, 5)]
+        );
+    }
+
+    #[test]
+    fn parsing_carries_on_after_a_broken_definition() {
+        let source = "sphere {\n    radius 1\n}\n\nlight {\n    type = ambient\n    intensity = 0.2\n}\n\ncamera {\n    aperture = \n}\n";
+        let (definition, diagnostics) = Parser::new("test.grfx", source).parse();
+        assert!(definition.is_none());
+        let lines: Vec<_> = positions(source).iter().map(|&(line, _, _)| line).collect();
+        assert_eq!(lines, [2, 11], "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn warnings_do_not_stop_the_scene_loading() {
+        let source = "sphere {\n    radius = 1\n    radius = 2\n    center = (0, 0, 5)\n    color = #fff\n}\n";
+        let (definition, diagnostics) = Parser::new("test.grfx", source).parse();
+        assert_eq!(definition.map(|d| d.spheres[0].radius), Some(2.0));
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        // The value that is ignored is underlined
+        assert_eq!(positions(source)[0].0, 2);
+    }
+
+    #[test]
+    fn values_of_the_wrong_kind_are_described() {
+        assert_eq!(
+            messages("window {\n    title = (1, 2, 3)\n}\n"),
+            ["Expected string for property title but got the tuple (1, 2, 3)"]
+        );
+        assert_eq!(
+            messages("fog {\n    color = 5\n    density = 0.1\n}\n"),
+            ["Expected a color for property color but got the number 5"]
+        );
+        assert_eq!(
+            messages("camera {\n    aperture = \"wide\"\n    focal_distance = 1\n}\n"),
+            ["Expected number for property aperture but got the string \"wide\""]
+        );
+    }
+
+    #[test]
+    fn names_where_numbers_are_expected_are_unknown_variables() {
+        assert_eq!(
+            messages("camera {\n    aperture = oops\n    focal_distance = 1\n}\n"),
+            ["Unknown variable 'oops'"]
+        );
+    }
+
+    #[test]
+    fn dividing_by_zero_is_an_error() {
+        let source = "let a = 1\nlet b = a / (a - 1)\n";
+        assert_eq!(messages(source), ["Division by zero"]);
+        assert_eq!(positions(source), [(2, 9, 11)]);
+    }
+
+    #[test]
+    fn expressions_are_evaluated() {
+        let source = "let r = 2\nsphere {\n    radius = r * 3 - 1\n    center = (1, 2, 3) * 2 + (0, 0, -r)\n    color = #fff\n}\n";
+        let (definition, diagnostics) = Parser::new("test.grfx", source).parse();
+        let definition = definition.unwrap_or_else(|| panic!("{:?}", diagnostics));
+        assert_eq!(definition.spheres[0].radius, 5.0);
+        assert_eq!(definition.spheres[0].center, (2.0, 4.0, 4.0));
+    }
+
+    #[test]
+    fn only_unused_definitions_in_the_root_file_are_reported() {
+        let library =
+            "material \"red\" {\n    color = #f00\n}\n\nmaterial \"blue\" {\n    color = #00f\n}\n";
+        let scene = "include \"library.grfx\"\n\ntexture \"checks\" {\n    type = checker\n    primary = #fff\n    secondary = #000\n}\n\nsphere {\n    radius = 1\n    center = (0, 0, 5)\n    material = \"red\"\n}\n";
+        let path = write_files(
+            "unused",
+            &[("scene.grfx", scene), ("library.grfx", library)],
+        );
+        let (definition, diagnostics) = parse_file(&path);
+        assert!(definition.is_some());
+        let messages: Vec<_> = diagnostics.iter().map(|d| &d.message[..]).collect();
+        assert_eq!(messages, ["The texture 'checks' is never used"]);
+        assert_eq!(diagnostics[0].file, path.display().to_string());
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let path = write_files(
+            "cycle",
+            &[
+                ("a.grfx", "include \"b.grfx\"\n"),
+                ("b.grfx", "include \"a.grfx\"\n"),
+            ],
+        );
+        let (definition, diagnostics) = parse_file(&path);
+        assert!(definition.is_none());
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert!(diagnostics[0]
+            .message
+            .starts_with("Files include each other in a cycle: "));
+        assert!(diagnostics[0].file.ends_with("b.grfx"));
+    }
+
+    #[test]
+    fn files_named_in_included_files_are_relative_to_them() {
+        let materials =
+            "texture \"wood\" {\n    type = image\n    file = \"textures/wood.png\"\n}\n";
+        let scene = "include \"lib/materials.grfx\"\n\nsphere {\n    radius = 1\n    center = (0, 0, 5)\n    color = \"wood\"\n}\n\nrender {\n    output = \"out/scene.png\"\n}\n";
+        let path = write_files(
+            "relative",
+            &[("scene.grfx", scene), ("lib/materials.grfx", materials)],
+        );
+        let (definition, diagnostics) = parse_file(&path);
+        let definition = definition.unwrap_or_else(|| panic!("{:?}", diagnostics));
+        let wood = Path::new("lib").join("textures").join("wood.png");
+        match &definition.textures[0].kind {
+            TextureKind::Image { file, .. } => assert_eq!(*file, wood.display().to_string()),
+            TextureKind::Pattern { .. } => panic!("wood should be an image texture"),
+        }
+        // Files named in the scene file itself are left as they are
+        let output = definition.render.and_then(|render| render.output);
+        assert_eq!(output.as_deref(), Some("out/scene.png"));
+    }
+
+    #[test]
+    fn files_named_in_loops_in_included_files_are_relative_to_them() {
+        let looks = "if 1 < 2 {\n    post {\n        lut = \"warm.cube\"\n    }\n}\n";
+        let path = write_files(
+            "relative-loops",
+            &[
+                ("scene.grfx", "include \"looks/warm.grfx\"\n"),
+                ("looks/warm.grfx", looks),
+            ],
+        );
+        let (definition, diagnostics) = parse_file(&path);
+        let definition = definition.unwrap_or_else(|| panic!("{:?}", diagnostics));
+        let lut = Path::new("looks").join("warm.cube").display().to_string();
+        match &definition.post.as_deref() {
+            Some([PassDefinition::Lut(file)]) => assert_eq!(*file, lut),
+            _ => panic!("the post definition should hold just the LUT"),
+        }
+    }
+
+    #[test]
+    fn loops_can_only_define_so_much() {
+        let source = "for i in 0..20000 {\n    light {\n        type = ambient\n        intensity = 0\n    }\n}\n";
+        assert_eq!(
+            messages(source),
+            ["The scene has more than 10000 definitions"]
+        );
+        assert_eq!(
+            messages("for i in 0..1000 {\n    for j in 0..1001 {\n        let k = j\n    }\n}\n"),
+            ["The loops here run more than 1000000 times"]
+        );
+    }
+
+    #[test]
+    fn a_loop_that_runs_too_long_only_stops_its_own_statement() {
+        let source = "for i in 0..2000000 {\n    let x = i\n}\n\nlet n = 2\n\ncamera {\n    aperture = m\n    focal_distance = n\n}\n";
+        assert_eq!(
+            messages(source),
+            [
+                "The loops here run more than 1000000 times",
+                "Unknown variable 'm'"
+            ]
+        );
+    }
+
+    #[test]
+    fn transforms_move_and_scale_spheres() {
+        let source = "sphere {\n    center = (1, 0, 0)\n    radius = 1\n    color = #fff\n    transform {\n        scale = 2\n        rotate = (0, 0, 1, deg(90))\n        translate = (0, 0, 5)\n    }\n}\n";
+        let (definition, diagnostics) = Parser::new("test.grfx", source).parse();
+        let definition = definition.unwrap_or_else(|| panic!("{:?}", diagnostics));
+        let sphere = &definition.spheres[0];
+        let (x, y, z) = sphere.center;
+        assert!(x.abs() < 1e-9 && (y - 2.0).abs() < 1e-9 && (z - 5.0).abs() < 1e-9);
+        assert_eq!(sphere.radius, 2.0);
+    }
+
+    #[test]
+    fn tuples_only_combine_with_tuples_of_the_same_size() {
+        assert_eq!(
+            messages("let v = (1, 2, 3, 4) + (1, 1, 1)\n"),
+            ["Can't work out the tuple (1, 2, 3, 4) + the tuple (1, 1, 1)"]
+        );
+        assert_eq!(
+            messages("sphere {\n    radius = 1\n    center = (1, 2) * 2\n    color = #fff\n}\n"),
+            ["Expected a tuple of 3 numbers for property center but got the tuple (2, 4)"]
+        );
+    }
+
+    #[test]
+    fn for_loops_run_over_arrays() {
+        let source = "for x in [1, 2, 4] {\n    sphere {\n        radius = 1\n        center = (x, 0, 5)\n        color = #fff\n    }\n}\n";
+        let (definition, diagnostics) = Parser::new("test.grfx", source).parse();
+        let definition = definition.unwrap_or_else(|| panic!("{:?}", diagnostics));
+        let xs: Vec<_> = definition.spheres.iter().map(|s| s.center.0).collect();
+        assert_eq!(xs, [1.0, 2.0, 4.0]);
+    }
 }
@@ -0,0 +1,892 @@
+use super::parser::{
+    BackgroundDefinition, BackgroundKind, CameraDefinition, ColorDefinition, FogDefinition,
+    LightDefinition, MaterialDefinition, MaterialSource, PassDefinition, RenderDefinition,
+    SceneDefinition, SphereDefinition, TextureDefinition, TextureKind, WindowDefinition,
+};
+use crate::color::ColorSpace;
+use crate::raster::{Filter, Wrap};
+use crate::scene::texture::Pattern;
+use crate::scene::Integrator;
+use std::fmt;
+
+/**
+ * A definition ready to be written out: its header, such as `texture "wood"`, and its properties
+ * in the order they are written. Each line also records the field of the definition types it was
+ * written from, such as spheres[0].radius, so that problems found in it can be traced back.
+ */
+struct Definition {
+    header: String,
+    /// The field the definition was written from, such as spheres[0]
+    path: String,
+    /// The field the header was written from, which is the name of named definitions
+    header_field: String,
+    /// The field that properties are written from, unless they say otherwise
+    scope: String,
+    properties: Vec<Property>,
+}
+
+struct Property {
+    name: &'static str,
+    value: String,
+    field: String,
+}
+
+impl Definition {
+    fn new(header: impl Into<String>, path: String) -> Self {
+        Self {
+            header: header.into(),
+            header_field: path.clone(),
+            scope: path.clone(),
+            path,
+            properties: vec![],
+        }
+    }
+
+    /**
+     * A definition with a name, such as `texture "wood"`, written from the element of a list at
+     * `path`
+     */
+    fn named(def_type: &str, name: &str, path: String) -> Self {
+        Self {
+            header_field: format!("{}.name", path),
+            ..Self::new(format!("{} {}", def_type, string(name)), path)
+        }
+    }
+
+    /**
+     * Write later properties from fields within `field` of the current scope
+     */
+    fn enter(&mut self, field: &str) {
+        self.scope = format!("{}.{}", self.scope, field);
+    }
+
+    fn property(&mut self, name: &'static str, value: String) {
+        let field = format!("{}.{}", self.scope, name);
+        self.property_from(name, field, value);
+    }
+
+    /**
+     * A property written from a field not named after it
+     */
+    fn property_from(&mut self, name: &'static str, field: String, value: String) {
+        self.properties.push(Property { name, value, field });
+    }
+
+    fn optional<T>(&mut self, name: &'static str, value: Option<T>, show: impl Fn(T) -> String) {
+        if let Some(value) = value {
+            self.property(name, show(value));
+        }
+    }
+}
+
+impl fmt::Display for Definition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {{", self.header)?;
+        for property in self.properties.iter() {
+            writeln!(f, "    {} = {}", property.name, property.value)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/**
+ * Writes the scene back out as a scene file, in the layout `giraffics fmt` gives, which parses to
+ * an equal definition. Everything is written literally: colors with rgbf, since channels from 0
+ * to 1 can't always be written exactly as (red, green, blue) tuples, and each definition on its
+ * own, as it was after any loops and transforms had been applied.
+ *
+ * Only definitions are printed, not a loaded `Scene`: by then textures and images have been loaded
+ * and materials resolved, and the file names and texture names needed to write them out are gone.
+ * To save a scene, print the definition it is loaded from.
+ */
+impl fmt::Display for SceneDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Printed::new(self).source)
+    }
+}
+
+/**
+ * A scene written out as a scene file, along with the field of the definition types that each
+ * line was written from
+ */
+pub(crate) struct Printed {
+    pub source: String,
+    fields: Vec<String>,
+}
+
+impl Printed {
+    pub(crate) fn new(scene: &SceneDefinition) -> Self {
+        let mut source = String::new();
+        let mut fields = vec![];
+        for (i, definition) in definitions(scene).iter().enumerate() {
+            if i > 0 {
+                source.push('\n');
+                fields.push(String::new());
+            }
+            source.push_str(&definition.to_string());
+            fields.push(definition.header_field.clone());
+            fields.extend(definition.properties.iter().map(|p| p.field.clone()));
+            fields.push(definition.path.clone());
+        }
+        Self { source, fields }
+    }
+
+    /**
+     * The field that `line`, counting from 1, was written from, such as spheres[0].radius
+     */
+    pub(crate) fn field(&self, line: usize) -> &str {
+        self.fields.get(line - 1).map_or("", |field| &field[..])
+    }
+}
+
+fn definitions(scene: &SceneDefinition) -> Vec<Definition> {
+    let mut definitions = vec![];
+    definitions.extend(scene.window.as_ref().map(window));
+    definitions.extend(scene.render.as_ref().map(render));
+    definitions.extend(scene.camera.as_ref().map(camera));
+    definitions.extend(scene.background.as_ref().map(background));
+    definitions.extend(scene.post.as_deref().map(post));
+    definitions.extend(scene.fog.as_ref().map(fog));
+    definitions.extend(scene.textures.iter().enumerate().map(texture));
+    for (i, named) in scene.materials.iter().enumerate() {
+        let mut definition = Definition::named("material", &named.name, list("materials", i));
+        definition.enter("material");
+        material(&mut definition, &named.material);
+        definitions.push(definition);
+    }
+    definitions.extend(scene.lights.iter().enumerate().map(light));
+    definitions.extend(scene.spheres.iter().enumerate().map(sphere));
+
+    // Definitions need at least one property, and leaving a setting out means the same as an
+    // empty one
+    definitions.retain(|definition| !definition.properties.is_empty());
+    definitions
+}
+
+/**
+ * The path of element `i` of the list of definitions in `field`
+ */
+fn list(field: &str, i: usize) -> String {
+    format!("{}[{}]", field, i)
+}
+
+fn window(window: &WindowDefinition) -> Definition {
+    let mut definition = Definition::new("window", "window".into());
+    definition.optional("title", window.title.as_deref(), string);
+    definition.optional("width", window.width, number);
+    definition.optional("height", window.height, number);
+    definition
+}
+
+fn render(render: &RenderDefinition) -> Definition {
+    let mut definition = Definition::new("render", "render".into());
+    definition.optional("colorspace", render.color_space, color_space);
+    definition.optional("integrator", render.integrator, |integrator| {
+        match integrator {
+            Integrator::Whitted => "whitted",
+            Integrator::Path => "path",
+        }
+        .into()
+    });
+    definition.optional("samples", render.samples, |n| n.to_string());
+    definition.optional("depth", render.depth, |n| n.to_string());
+    definition.optional("output", render.output.as_deref(), string);
+    definition
+}
+
+fn camera(camera: &CameraDefinition) -> Definition {
+    let mut definition = Definition::new("camera", "camera".into());
+    definition.optional("aperture", camera.aperture, number);
+    definition.optional("focal_distance", camera.focal_distance, number);
+    definition
+}
+
+fn background(background: &BackgroundDefinition) -> Definition {
+    let mut definition = Definition::new("background", "background".into());
+    let kind = "background.kind".to_string();
+    match &background.kind {
+        BackgroundKind::Color { color: c } => {
+            definition.property_from("type", kind.clone(), "color".into());
+            definition.property_from("color", format!("{}.color.color", kind), color(c));
+        }
+        BackgroundKind::Gradient { top, bottom } => {
+            definition.property_from("type", kind.clone(), "gradient".into());
+            definition.property_from("top", format!("{}.gradient.top", kind), color(top));
+            definition.property_from("bottom", format!("{}.gradient.bottom", kind), color(bottom));
+        }
+        BackgroundKind::Image { file } => {
+            definition.property_from("type", kind.clone(), "image".into());
+            definition.property_from("file", format!("{}.image.file", kind), string(file));
+        }
+        BackgroundKind::Sky { sun, turbidity } => {
+            definition.property_from("type", kind.clone(), "sky".into());
+            definition.property_from("sun", format!("{}.sky.sun", kind), tuple(*sun));
+            definition.property_from(
+                "turbidity",
+                format!("{}.sky.turbidity", kind),
+                number(*turbidity),
+            );
+        }
+    }
+    definition.optional("intensity", background.intensity, number);
+    definition.optional("lighting", background.lighting, flag);
+    definition.optional("transparent", background.transparent, flag);
+    definition
+}
+
+fn post(passes: &[PassDefinition]) -> Definition {
+    let mut definition = Definition::new("post", "post".into());
+    for (i, pass) in passes.iter().enumerate() {
+        // Each pass is its own element of the list
+        definition.scope = list("post", i);
+        match pass {
+            PassDefinition::Exposure(stops) => definition.property("exposure", number(*stops)),
+            PassDefinition::Contrast(contrast) => {
+                definition.property("contrast", number(*contrast))
+            }
+            PassDefinition::Saturation(saturation) => {
+                definition.property("saturation", number(*saturation))
+            }
+            PassDefinition::Vignette(strength) => {
+                definition.property("vignette", number(*strength))
+            }
+            PassDefinition::Bloom {
+                strength,
+                threshold,
+            } => {
+                definition.enter("bloom");
+                definition.property_from(
+                    "bloom",
+                    format!("{}.strength", definition.scope),
+                    number(*strength),
+                );
+                // A threshold applies to the first bloom, wherever it is written
+                definition.optional("threshold", *threshold, number);
+            }
+            PassDefinition::Lut(file) => definition.property("lut", string(file)),
+        }
+    }
+    definition
+}
+
+fn fog(fog: &FogDefinition) -> Definition {
+    let mut definition = Definition::new("fog", "fog".into());
+    definition.property("color", color(&fog.color));
+    definition.property("density", number(fog.density));
+    definition.optional("falloff", fog.falloff, number);
+    definition
+}
+
+fn texture((i, texture): (usize, &TextureDefinition)) -> Definition {
+    let mut definition = Definition::named("texture", &texture.name, list("textures", i));
+    definition.enter("kind");
+    match &texture.kind {
+        TextureKind::Pattern {
+            pattern,
+            primary,
+            secondary,
+            scale,
+            direction,
+            octaves,
+            turbulence,
+        } => {
+            let name = match pattern {
+                Pattern::Checker => "checker",
+                Pattern::Stripes => "stripes",
+                Pattern::Rings => "rings",
+                Pattern::Gradient => "gradient",
+                Pattern::Noise => "noise",
+                Pattern::Marble => "marble",
+                Pattern::Wood => "wood",
+            };
+            definition.enter("pattern");
+            let field = format!("{}.pattern", definition.scope);
+            definition.property_from("type", field, name.into());
+            definition.property("primary", rgb(*primary));
+            definition.property("secondary", rgb(*secondary));
+            definition.optional("scale", *scale, number);
+            definition.optional("direction", *direction, tuple);
+            definition.optional("octaves", *octaves, |n| n.to_string());
+            definition.optional("turbulence", *turbulence, number);
+        }
+        TextureKind::Image {
+            file,
+            filter,
+            wrap,
+            color_space: space,
+        } => {
+            definition.property_from("type", definition.scope.clone(), "image".into());
+            definition.enter("image");
+            definition.property("file", string(file));
+            definition.optional("filter", *filter, |filter| {
+                match filter {
+                    Filter::Nearest => "nearest",
+                    Filter::Bilinear => "bilinear",
+                }
+                .into()
+            });
+            definition.optional("wrap", *wrap, |wrap| {
+                match wrap {
+                    Wrap::Repeat => "repeat",
+                    Wrap::Clamp => "clamp",
+                }
+                .into()
+            });
+            definition.optional("colorspace", *space, color_space);
+        }
+    }
+    definition
+}
+
+/**
+ * The properties of a material, shared by named materials and spheres with their own
+ */
+fn material(definition: &mut Definition, material: &MaterialDefinition) {
+    definition.property("color", color(&material.color));
+    definition.optional("specular", material.specular, number);
+    definition.optional("reflective", material.reflective, number);
+    definition.optional("emission", material.emission.as_ref(), color);
+    definition.optional("strength", material.strength, number);
+    definition.optional("normal", material.normal.as_deref(), string);
+    definition.optional("bump", material.bump.as_deref(), string);
+    definition.optional("bumpheight", material.bump_height, number);
+    if let Some((metallic, roughness)) = material.microfacet {
+        let field = format!("{}.microfacet", definition.scope);
+        definition.property_from("metallic", format!("{}[0]", field), number(metallic));
+        definition.property_from("roughness", format!("{}[1]", field), number(roughness));
+    }
+    definition.optional("opacity", material.opacity, number);
+    definition.optional("density", material.density, number);
+}
+
+fn light((i, light): (usize, &LightDefinition)) -> Definition {
+    let path = list("lights", i);
+    let mut definition = Definition::new("light", path.clone());
+    let light_type = match light {
+        LightDefinition::Ambient { .. } => "ambient",
+        LightDefinition::Point { .. } => "point",
+        LightDefinition::Direction { .. } => "directional",
+    };
+    definition.property_from("type", path, light_type.into());
+    definition.enter(light_type);
+    match light {
+        LightDefinition::Ambient { intensity } => {
+            definition.property("intensity", number(*intensity));
+        }
+        LightDefinition::Point {
+            intensity,
+            position,
+        } => {
+            definition.property("intensity", number(*intensity));
+            definition.property("position", tuple(*position));
+        }
+        LightDefinition::Direction {
+            intensity,
+            direction,
+        } => {
+            definition.property("intensity", number(*intensity));
+            definition.property("direction", tuple(*direction));
+        }
+    }
+    definition
+}
+
+fn sphere((i, sphere): (usize, &SphereDefinition)) -> Definition {
+    let path = list("spheres", i);
+    let mut definition = Definition::new("sphere", path.clone());
+    match &sphere.material {
+        MaterialSource::Named(name) => {
+            definition.property_from("material", format!("{}.material.named", path), string(name))
+        }
+        MaterialSource::Inline(inline) => {
+            definition.enter("material.inline");
+            material(&mut definition, inline);
+            definition.scope = path;
+        }
+    }
+    definition.property("center", tuple(sphere.center));
+    definition.property("radius", number(sphere.radius));
+    definition
+}
+
+/**
+ * A number that reads back exactly, in scientific notation if it is very large or very small
+ */
+fn number(n: f64) -> String {
+    let magnitude = n.abs();
+    if magnitude != 0.0 && !(1e-5..1e16).contains(&magnitude) {
+        format!("{:e}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+fn tuple((x, y, z): (f64, f64, f64)) -> String {
+    format!("({}, {}, {})", number(x), number(y), number(z))
+}
+
+fn rgb((red, green, blue): (f64, f64, f64)) -> String {
+    format!("rgbf({}, {}, {})", number(red), number(green), number(blue))
+}
+
+fn color(color: &ColorDefinition) -> String {
+    match color {
+        ColorDefinition::Rgb(channels) => rgb(*channels),
+        ColorDefinition::Texture(name) => string(name),
+    }
+}
+
+fn color_space(color_space: ColorSpace) -> String {
+    match color_space {
+        ColorSpace::Srgb => "srgb",
+        ColorSpace::Linear => "linear",
+    }
+    .into()
+}
+
+fn flag(flag: bool) -> String {
+    flag.to_string()
+}
+
+/**
+ * A quoted string, escaping the characters that can't appear in it as they are
+ */
+fn string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::error::Diagnostic;
+    use super::super::format::format;
+    use super::super::parser::{NamedMaterialDefinition, Parser};
+    use super::*;
+    use proptest::num::f64::{NORMAL, POSITIVE, SUBNORMAL, ZERO};
+    use proptest::option::of;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    /// Any finite number
+    fn any_number() -> impl Strategy<Value = f64> {
+        NORMAL | SUBNORMAL | ZERO
+    }
+
+    fn positive() -> impl Strategy<Value = f64> {
+        POSITIVE | NORMAL | SUBNORMAL
+    }
+
+    fn non_negative() -> impl Strategy<Value = f64> {
+        POSITIVE | NORMAL | SUBNORMAL | ZERO
+    }
+
+    fn unit() -> impl Strategy<Value = f64> {
+        0.0..=1.0
+    }
+
+    fn count() -> impl Strategy<Value = usize> {
+        1usize..1 << 40
+    }
+
+    fn any_tuple() -> impl Strategy<Value = (f64, f64, f64)> {
+        (any_number(), any_number(), any_number())
+    }
+
+    fn channels() -> impl Strategy<Value = (f64, f64, f64)> {
+        (unit(), unit(), unit())
+    }
+
+    /// Text including the characters that have to be escaped
+    fn text() -> impl Strategy<Value = String> {
+        "[a-z0-9 ./\"\\\\\n\t]{0,10}"
+    }
+
+    fn color_space() -> impl Strategy<Value = ColorSpace> {
+        prop_oneof![Just(ColorSpace::Srgb), Just(ColorSpace::Linear)]
+    }
+
+    fn window() -> impl Strategy<Value = WindowDefinition> {
+        (of(text()), of(any_number()), of(any_number())).prop_map(|(title, width, height)| {
+            WindowDefinition {
+                title,
+                width,
+                height,
+            }
+        })
+    }
+
+    fn render() -> impl Strategy<Value = RenderDefinition> {
+        let integrator = prop_oneof![Just(Integrator::Whitted), Just(Integrator::Path)];
+        (
+            of(color_space()),
+            of(integrator),
+            of(count()),
+            of(count()),
+            of(text()),
+        )
+            .prop_map(|(color_space, integrator, samples, depth, output)| {
+                RenderDefinition {
+                    color_space,
+                    integrator,
+                    samples,
+                    depth,
+                    output,
+                }
+            })
+    }
+
+    fn camera() -> impl Strategy<Value = CameraDefinition> {
+        // An aperture needs a focal distance
+        (of(non_negative()), positive()).prop_map(|(aperture, focal_distance)| CameraDefinition {
+            aperture,
+            focal_distance: Some(focal_distance),
+        })
+    }
+
+    fn background(textures: &[String]) -> impl Strategy<Value = BackgroundDefinition> {
+        let kind = prop_oneof![
+            color(textures).prop_map(|color| BackgroundKind::Color { color }),
+            (color(textures), color(textures))
+                .prop_map(|(top, bottom)| BackgroundKind::Gradient { top, bottom }),
+            text().prop_map(|file| BackgroundKind::Image { file }),
+            ((any_number(), positive(), any_number()), 1.7..=10.0)
+                .prop_map(|(sun, turbidity)| BackgroundKind::Sky { sun, turbidity }),
+        ];
+        (kind, of(any_number()), of(any::<bool>()), of(any::<bool>())).prop_map(
+            |(kind, intensity, lighting, transparent)| BackgroundDefinition {
+                kind,
+                intensity,
+                lighting,
+                transparent,
+            },
+        )
+    }
+
+    fn post() -> impl Strategy<Value = Vec<PassDefinition>> {
+        let pass = prop_oneof![
+            any_number().prop_map(PassDefinition::Exposure),
+            positive().prop_map(PassDefinition::Contrast),
+            non_negative().prop_map(PassDefinition::Saturation),
+            unit().prop_map(PassDefinition::Vignette),
+            (positive(), of(any_number())).prop_map(|(strength, threshold)| {
+                PassDefinition::Bloom {
+                    strength,
+                    threshold,
+                }
+            }),
+            text().prop_map(PassDefinition::Lut),
+        ];
+        prop::collection::vec(pass, 0..6).prop_map(|mut passes| {
+            // Only the first bloom can have a threshold
+            let mut first = true;
+            for pass in passes.iter_mut() {
+                if let PassDefinition::Bloom { threshold, .. } = pass {
+                    if !first {
+                        *threshold = None;
+                    }
+                    first = false;
+                }
+            }
+            passes
+        })
+    }
+
+    fn fog(textures: &[String]) -> impl Strategy<Value = FogDefinition> {
+        (color(textures), positive(), of(non_negative())).prop_map(|(color, density, falloff)| {
+            FogDefinition {
+                color,
+                density,
+                falloff,
+            }
+        })
+    }
+
+    fn texture_kind() -> impl Strategy<Value = TextureKind> {
+        let pattern = select(vec![
+            Pattern::Checker,
+            Pattern::Stripes,
+            Pattern::Rings,
+            Pattern::Gradient,
+            Pattern::Noise,
+            Pattern::Marble,
+            Pattern::Wood,
+        ]);
+        let filter = prop_oneof![Just(Filter::Nearest), Just(Filter::Bilinear)];
+        let wrap = prop_oneof![Just(Wrap::Repeat), Just(Wrap::Clamp)];
+        prop_oneof![
+            (
+                pattern,
+                channels(),
+                channels(),
+                of(positive()),
+                of(any_tuple()),
+                of(count()),
+                of(any_number()),
+            )
+                .prop_map(
+                    |(pattern, primary, secondary, scale, direction, octaves, turbulence)| {
+                        TextureKind::Pattern {
+                            pattern,
+                            primary,
+                            secondary,
+                            scale,
+                            direction,
+                            octaves,
+                            turbulence,
+                        }
+                    }
+                ),
+            (text(), of(filter), of(wrap), of(color_space())).prop_map(
+                |(file, filter, wrap, color_space)| TextureKind::Image {
+                    file,
+                    filter,
+                    wrap,
+                    color_space,
+                }
+            ),
+        ]
+    }
+
+    /**
+     * Random text made unique by the index after it
+     */
+    fn names(max: usize) -> impl Strategy<Value = Vec<String>> {
+        prop::collection::vec(text(), 0..max).prop_map(|names| {
+            names
+                .into_iter()
+                .enumerate()
+                .map(|(i, name)| format!("{}|{}", name, i))
+                .collect()
+        })
+    }
+
+    fn color(textures: &[String]) -> BoxedStrategy<ColorDefinition> {
+        let rgb = channels().prop_map(ColorDefinition::Rgb);
+        if textures.is_empty() {
+            rgb.boxed()
+        } else {
+            prop_oneof![
+                rgb,
+                select(textures.to_vec()).prop_map(ColorDefinition::Texture)
+            ]
+            .boxed()
+        }
+    }
+
+    fn texture_name(textures: &[String]) -> BoxedStrategy<Option<String>> {
+        if textures.is_empty() {
+            Just(None).boxed()
+        } else {
+            of(select(textures.to_vec())).boxed()
+        }
+    }
+
+    fn material(textures: &[String]) -> impl Strategy<Value = MaterialDefinition> {
+        // strength needs an emission, bumpheight a bump, and specular can't go with metallic and
+        // roughness
+        let emission = of((color(textures), of(any_number())));
+        let bump = of((select_or_none(textures), of(any_number())));
+        let shading = prop_oneof![
+            of(positive()).prop_map(|specular| (specular, None)),
+            (unit(), unit()).prop_map(|microfacet| (None, Some(microfacet))),
+        ];
+        (
+            color(textures),
+            shading,
+            of(unit()),
+            emission,
+            texture_name(textures),
+            bump,
+            of(unit()),
+            of(positive()),
+        )
+            .prop_map(
+                |(
+                    color,
+                    (specular, microfacet),
+                    reflective,
+                    emission,
+                    normal,
+                    bump,
+                    opacity,
+                    density,
+                )| {
+                    let (emission, strength) = match emission {
+                        Some((emission, strength)) => (Some(emission), strength),
+                        None => (None, None),
+                    };
+                    let (bump, bump_height) = match bump {
+                        Some((Some(bump), height)) => (Some(bump), height),
+                        _ => (None, None),
+                    };
+                    MaterialDefinition {
+                        color,
+                        specular,
+                        reflective,
+                        emission,
+                        strength,
+                        normal,
+                        bump,
+                        bump_height,
+                        microfacet,
+                        opacity,
+                        density,
+                    }
+                },
+            )
+    }
+
+    fn select_or_none(textures: &[String]) -> BoxedStrategy<Option<String>> {
+        if textures.is_empty() {
+            Just(None).boxed()
+        } else {
+            select(textures.to_vec()).prop_map(Some).boxed()
+        }
+    }
+
+    fn light() -> impl Strategy<Value = LightDefinition> {
+        prop_oneof![
+            any_number().prop_map(|intensity| LightDefinition::Ambient { intensity }),
+            (any_number(), any_tuple()).prop_map(|(intensity, position)| LightDefinition::Point {
+                intensity,
+                position
+            }),
+            (any_number(), any_tuple()).prop_map(|(intensity, direction)| {
+                LightDefinition::Direction {
+                    intensity,
+                    direction,
+                }
+            }),
+        ]
+    }
+
+    fn sphere(textures: &[String], materials: &[String]) -> BoxedStrategy<SphereDefinition> {
+        let inline = material(textures).prop_map(|m| MaterialSource::Inline(Box::new(m)));
+        let source = if materials.is_empty() {
+            inline.boxed()
+        } else {
+            let named = select(materials.to_vec()).prop_map(MaterialSource::Named);
+            prop_oneof![inline, named].boxed()
+        };
+        (source, any_tuple(), any_number())
+            .prop_map(|(material, center, radius)| SphereDefinition {
+                material,
+                center,
+                radius,
+            })
+            .boxed()
+    }
+
+    /**
+     * Scenes that pass every check, with materials and spheres only referring to textures and
+     * materials that exist
+     */
+    fn scene() -> impl Strategy<Value = SceneDefinition> {
+        // The parser never gives empty settings, as definitions need at least one property
+        let settings = (
+            of(window().prop_filter("empty", |w| *w != WindowDefinition::default())),
+            of(render().prop_filter("empty", |r| *r != RenderDefinition::default())),
+            of(camera()),
+            of(post().prop_filter("empty", |passes| !passes.is_empty())),
+        );
+        let objects = (names(4), names(4)).prop_flat_map(|(texture_names, material_names)| {
+            let textures = texture_names
+                .iter()
+                .map(|name| {
+                    let name = name.clone();
+                    texture_kind().prop_map(move |kind| TextureDefinition {
+                        name: name.clone(),
+                        kind,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let materials = material_names
+                .iter()
+                .map(|name| {
+                    let name = name.clone();
+                    material(&texture_names).prop_map(move |material| NamedMaterialDefinition {
+                        name: name.clone(),
+                        material,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let spheres = prop::collection::vec(sphere(&texture_names, &material_names), 0..4);
+            (
+                of(background(&texture_names)),
+                of(fog(&texture_names)),
+                textures,
+                materials,
+                prop::collection::vec(light(), 0..4),
+                spheres,
+            )
+        });
+        (settings, objects).prop_map(
+            |(
+                (window, render, camera, post),
+                (background, fog, textures, materials, lights, spheres),
+            )| {
+                SceneDefinition {
+                    window,
+                    render,
+                    camera,
+                    background,
+                    post,
+                    fog,
+                    textures,
+                    materials,
+                    lights,
+                    spheres,
+                }
+            },
+        )
+    }
+
+    fn parse(text: &str) -> (Option<SceneDefinition>, Vec<Diagnostic>) {
+        Parser::new("test.grfx", text).parse()
+    }
+
+    #[test]
+    fn the_example_scene_prints_and_parses_back() {
+        let (definition, _) = parse(include_str!("../../scene.grfx"));
+        let definition = definition.unwrap();
+        let text = definition.to_string();
+        let (read, diagnostics) = parse(&text);
+        assert_eq!(read, Some(definition), "{}\n{:?}", text, diagnostics);
+    }
+
+    proptest! {
+        #[test]
+        fn printed_scenes_parse_back_the_same(definition in scene()) {
+            let text = definition.to_string();
+            let (read, diagnostics) = parse(&text);
+            prop_assert_eq!(read, Some(definition), "{}\n{:?}", text, diagnostics);
+        }
+
+        #[test]
+        fn every_printed_line_has_a_field(definition in scene()) {
+            let printed = Printed::new(&definition);
+            let lines = printed.source.lines().collect::<Vec<_>>();
+            prop_assert_eq!(printed.fields.len(), lines.len());
+            for (line, field) in lines.iter().zip(printed.fields.iter()) {
+                prop_assert_eq!(line.is_empty(), field.is_empty());
+            }
+        }
+
+        #[test]
+        fn printed_scenes_are_formatted(definition in scene()) {
+            let text = definition.to_string();
+            prop_assert_eq!(format(&text), text);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use super::lexer::Token;
+use logos::Logos;
+use std::fmt;
+
+/**
+ * A scene file as a tree of lines and braced blocks that keeps every character of the source,
+ * including whitespace and comments, so that it can be written back out exactly or reformatted.
+ *
+ * The tree only follows the braces and line breaks of the file, not the full grammar, so it should
+ * be built from a file that parses without syntax errors.
+ */
+pub(crate) struct SyntaxTree {
+    pub nodes: Vec<SyntaxNode>,
+    /// Whitespace and any comment after the last token
+    pub trailing: String,
+}
+
+pub(crate) enum SyntaxNode {
+    /// A statement or assignment, up to but not including the end of its line. Arrays can spread
+    /// it over several lines.
+    Line(Vec<SyntaxToken>),
+    Block(Block),
+    /// The end of a line and any blank lines after it, along with the comment before it, if any
+    NewLine(SyntaxToken),
+}
+
+/**
+ * A definition, sub-block, loop or conditional: the tokens before an opening brace, and what is
+ * between the braces. The else of an if is a block of its own, on the same line as the closing
+ * brace before it.
+ */
+pub(crate) struct Block {
+    pub header: Vec<SyntaxToken>,
+    pub open: SyntaxToken,
+    pub body: Vec<SyntaxNode>,
+    /// Missing if the file ends first
+    pub close: Option<SyntaxToken>,
+}
+
+/**
+ * A token along with the text it was lexed from and the whitespace and comment before it
+ */
+pub(crate) struct SyntaxToken {
+    pub token: Token,
+    pub text: String,
+    pub leading: String,
+}
+
+impl SyntaxToken {
+    /**
+     * The comment before this token. Comments run to the end of the line, so only the ends of lines
+     * have them.
+     */
+    pub(crate) fn comment(&self) -> Option<&str> {
+        comment(&self.leading)
+    }
+
+    /**
+     * The number of line breaks in a newline token
+     */
+    pub(crate) fn line_breaks(&self) -> usize {
+        self.text.matches('\n').count().max(1)
+    }
+}
+
+/**
+ * The comment in some whitespace and comment text, if it has one
+ */
+pub(crate) fn comment(trivia: &str) -> Option<&str> {
+    Some(trivia.trim()).filter(|comment| !comment.is_empty())
+}
+
+impl SyntaxTree {
+    pub(crate) fn parse(source: &str) -> Self {
+        let mut tokens = vec![];
+        let mut end = 0;
+        for (token, span) in Token::lexer(source).spanned() {
+            tokens.push(SyntaxToken {
+                token,
+                text: source[span.clone()].into(),
+                leading: source[end..span.start].into(),
+            });
+            end = span.end;
+        }
+
+        let mut tokens = tokens.into_iter().peekable();
+        let mut nodes = body(&mut tokens);
+        // A stray closing brace ends the top level early, so carry on after it
+        while let Some(stray) = tokens.next() {
+            nodes.push(SyntaxNode::Line(vec![stray]));
+            nodes.extend(body(&mut tokens));
+        }
+        Self {
+            nodes,
+            trailing: source[end..].into(),
+        }
+    }
+}
+
+type Tokens = std::iter::Peekable<std::vec::IntoIter<SyntaxToken>>;
+
+/**
+ * The lines and blocks up to a closing brace at the same depth, or the end of the file
+ */
+fn body(tokens: &mut Tokens) -> Vec<SyntaxNode> {
+    let mut nodes = vec![];
+    loop {
+        match tokens.peek().map(|t| &t.token) {
+            None | Some(Token::RBrace) => return nodes,
+            Some(Token::NewLine) => nodes.push(SyntaxNode::NewLine(tokens.next().unwrap())),
+            Some(_) => {
+                let line = line(tokens);
+                nodes.push(match tokens.peek().map(|t| &t.token) {
+                    Some(Token::LBrace) => SyntaxNode::Block(block(line, tokens)),
+                    _ => SyntaxNode::Line(line),
+                });
+            }
+        }
+    }
+}
+
+/**
+ * Tokens up to the end of the line or a brace, outside of any brackets
+ */
+fn line(tokens: &mut Tokens) -> Vec<SyntaxToken> {
+    let mut line = vec![];
+    let mut depth = 0;
+    while let Some(token) = tokens.peek() {
+        match token.token {
+            Token::LParen | Token::LBracket => depth += 1,
+            Token::RParen | Token::RBracket => depth -= 1,
+            Token::NewLine | Token::LBrace | Token::RBrace if depth <= 0 => break,
+            _ => {}
+        }
+        line.push(tokens.next().unwrap());
+    }
+    line
+}
+
+/**
+ * The block opened by the next token, which is a brace
+ */
+fn block(header: Vec<SyntaxToken>, tokens: &mut Tokens) -> Block {
+    let open = tokens.next().expect("blocks start at an opening brace");
+    let body = body(tokens);
+    let close = tokens.next();
+    Block {
+        header,
+        open,
+        body,
+        close,
+    }
+}
+
+impl fmt::Display for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.leading, self.text)
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Line(tokens) => tokens.iter().try_for_each(|token| write!(f, "{}", token)),
+            Self::Block(block) => write!(f, "{}", block),
+            Self::NewLine(token) => write!(f, "{}", token),
+        }
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.header.iter() {
+            write!(f, "{}", token)?;
+        }
+        write!(f, "{}", self.open)?;
+        for node in self.body.iter() {
+            write!(f, "{}", node)?;
+        }
+        match &self.close {
+            Some(close) => write!(f, "{}", close),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * Writes the tree back out exactly as it was read
+ */
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in self.nodes.iter() {
+            write!(f, "{}", node)?;
+        }
+        write!(f, "{}", self.trailing)
+    }
+}
@@ -2,20 +2,38 @@ mod canvas;
 mod color;
 mod coord;
 mod lang;
+mod post;
+mod raster;
 mod scene;
 mod traits;
 
 use canvas::Canvas;
-use color::{Color, BLACK};
+use color::{ColorSpace, LinearColor, BLACK};
 use coord::{WorldCoordinate, ORIGIN};
-use lang::parser::{LightDefinition, Parser, SceneDefinition};
-use log::error;
+use lang::data::DataFormat;
+use lang::format;
+use lang::parser::{
+    BackgroundKind, ColorDefinition, LightDefinition, MaterialDefinition, MaterialSource, Parser,
+    PassDefinition, SceneDefinition, TextureKind,
+};
+use log::{error, info};
 use pixels::SurfaceTexture;
+use post::{Lut, Pass};
+use raster::Raster;
+use scene::background::Background;
+use scene::media::Fog;
 use scene::object::light::Light;
+use scene::object::material::Material;
+use scene::object::microfacet::Microfacet;
 use scene::object::shape::Sphere;
-use scene::{Scene, ViewPort};
+use scene::sky::Sky;
+use scene::texture::{ImageTexture, PatternTexture, Texture};
+use scene::{Integrator, Scene, ViewPort};
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::path::Path;
+use std::process;
 use winit::event::{Event, VirtualKeyCode};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit_input_helper::WinitInputHelper;
@@ -23,22 +41,35 @@ use winit_input_helper::WinitInputHelper;
 fn main() -> Result<(), String> {
     env_logger::init();
     let args: Vec<_> = env::args().collect();
+    if args.len() >= 2 && args[1] == "fmt" {
+        return fmt(&args[2..]);
+    }
     let file = if args.len() >= 2 { &args[1] } else { "" };
     if file.is_empty() {
         return Err("Supply a valid file name".into());
     }
 
     let contents =
-        fs::read_to_string(&file).map_err(|e| format!("Failed to read '{}': {}", &file, e))?;
-    let mut parser = Parser::new(&contents);
-    let definitions = parser.parse()?;
+        fs::read_to_string(file).map_err(|e| format!("Failed to read '{}': {}", &file, e))?;
+    let (definitions, diagnostics) = match DataFormat::of(Path::new(file)) {
+        Some(format) => format.parse(file, &contents)?,
+        None => Parser::new(file, &contents).parse(),
+    };
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}\n", diagnostic);
+    }
+    let definitions = match definitions {
+        Some(definitions) => definitions,
+        None => process::exit(1),
+    };
 
-    let scene = load_scene(definitions);
+    let base_dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+    let mut scene = load_scene(definitions, base_dir)?;
 
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
     let canvas = scene.canvas();
-    let window = canvas.window().build(&event_loop).unwrap();
+    let window = canvas.window(scene.title()).build(&event_loop).unwrap();
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
@@ -51,7 +82,7 @@ fn main() -> Result<(), String> {
         // Draw the current frame
         if let Event::RedrawRequested(_) = event {
             let frame = pixels.get_frame();
-            scene.render(frame);
+            let finished = scene.render(frame);
 
             if pixels
                 .render()
@@ -61,6 +92,16 @@ fn main() -> Result<(), String> {
                 *control_flow = ControlFlow::Exit;
                 return;
             }
+
+            // Keep refining the image until every pixel has all of its samples
+            if !finished {
+                window.request_redraw();
+            } else if let Some(path) = scene.take_output() {
+                match scene.save_png(&path) {
+                    Ok(()) => info!("Saved image to '{}'", path.display()),
+                    Err(e) => error!("{}", e),
+                }
+            }
         }
 
         // Handle input events
@@ -82,7 +123,57 @@ fn main() -> Result<(), String> {
     });
 }
 
-fn load_scene(definition: SceneDefinition) -> Scene {
+/**
+ * `giraffics fmt [--check] <files>`: lay out scene files in the canonical style, or with --check
+ * only list the ones that aren't, failing if there are any
+ */
+fn fmt(args: &[String]) -> Result<(), String> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<_> = args.iter().filter(|arg| *arg != "--check").collect();
+    if files.is_empty() {
+        return Err("Supply the scene files to format".into());
+    }
+
+    let mut failed = false;
+    for file in files {
+        let source =
+            fs::read_to_string(file).map_err(|e| format!("Failed to read '{}': {}", file, e))?;
+        let diagnostics = Parser::new(file, &source).syntax_errors();
+        if !diagnostics.is_empty() {
+            for diagnostic in diagnostics.iter() {
+                eprintln!("{}\n", diagnostic);
+            }
+            failed = true;
+            continue;
+        }
+
+        let formatted = format::format(&source);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            let line = source
+                .lines()
+                .zip(formatted.lines())
+                .take_while(|(a, b)| a == b)
+                .count()
+                + 1;
+            println!("{}:{}: not formatted", file, line);
+            failed = true;
+        } else {
+            fs::write(file, formatted).map_err(|e| format!("Failed to write '{}': {}", file, e))?;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/**
+ * Build the scene described by `definition`, resolving any file paths it contains relative to `base_dir`
+ */
+fn load_scene(definition: SceneDefinition, base_dir: &Path) -> Result<Scene, String> {
     let mut window_width = canvas::DEFAULT_WIDTH;
     let mut window_height = canvas::DEFAULT_HEIGHT;
     let mut canvas = Canvas::default();
@@ -99,36 +190,237 @@ fn load_scene(definition: SceneDefinition) -> Scene {
         }
     }
 
-    let lights = definition
+    let mut lights: Vec<Light> = definition
         .lights
         .into_iter()
         .map(|light_def| match light_def {
-            LightDefinition::AmbientLight { intensity } => Light::ambient(intensity),
-            LightDefinition::DirectionLight {
+            LightDefinition::Ambient { intensity } => Light::ambient(intensity),
+            LightDefinition::Direction {
                 intensity,
                 direction,
             } => Light::direction(WorldCoordinate::from_tuple(direction), intensity),
-            LightDefinition::PointLight {
+            LightDefinition::Point {
                 intensity,
                 position,
             } => Light::point(WorldCoordinate::from_tuple(position), intensity),
         })
         .collect();
 
+    let mut color_space = ColorSpace::default();
+    let mut integrator = Integrator::default();
+    let mut samples = None;
+    let mut max_depth = 8;
+    let mut output = None;
+    if let Some(render_def) = definition.render {
+        if let Some(space) = render_def.color_space {
+            color_space = space;
+        }
+        if let Some(kind) = render_def.integrator {
+            integrator = kind;
+        }
+        if let Some(depth) = render_def.depth {
+            max_depth = depth;
+        }
+        samples = render_def.samples;
+        output = render_def.output;
+    }
+
+    let to_linear = |channels| LinearColor::decode(channels, color_space);
+    let mut textures: HashMap<String, Texture> = HashMap::new();
+    for texture_def in definition.textures {
+        let texture = match texture_def.kind {
+            TextureKind::Pattern {
+                pattern,
+                primary,
+                secondary,
+                scale,
+                direction,
+                octaves,
+                turbulence,
+            } => {
+                let mut pattern =
+                    PatternTexture::new(pattern, to_linear(primary), to_linear(secondary));
+                if let Some(scale) = scale {
+                    pattern = pattern.with_scale(scale);
+                }
+                if let Some(direction) = direction {
+                    pattern = pattern.with_direction(WorldCoordinate::from_tuple(direction));
+                }
+                if let Some(octaves) = octaves {
+                    pattern = pattern.with_octaves(octaves);
+                }
+                if let Some(turbulence) = turbulence {
+                    pattern = pattern.with_turbulence(turbulence);
+                }
+                Texture::Pattern(pattern)
+            }
+            TextureKind::Image {
+                file,
+                filter,
+                wrap,
+                color_space: image_color_space,
+            } => {
+                let image_color_space = image_color_space.unwrap_or(color_space);
+                let mut image =
+                    ImageTexture::new(Raster::load(&base_dir.join(file), image_color_space)?);
+                if let Some(filter) = filter {
+                    image = image.with_filter(filter);
+                }
+                if let Some(wrap) = wrap {
+                    image = image.with_wrap(wrap);
+                }
+                Texture::Image(image)
+            }
+        };
+        textures.insert(texture_def.name, texture);
+    }
+    // Texture references are checked when the scene definition is parsed
+    let to_texture = |color_def: ColorDefinition| match color_def {
+        ColorDefinition::Rgb(tuple) => Texture::Solid(to_linear(tuple)),
+        ColorDefinition::Texture(name) => textures[&name].clone(),
+    };
+
+    let to_material = |material_def: MaterialDefinition| {
+        let mut material = Material::new(to_texture(material_def.color));
+        if let Some(specular) = material_def.specular {
+            material = material.with_specular(specular);
+        }
+        if let Some((metallic, roughness)) = material_def.microfacet {
+            material = material.with_microfacet(Microfacet::new(metallic, roughness));
+        }
+        if let Some(reflective) = material_def.reflective {
+            material = material.with_reflective(reflective);
+        }
+        if let Some(opacity) = material_def.opacity {
+            material = material.with_opacity(opacity);
+        }
+        if let Some(density) = material_def.density {
+            material = material.with_density(density);
+        }
+        if let Some(emission) = material_def.emission {
+            let strength = material_def.strength.unwrap_or(1.0);
+            material = material.with_emission(to_texture(emission).scaled(strength));
+        }
+        if let Some(normal) = material_def.normal {
+            material = material.with_normal_map(textures[&normal].clone());
+        }
+        if let Some(bump) = material_def.bump {
+            let height = material_def.bump_height.unwrap_or(1.0);
+            material = material.with_bump(textures[&bump].clone(), height);
+        }
+        material
+    };
+    let materials: HashMap<String, Material> = definition
+        .materials
+        .into_iter()
+        .map(|named| (named.name, to_material(named.material)))
+        .collect();
+
     let spheres = definition
         .spheres
         .into_iter()
         .map(|sphere| {
+            let material = match sphere.material {
+                MaterialSource::Inline(material_def) => to_material(*material_def),
+                // References are checked when the scene definition is parsed
+                MaterialSource::Named(name) => materials[&name].clone(),
+            };
             Sphere::new(
                 sphere.radius,
                 WorldCoordinate::from_tuple(sphere.center),
-                Color::from_rgb_tuple(sphere.color),
+                material,
             )
         })
         .collect();
 
+    let mut background = Background::Color(Texture::Solid(BLACK.to_linear(color_space)));
+    let mut environment_lighting = false;
+    let mut transparent_background = false;
+    if let Some(background_def) = definition.background {
+        let intensity = background_def.intensity.unwrap_or(1.0);
+        background = match background_def.kind {
+            BackgroundKind::Color { color } => {
+                Background::Color(to_texture(color).scaled(intensity))
+            }
+            BackgroundKind::Gradient { top, bottom } => Background::Gradient {
+                top: to_texture(top).scaled(intensity),
+                bottom: to_texture(bottom).scaled(intensity),
+            },
+            BackgroundKind::Image { file } => Background::Image {
+                raster: Raster::load(&base_dir.join(file), color_space)?,
+                intensity,
+            },
+            BackgroundKind::Sky { sun, turbidity } => {
+                let sky = Sky::new(WorldCoordinate::from_tuple(sun), turbidity, intensity);
+                lights.push(sky.sun_light());
+                Background::Sky(sky)
+            }
+        };
+        environment_lighting = background_def.lighting.unwrap_or(false);
+        transparent_background = background_def.transparent.unwrap_or(false);
+    }
+
     canvas = canvas.with_height(window_height).with_width(window_width);
-    Scene::new(ORIGIN, ViewPort::default(), canvas, BLACK, window_title)
-        .with_lights(lights)
-        .with_spheres(spheres)
+    let mut scene = Scene::new(
+        ORIGIN,
+        ViewPort::for_canvas(canvas),
+        canvas,
+        background,
+        window_title,
+    )
+    .with_lights(lights)
+    .with_spheres(spheres)
+    .with_color_space(color_space)
+    .with_integrator(
+        integrator,
+        samples.unwrap_or_else(|| integrator.default_samples()),
+        max_depth,
+    );
+
+    if environment_lighting {
+        scene = scene.with_environment_lighting();
+    }
+    if let Some(camera_def) = definition.camera {
+        if let (Some(aperture), Some(focal_distance)) =
+            (camera_def.aperture, camera_def.focal_distance)
+        {
+            scene = scene.with_depth_of_field(aperture, focal_distance);
+        }
+    }
+    if let Some(fog_def) = definition.fog {
+        scene = scene.with_fog(Fog::new(
+            to_texture(fog_def.color),
+            fog_def.density,
+            fog_def.falloff.unwrap_or(0.0),
+        ));
+    }
+    if let Some(pass_defs) = definition.post {
+        let passes = pass_defs
+            .into_iter()
+            .map(|pass_def| {
+                Ok(match pass_def {
+                    PassDefinition::Exposure(stops) => Pass::Exposure(stops),
+                    PassDefinition::Contrast(contrast) => Pass::Contrast(contrast),
+                    PassDefinition::Saturation(saturation) => Pass::Saturation(saturation),
+                    PassDefinition::Vignette(strength) => Pass::Vignette(strength),
+                    PassDefinition::Bloom {
+                        strength,
+                        threshold,
+                    } => Pass::Bloom {
+                        strength,
+                        threshold: threshold.unwrap_or(1.0),
+                    },
+                    PassDefinition::Lut(file) => Pass::Lut(Lut::load(&base_dir.join(file))?),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        scene = scene.with_post(passes);
+    }
+    if transparent_background {
+        scene = scene.with_transparent_background();
+    }
+    if let Some(output) = output {
+        scene = scene.with_output(base_dir.join(output));
+    }
+    Ok(scene)
 }
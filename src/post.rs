@@ -0,0 +1,399 @@
+use crate::color::{ColorSpace, LinearColor};
+use std::fs;
+use std::path::Path;
+
+/**
+ * The mid-grey that contrast changes pivot around, so that it keeps its brightness
+ */
+const MIDDLE_GREY: f64 = 0.18;
+
+/**
+ * How many times bloom repeats its box blur, which together approximate a Gaussian blur
+ */
+const BLOOM_BLUR_PASSES: usize = 3;
+
+/**
+ * A post-processing step applied to the finished image, before it is encoded for display
+ */
+pub(crate) enum Pass {
+    /// Brighten or darken by a number of photographic stops
+    Exposure(f64),
+    /// Push values away from (above 1) or towards (below 1) middle grey
+    Contrast(f64),
+    /// 0 gives greyscale, 1 leaves colors unchanged and larger values exaggerate them
+    Saturation(f64),
+    /// Darken towards the corners, which are scaled by 1 minus the strength
+    Vignette(f64),
+    /// Make bright areas glow by adding a blurred copy of everything brighter than `threshold`
+    Bloom { strength: f64, threshold: f64 },
+    /// Remap colors through a 3D lookup table
+    Lut(Lut),
+}
+
+impl Pass {
+    /**
+     * Apply the pass to an image of `width` by `height` pixels, stored row by row
+     */
+    pub(crate) fn apply(
+        &self,
+        image: &mut [LinearColor],
+        width: usize,
+        height: usize,
+        color_space: ColorSpace,
+    ) {
+        match self {
+            Self::Exposure(stops) => {
+                let factor = 2f64.powf(*stops);
+                image.iter_mut().for_each(|pixel| *pixel = *pixel * factor);
+            }
+            Self::Contrast(contrast) => {
+                let adjust =
+                    |channel: f64| MIDDLE_GREY * (channel.max(0.0) / MIDDLE_GREY).powf(*contrast);
+                for pixel in image.iter_mut() {
+                    *pixel = LinearColor::new(
+                        adjust(pixel.red()),
+                        adjust(pixel.green()),
+                        adjust(pixel.blue()),
+                    );
+                }
+            }
+            Self::Saturation(saturation) => {
+                for pixel in image.iter_mut() {
+                    let grey = LinearColor::new(1.0, 1.0, 1.0) * pixel.luminance();
+                    *pixel = grey + (*pixel - grey) * *saturation;
+                }
+            }
+            Self::Vignette(strength) => {
+                let (center_x, center_y) = (width as f64 / 2.0, height as f64 / 2.0);
+                let corner_squared = center_x * center_x + center_y * center_y;
+                for (index, pixel) in image.iter_mut().enumerate() {
+                    let dx = (index % width) as f64 + 0.5 - center_x;
+                    let dy = (index / width) as f64 + 0.5 - center_y;
+                    let falloff = (dx * dx + dy * dy) / corner_squared;
+                    *pixel = *pixel * (1.0 - strength * falloff);
+                }
+            }
+            Self::Bloom {
+                strength,
+                threshold,
+            } => {
+                let mut bright: Vec<LinearColor> = image
+                    .iter()
+                    .map(|pixel| {
+                        LinearColor::new(
+                            (pixel.red() - threshold).max(0.0),
+                            (pixel.green() - threshold).max(0.0),
+                            (pixel.blue() - threshold).max(0.0),
+                        )
+                    })
+                    .collect();
+                let radius = (width.max(height) / 100).max(1);
+                for _ in 0..BLOOM_BLUR_PASSES {
+                    box_blur(&mut bright, width, height, radius);
+                }
+                for (pixel, glow) in image.iter_mut().zip(bright) {
+                    *pixel = *pixel + glow * *strength;
+                }
+            }
+            Self::Lut(lut) => {
+                for pixel in image.iter_mut() {
+                    *pixel = lut.apply(*pixel, color_space);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Blur an image in place by averaging each pixel with those up to `radius` away, first along rows
+ * and then along columns
+ */
+fn box_blur(image: &mut [LinearColor], width: usize, height: usize, radius: usize) {
+    let mut line = Vec::with_capacity(width.max(height));
+    for y in 0..height {
+        line.clear();
+        line.extend((0..width).map(|x| image[y * width + x]));
+        for (x, blurred) in blur_line(&line, radius).into_iter().enumerate() {
+            image[y * width + x] = blurred;
+        }
+    }
+    for x in 0..width {
+        line.clear();
+        line.extend((0..height).map(|y| image[y * width + x]));
+        for (y, blurred) in blur_line(&line, radius).into_iter().enumerate() {
+            image[y * width + x] = blurred;
+        }
+    }
+}
+
+/**
+ * A moving average over a window of `2 * radius + 1` values, treating values past either end as
+ * repeats of the end value
+ */
+fn blur_line(line: &[LinearColor], radius: usize) -> Vec<LinearColor> {
+    let last = line.len() as isize - 1;
+    let at = |i: isize| line[i.clamp(0, last) as usize];
+    let radius = radius as isize;
+    let scale = 1.0 / (2 * radius + 1) as f64;
+
+    let mut sum = (-radius..=radius).fold(LinearColor::default(), |sum, i| sum + at(i));
+    let mut blurred = Vec::with_capacity(line.len());
+    for i in 0..line.len() as isize {
+        blurred.push(sum * scale);
+        sum = sum + at(i + radius + 1) - at(i - radius);
+    }
+    blurred
+}
+
+/**
+ * A 3D color lookup table, as exported by grading tools in the .cube format. Tables are applied to
+ * the color-space encoded values that would otherwise be displayed.
+ */
+pub(crate) struct Lut {
+    size: usize,
+    domain_min: [f64; 3],
+    domain_max: [f64; 3],
+    /// Output colors with red varying fastest, then green, then blue
+    table: Vec<[f64; 3]>,
+}
+
+impl Lut {
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read LUT '{}': {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| format!("Invalid LUT '{}': {}", path.display(), e))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = vec![];
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default();
+            let numbers = |words: std::str::SplitWhitespace| -> Result<[f64; 3], String> {
+                let values = words
+                    .map(|w| {
+                        w.parse::<f64>()
+                            .map_err(|_| format!("Expected a number but got '{}'", w))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                match values[..] {
+                    [a, b, c] => Ok([a, b, c]),
+                    _ => Err(format!("Expected three numbers but got '{}'", line)),
+                }
+            };
+            match keyword {
+                "TITLE" => {}
+                "LUT_3D_SIZE" => {
+                    let n = words
+                        .next()
+                        .and_then(|w| w.parse::<usize>().ok())
+                        .filter(|n| *n >= 2)
+                        .ok_or("Expected LUT_3D_SIZE to be a whole number of at least 2")?;
+                    size = Some(n);
+                }
+                "LUT_1D_SIZE" | "LUT_1D_INPUT_RANGE" => {
+                    return Err("Only 3D LUTs are supported".into())
+                }
+                "DOMAIN_MIN" => domain_min = numbers(words)?,
+                "DOMAIN_MAX" => domain_max = numbers(words)?,
+                // The same input domain for every channel, as written by Resolve
+                "LUT_3D_INPUT_RANGE" => {
+                    let range = words
+                        .map(|w| w.parse::<f64>().ok())
+                        .collect::<Option<Vec<_>>>();
+                    match range.as_deref() {
+                        Some([min, max]) => {
+                            domain_min = [*min; 3];
+                            domain_max = [*max; 3];
+                        }
+                        _ => return Err("Expected LUT_3D_INPUT_RANGE to be two numbers".into()),
+                    }
+                }
+                _ if keyword.parse::<f64>().is_ok() => {
+                    table.push(numbers(line.split_whitespace())?)
+                }
+                _ => return Err(format!("Unknown keyword '{}'", keyword)),
+            }
+        }
+
+        let size = size.ok_or("Missing LUT_3D_SIZE")?;
+        if table.len() != size * size * size {
+            return Err(format!(
+                "Expected {} entries for a LUT of size {} but got {}",
+                size * size * size,
+                size,
+                table.len()
+            ));
+        }
+        Ok(Self {
+            size,
+            domain_min,
+            domain_max,
+            table,
+        })
+    }
+
+    fn apply(&self, color: LinearColor, color_space: ColorSpace) -> LinearColor {
+        let encoded = [
+            color_space.encode_unit(color.red()),
+            color_space.encode_unit(color.green()),
+            color_space.encode_unit(color.blue()),
+        ];
+        let max_index = (self.size - 1) as f64;
+        let mut position = [0.0; 3];
+        for channel in 0..3 {
+            let range = self.domain_max[channel] - self.domain_min[channel];
+            let t = (encoded[channel] - self.domain_min[channel]) / range;
+            position[channel] = t.clamp(0.0, 1.0) * max_index;
+        }
+
+        // Trilinear interpolation between the eight surrounding entries
+        let low = position.map(|p| (p.floor() as usize).min(self.size - 2));
+        let fraction = [
+            position[0] - low[0] as f64,
+            position[1] - low[1] as f64,
+            position[2] - low[2] as f64,
+        ];
+        let mut result = [0.0; 3];
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let mut weight = 1.0;
+            for channel in 0..3 {
+                weight *= if offset[channel] == 1 {
+                    fraction[channel]
+                } else {
+                    1.0 - fraction[channel]
+                };
+            }
+            let (r, g, b) = (low[0] + offset[0], low[1] + offset[1], low[2] + offset[2]);
+            let entry = self.table[(b * self.size + g) * self.size + r];
+            for channel in 0..3 {
+                result[channel] += weight * entry[channel];
+            }
+        }
+
+        LinearColor::decode((result[0], result[1], result[2]), color_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: &str = "0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+
+    fn apply(pass: Pass, pixels: &[LinearColor], width: usize) -> Vec<LinearColor> {
+        let mut image = pixels.to_vec();
+        pass.apply(&mut image, width, pixels.len() / width, ColorSpace::Srgb);
+        image
+    }
+
+    #[test]
+    fn each_stop_of_exposure_doubles_the_light() {
+        let pixel = LinearColor::new(0.1, 0.2, 0.3);
+        assert_eq!(apply(Pass::Exposure(1.0), &[pixel], 1), vec![pixel * 2.0]);
+        assert_eq!(apply(Pass::Exposure(-2.0), &[pixel], 1), vec![pixel * 0.25]);
+    }
+
+    #[test]
+    fn contrast_keeps_middle_grey() {
+        let grey = LinearColor::new(MIDDLE_GREY, MIDDLE_GREY, MIDDLE_GREY);
+        let bright = LinearColor::new(0.5, 0.5, 0.5);
+        let image = apply(Pass::Contrast(2.0), &[grey, bright], 2);
+        assert_eq!(image[0], grey);
+        assert!(image[1].red() > 0.5);
+    }
+
+    #[test]
+    fn no_saturation_leaves_only_the_luminance() {
+        let pixel = LinearColor::new(0.8, 0.4, 0.1);
+        let grey = apply(Pass::Saturation(0.0), &[pixel], 1)[0];
+        assert!((grey.red() - pixel.luminance()).abs() < 1e-12);
+        assert!((grey.red() - grey.green()).abs() < 1e-12);
+        assert!((grey.green() - grey.blue()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn vignettes_darken_the_corners_more_than_the_middle() {
+        let white = LinearColor::new(1.0, 1.0, 1.0);
+        let image = apply(Pass::Vignette(0.5), &[white; 9], 3);
+        assert!(image[4].red() > image[1].red());
+        assert!(image[1].red() > image[0].red());
+        assert_eq!(image[0], image[8]);
+    }
+
+    #[test]
+    fn bloom_spreads_only_light_above_the_threshold() {
+        let mut pixels = vec![LinearColor::new(0.5, 0.5, 0.5); 9];
+        let dim = apply(
+            Pass::Bloom {
+                strength: 1.0,
+                threshold: 1.0,
+            },
+            &pixels,
+            3,
+        );
+        assert_eq!(dim, pixels);
+
+        pixels[4] = LinearColor::new(10.0, 10.0, 10.0);
+        let bloomed = apply(
+            Pass::Bloom {
+                strength: 1.0,
+                threshold: 1.0,
+            },
+            &pixels,
+            3,
+        );
+        assert!(bloomed[0].red() > 0.5);
+    }
+
+    #[test]
+    fn luts_have_a_size_domain_and_table() {
+        let lut = Lut::parse(&format!(
+            "# comment\nTITLE \"identity\"\nLUT_3D_SIZE 2\nDOMAIN_MIN 0 0 0\n\
+             DOMAIN_MAX 1 1 1\n\n{}",
+            IDENTITY
+        ))
+        .unwrap();
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.table.len(), 8);
+        assert_eq!(lut.table[1], [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn an_input_range_sets_the_domain_of_every_channel() {
+        let lut = Lut::parse(&format!(
+            "LUT_3D_SIZE 2\nLUT_3D_INPUT_RANGE 0.1 0.9\n{}",
+            IDENTITY
+        ))
+        .unwrap();
+        assert_eq!(lut.domain_min, [0.1; 3]);
+        assert_eq!(lut.domain_max, [0.9; 3]);
+    }
+
+    #[test]
+    fn one_dimensional_luts_are_rejected() {
+        for keyword in ["LUT_1D_SIZE 2", "LUT_1D_INPUT_RANGE 0 1"].iter() {
+            assert_eq!(
+                Lut::parse(&format!("{}\n{}", keyword, IDENTITY)).err(),
+                Some("Only 3D LUTs are supported".into())
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_keywords_are_named() {
+        assert_eq!(
+            Lut::parse(&format!("LUT_3D_SIZE 2\nLUT_IN_VIDEO_RANGE\n{}", IDENTITY)).err(),
+            Some("Unknown keyword 'LUT_IN_VIDEO_RANGE'".into())
+        );
+    }
+}
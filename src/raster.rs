@@ -0,0 +1,196 @@
+use crate::color::{Color, ColorSpace, LinearColor};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/**
+ * An image loaded from disk and decoded into linear light, for use as a texture or environment map
+ */
+pub(crate) struct Raster {
+    width: usize,
+    height: usize,
+    pixels: Vec<LinearColor>,
+}
+
+impl Raster {
+    /**
+     * Load an image file. High dynamic range formats (such as Radiance .hdr) already store linear
+     * values; 8-bit formats are decoded using `color_space`.
+     */
+    pub(crate) fn load(path: &Path, color_space: ColorSpace) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("Failed to load image '{}': {}", path.display(), e))?;
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        if width == 0 || height == 0 {
+            return Err(format!("The image '{}' is empty", path.display()));
+        }
+
+        let pixels = match image {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => image
+                .to_rgb32f()
+                .pixels()
+                .map(|p| LinearColor::new(p[0] as f64, p[1] as f64, p[2] as f64))
+                .collect(),
+            _ => image
+                .to_rgb8()
+                .pixels()
+                .map(|p| Color::rgb(p[0], p[1], p[2]).to_linear(color_space))
+                .collect(),
+        };
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub(crate) fn width(&self) -> usize {
+        self.width
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.height
+    }
+
+    pub(crate) fn pixel(&self, x: usize, y: usize) -> LinearColor {
+        self.pixels[y * self.width + x]
+    }
+
+    /**
+     * Look up the image at (u, v), where (0, 0) is the top left corner and (1, 1) the bottom right,
+     * with `wrap_u` and `wrap_v` saying what happens outside that range in each direction
+     */
+    pub(crate) fn sample(
+        &self,
+        u: f64,
+        v: f64,
+        filter: Filter,
+        wrap_u: Wrap,
+        wrap_v: Wrap,
+    ) -> LinearColor {
+        let x = u * self.width as f64 - 0.5;
+        let y = v * self.height as f64 - 0.5;
+
+        match filter {
+            Filter::Nearest => self.pixel(
+                wrap_u.index(x.round(), self.width),
+                wrap_v.index(y.round(), self.height),
+            ),
+            Filter::Bilinear => {
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (left, right) = (
+                    wrap_u.index(x0, self.width),
+                    wrap_u.index(x0 + 1.0, self.width),
+                );
+                let (top, bottom) = (
+                    wrap_v.index(y0, self.height),
+                    wrap_v.index(y0 + 1.0, self.height),
+                );
+
+                self.pixel(left, top) * ((1.0 - fx) * (1.0 - fy))
+                    + self.pixel(right, top) * (fx * (1.0 - fy))
+                    + self.pixel(left, bottom) * ((1.0 - fx) * fy)
+                    + self.pixel(right, bottom) * (fx * fy)
+            }
+        }
+    }
+
+    /**
+     * The brightest any channel of any pixel is
+     */
+    pub(crate) fn max_channel(&self) -> f64 {
+        self.pixels
+            .iter()
+            .map(|p| p.max_channel())
+            .fold(0.0, f64::max)
+    }
+}
+
+/**
+ * How to combine the pixels around a sampled point
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Filter {
+    /// Take the closest pixel, giving hard pixel edges
+    Nearest,
+    /// Blend the four closest pixels
+    Bilinear,
+}
+
+/**
+ * What lies beyond the edges of an image
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Wrap {
+    /// The image tiles endlessly
+    Repeat,
+    /// The edge pixels extend outwards
+    Clamp,
+}
+
+impl Wrap {
+    fn index(self, position: f64, size: usize) -> usize {
+        match self {
+            Self::Repeat => (position as isize).rem_euclid(size as isize) as usize,
+            Self::Clamp => position.clamp(0.0, (size - 1) as f64) as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: LinearColor = LinearColor::new(0.0, 0.0, 0.0);
+    const WHITE: LinearColor = LinearColor::new(1.0, 1.0, 1.0);
+
+    /**
+     * A two pixel wide, one pixel high image: black on the left and white on the right
+     */
+    fn black_and_white() -> Raster {
+        Raster {
+            width: 2,
+            height: 1,
+            pixels: vec![BLACK, WHITE],
+        }
+    }
+
+    #[test]
+    fn nearest_sampling_takes_the_pixel_under_the_point() {
+        let raster = black_and_white();
+        let sample = |u| raster.sample(u, 0.5, Filter::Nearest, Wrap::Repeat, Wrap::Repeat);
+        assert_eq!(sample(0.2), BLACK);
+        assert_eq!(sample(0.8), WHITE);
+    }
+
+    #[test]
+    fn repeating_images_tile_and_clamped_images_extend_their_edges() {
+        let raster = black_and_white();
+        let sample = |u, wrap| raster.sample(u, 0.5, Filter::Nearest, wrap, wrap);
+        assert_eq!(sample(1.2, Wrap::Repeat), BLACK);
+        assert_eq!(sample(-0.2, Wrap::Repeat), WHITE);
+        assert_eq!(sample(1.2, Wrap::Clamp), WHITE);
+        assert_eq!(sample(-0.2, Wrap::Clamp), BLACK);
+    }
+
+    #[test]
+    fn bilinear_sampling_blends_between_pixel_centers() {
+        let raster = black_and_white();
+        let sample = |u| raster.sample(u, 0.5, Filter::Bilinear, Wrap::Clamp, Wrap::Clamp);
+        assert_eq!(sample(0.25), BLACK);
+        assert_eq!(sample(0.5), LinearColor::new(0.5, 0.5, 0.5));
+        assert_eq!(sample(0.75), WHITE);
+    }
+
+    #[test]
+    fn empty_images_are_rejected() {
+        let path = std::env::temp_dir().join(format!("giraffics-empty-{}.hdr", std::process::id()));
+        std::fs::write(&path, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 0 +X 0\n").unwrap();
+        let error = Raster::load(&path, ColorSpace::Srgb).err().unwrap();
+        assert!(error.ends_with("is empty"), "{}", error);
+    }
+}
@@ -1,37 +1,99 @@
+pub(crate) mod background;
+pub(crate) mod media;
+mod noise;
 pub(crate) mod object;
+mod path;
+mod sampling;
+pub(crate) mod sky;
+pub(crate) mod texture;
 
 use crate::canvas::Canvas;
-use crate::color::Color;
+use crate::color::{ColorSpace, LinearColor};
 use crate::coord::{CanvasCoordinate, WorldCoordinate};
+use crate::post::Pass;
 use crate::traits::Converts;
+use background::{Background, IrradianceMap};
+use media::Fog;
 use object::light::Light;
+use object::material::Material;
 use object::shape::Sphere;
+use sampling::{uniform_disk, Rng};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/**
+ * The offset along a ray before which intersections are ignored, so that rays leaving a
+ * surface don't immediately hit the surface they started from
+ */
+const SURFACE_EPSILON: f64 = 1e-6;
 
 #[derive(Copy, Clone)]
 pub(crate) struct ViewPort {
-    depth: usize,
-    width: usize,
-    height: usize,
+    depth: f64,
+    width: f64,
+    height: f64,
 }
 
-impl Default for ViewPort {
-    fn default() -> Self {
+impl ViewPort {
+    /**
+     * A viewport one unit wide at depth 1, with its height matched to the aspect ratio of the canvas
+     */
+    pub(crate) fn for_canvas(canvas: Canvas) -> Self {
         Self {
-            depth: 1,
-            width: 1,
-            height: 1,
+            depth: 1.0,
+            width: 1.0,
+            height: canvas.height() as f64 / canvas.width() as f64,
+        }
+    }
+}
+
+/**
+ * The algorithm used to compute the color seen along each camera ray
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Integrator {
+    /// Direct lighting at the first surface hit
+    #[default]
+    Whitted,
+    /// Monte Carlo path tracing, giving global illumination
+    Path,
+}
+
+impl Integrator {
+    pub(crate) fn default_samples(self) -> usize {
+        match self {
+            Self::Whitted => 1,
+            Self::Path => 64,
         }
     }
 }
 
 pub(crate) struct Scene {
     camera_position: WorldCoordinate,
+    aperture: f64,
+    focal_distance: f64,
     viewport: ViewPort,
     canvas: Canvas,
     spheres: Vec<Sphere>,
-    background_color: Color,
+    volumes: Vec<Sphere>,
+    fog: Option<Fog>,
+    background: Background,
+    environment_lighting: Option<IrradianceMap>,
+    transparent_background: bool,
     lights: Vec<Light>,
     title: String,
+    color_space: ColorSpace,
+    integrator: Integrator,
+    samples: usize,
+    max_depth: usize,
+    output: Option<PathBuf>,
+    post: Vec<Pass>,
+    accumulator: Vec<LinearColor>,
+    coverage: Vec<f64>,
+    samples_taken: usize,
+    /// The finished image after post-processing, kept so that redrawing it doesn't repeat the passes
+    processed: Option<Vec<LinearColor>>,
 }
 
 impl Scene {
@@ -39,35 +101,134 @@ impl Scene {
         camera_position: WorldCoordinate,
         viewport: ViewPort,
         canvas: Canvas,
-        background_color: Color,
+        background: Background,
         title: String,
     ) -> Self {
         Scene {
             camera_position,
+            aperture: 0.0,
+            focal_distance: 1.0,
             viewport,
             canvas,
             spheres: vec![],
-            background_color,
+            volumes: vec![],
+            fog: None,
+            background,
+            environment_lighting: None,
+            transparent_background: false,
             lights: vec![],
             title,
+            color_space: ColorSpace::default(),
+            integrator: Integrator::default(),
+            samples: Integrator::default().default_samples(),
+            max_depth: 8,
+            output: None,
+            post: vec![],
+            accumulator: vec![],
+            coverage: vec![],
+            samples_taken: 0,
+            processed: None,
         }
     }
 
+    /**
+     * Use the given integrator, taking `samples` samples per pixel and following paths for at most `max_depth` bounces
+     */
+    pub(crate) fn with_integrator(
+        mut self,
+        integrator: Integrator,
+        samples: usize,
+        max_depth: usize,
+    ) -> Self {
+        self.integrator = integrator;
+        self.samples = samples;
+        self.max_depth = max_depth;
+        self
+    }
+
+    /**
+     * Model the camera as a thin lens `aperture` units across, so that only objects `focal_distance`
+     * units in front of it are sharp. Blur only builds up over several samples per pixel.
+     */
+    pub(crate) fn with_depth_of_field(mut self, aperture: f64, focal_distance: f64) -> Self {
+        self.aperture = aperture;
+        self.focal_distance = focal_distance;
+        self
+    }
+
+    pub(crate) fn with_color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    /**
+     * Let the background light the scene, in addition to being seen behind it
+     */
+    pub(crate) fn with_environment_lighting(mut self) -> Self {
+        self.environment_lighting = Some(IrradianceMap::new(&self.background));
+        self
+    }
+
+    /**
+     * Leave the background out of the image, so that pixels the scene doesn't cover are transparent.
+     * The background still lights the scene and is seen in reflections.
+     */
+    pub(crate) fn with_transparent_background(mut self) -> Self {
+        self.transparent_background = true;
+        self
+    }
+
+    /**
+     * Save the image to `path` as a PNG once rendering finishes
+     */
+    pub(crate) fn with_output(mut self, path: PathBuf) -> Self {
+        self.output = Some(path);
+        self
+    }
+
+    /**
+     * Apply the given post-processing passes, in order, to the image once every sample has been
+     * taken. The image is shown without them while it is still being refined.
+     */
+    pub(crate) fn with_post(mut self, passes: Vec<Pass>) -> Self {
+        self.post = passes;
+        self
+    }
+
     pub(crate) fn with_lights(mut self, lights: Vec<Light>) -> Self {
         self.lights = lights;
         self
     }
 
+    /**
+     * Add spheres to the scene. Spheres whose material has a density are volumes: rather than having
+     * a surface, they scatter light throughout their inside.
+     */
     pub(crate) fn with_spheres(mut self, spheres: Vec<Sphere>) -> Self {
-        self.spheres = spheres;
+        let (volumes, surfaces) = spheres
+            .into_iter()
+            .partition(|sphere| sphere.material().density().is_some());
+        self.spheres = surfaces;
+        self.volumes = volumes;
         self
     }
 
-    fn trace_ray(&self, viewport_coord: WorldCoordinate, t_min: f64, t_max: f64) -> Color {
+    pub(crate) fn with_fog(mut self, fog: Fog) -> Self {
+        self.fog = Some(fog);
+        self
+    }
+
+    fn closest_intersection(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<(&Sphere, f64)> {
         let mut closest_t = f64::INFINITY;
         let mut closest_sphere: Option<&Sphere> = None;
         for sphere in self.spheres.iter() {
-            let (t1, t2) = sphere.intersect_ray(self.camera_position, viewport_coord);
+            let (t1, t2) = sphere.intersect_ray(origin, direction);
             if t_min <= t1 && t1 <= t_max && t1 < closest_t {
                 closest_sphere = Some(sphere);
                 closest_t = t1
@@ -78,47 +239,386 @@ impl Scene {
             }
         }
 
-        match closest_sphere {
-            Some(s) => {
-                let color = s.color();
-                let point = self.camera_position + viewport_coord * closest_t;
-                let normal = {
-                    let normal_dir = point - s.center();
-                    normal_dir / normal_dir.abs()
-                };
-                let light_intensity = self.compute_lighting(point, normal);
+        closest_sphere.map(|sphere| (sphere, closest_t))
+    }
+
+    fn trace_ray(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+        depth: usize,
+        from_camera: bool,
+    ) -> LinearColor {
+        let (color, t_end) = match self.closest_intersection(origin, direction, t_min, t_max) {
+            Some((s, closest_t)) => (
+                self.shade(s, origin, direction, closest_t, depth, from_camera),
+                closest_t,
+            ),
+            None => (self.escaped(direction, from_camera), t_max),
+        };
+        let (added, transmittance) = self.along_ray(origin, direction, t_min, t_end);
+        added + color * transmittance
+    }
+
+    /**
+     * The light leaving the point `closest_t` along the ray from `origin` where it hits `s`
+     */
+    fn shade(
+        &self,
+        sphere: &Sphere,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        closest_t: f64,
+        depth: usize,
+        from_camera: bool,
+    ) -> LinearColor {
+        let material = sphere.material();
+        let point = origin + direction * closest_t;
+        let surface = sphere.surface_at(point);
+        let normal = material.shading_normal(&surface);
+        let view = -direction;
+        let albedo = material.color_at(&surface);
+        let mut local_color = self.compute_lighting(point, normal, view, material, albedo);
+        if let Some(environment) = &self.environment_lighting {
+            local_color =
+                local_color + material.ambient_reflectance(albedo) * environment.irradiance(normal);
+        }
+
+        let reflective = material.reflective();
+        if depth > 0 && reflective > 0.0 {
+            let reflected_color = self.trace_ray(
+                point,
+                view.reflect(normal),
+                SURFACE_EPSILON,
+                f64::INFINITY,
+                depth - 1,
+                false,
+            );
+            local_color = local_color * (1.0 - reflective) + reflected_color * reflective;
+        }
+        local_color = local_color + material.emission_at(&surface);
+
+        let opacity = material.opacity();
+        if opacity >= 1.0 {
+            return local_color;
+        }
+        let behind = self.trace_ray(
+            point,
+            direction,
+            SURFACE_EPSILON,
+            f64::INFINITY,
+            depth,
+            from_camera,
+        );
+        local_color * opacity + behind * (1.0 - opacity)
+    }
+
+    /**
+     * The light arriving along a ray that leaves the scene without hitting anything
+     */
+    fn escaped(&self, direction: WorldCoordinate, from_camera: bool) -> LinearColor {
+        if from_camera && self.transparent_background {
+            LinearColor::default()
+        } else {
+            self.background.radiance(direction)
+        }
+    }
 
-                color.scale(light_intensity)
+    /**
+     * The fraction of the view along a camera ray that objects cover, taking their opacity into
+     * account. Always 1 unless the background is transparent.
+     */
+    fn coverage(&self, origin: WorldCoordinate, direction: WorldCoordinate, t_min: f64) -> f64 {
+        if self.transparent_background {
+            1.0 - self.transmittance(origin, direction, t_min, f64::INFINITY)
+        } else {
+            1.0
+        }
+    }
+
+    /**
+     * The fraction of light that makes it along a ray between `t_min` and `t_max` through any partly
+     * transparent objects in the way
+     */
+    fn transmittance(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+    ) -> f64 {
+        let mut transmittance = self.media_transmittance(origin, direction, t_min, t_max);
+        let mut t_min = t_min;
+        while let Some((sphere, t)) = self.closest_intersection(origin, direction, t_min, t_max) {
+            transmittance *= 1.0 - sphere.material().opacity();
+            if transmittance <= 0.0 {
+                return 0.0;
             }
-            None => self.background_color,
+            t_min = t + SURFACE_EPSILON;
         }
+        transmittance
     }
 
-    fn compute_lighting(&self, point: WorldCoordinate, normal: WorldCoordinate) -> f64 {
-        self.lights
+    /**
+     * The light reflected towards `view` from every light in the scene, for a surface whose color at
+     * `point` is `albedo`
+     */
+    fn compute_lighting(
+        &self,
+        point: WorldCoordinate,
+        normal: WorldCoordinate,
+        view: WorldCoordinate,
+        material: &Material,
+        albedo: LinearColor,
+    ) -> LinearColor {
+        self.lights.iter().fold(LinearColor::default(), |sum, l| {
+            let reflected = match (material.microfacet(), l.direction_from(point)) {
+                (Some(microfacet), Some((to_light, _))) => {
+                    microfacet.reflect(albedo, normal, view, to_light) * l.intensity()
+                }
+                (Some(_), None) => material.ambient_reflectance(albedo) * l.intensity(),
+                (None, _) => {
+                    let diffuse = l.illumination_at_point(point, normal);
+                    let intensity = match material.specular() {
+                        Some(exponent) => {
+                            diffuse + l.specular_at_point(point, normal, view, exponent)
+                        }
+                        None => diffuse,
+                    };
+                    albedo * intensity
+                }
+            };
+            sum + reflected
+        })
+    }
+
+    /**
+     * Take one more sample for every pixel, then write the average of all samples so far to `frame`.
+     * Returns true once the requested number of samples per pixel has been reached.
+     */
+    pub(crate) fn render(&mut self, frame: &mut [u8]) -> bool {
+        if self.accumulator.len() != self.canvas.pixel_count() {
+            self.accumulator = vec![LinearColor::default(); self.canvas.pixel_count()];
+            self.coverage = vec![0.0; self.canvas.pixel_count()];
+            self.samples_taken = 0;
+            self.processed = None;
+        }
+
+        if self.samples_taken < self.samples {
+            let mut rng = Rng::new(self.samples_taken as u64);
+            // The first sample goes through the pixel center, so a single sample matches a plain render
+            let jitter = self.samples_taken > 0;
+            for coord in self.canvas.iter_pixels() {
+                let index = match self.canvas.pixel_index(coord) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let (dx, dy) = if jitter {
+                    (rng.next_f64() - 0.5, rng.next_f64() - 0.5)
+                } else {
+                    (0.0, 0.0)
+                };
+                let (origin, direction, t_min) =
+                    self.camera_ray(coord.x as f64 + dx, coord.y as f64 + dy, &mut rng);
+                let color = match self.integrator {
+                    Integrator::Whitted => self.trace_ray(
+                        origin,
+                        direction,
+                        t_min,
+                        f64::INFINITY,
+                        self.max_depth,
+                        true,
+                    ),
+                    Integrator::Path => self.trace_path(origin, direction, t_min, &mut rng),
+                };
+                self.accumulator[index] = self.accumulator[index] + color;
+                self.coverage[index] += self.coverage(origin, direction, t_min);
+            }
+            self.samples_taken += 1;
+        }
+
+        let finished = self.samples_taken >= self.samples;
+        if finished && self.processed.is_none() {
+            self.post_process();
+        }
+        self.write_frame(frame);
+        finished
+    }
+
+    /**
+     * Apply the post-processing passes to the finished image
+     */
+    fn post_process(&mut self) {
+        let (mut image, _) = self.average();
+        for pass in &self.post {
+            pass.apply(
+                &mut image,
+                self.canvas.width(),
+                self.canvas.height(),
+                self.color_space,
+            );
+        }
+        self.processed = Some(image);
+    }
+
+    /**
+     * The average of the samples taken so far for every pixel, along with how much of the pixel
+     * the scene covers. Colors are straight rather than premultiplied by coverage, so that
+     * post-processing treats transparent pixels like any other.
+     */
+    fn average(&self) -> (Vec<LinearColor>, Vec<f64>) {
+        let scale = 1.0 / self.samples_taken.max(1) as f64;
+        let alpha: Vec<f64> = self.coverage.iter().map(|c| c * scale).collect();
+        let image = self
+            .accumulator
             .iter()
-            .map(|l| l.illumination_at_point(point, normal))
-            .sum()
+            .zip(&alpha)
+            .map(|(color, alpha)| {
+                if *alpha > 0.0 {
+                    *color * (scale / alpha)
+                } else {
+                    LinearColor::default()
+                }
+            })
+            .collect();
+        (image, alpha)
     }
 
-    pub(crate) fn render(&self, frame: &mut [u8]) {
+    /**
+     * Write the image to `frame`, post-processed once it is finished, as RGBA with the alpha
+     * channel giving how much of the pixel the scene covers
+     */
+    fn write_frame(&self, frame: &mut [u8]) {
+        let (average, alpha) = self.average();
+        let image = self.processed.as_ref().unwrap_or(&average);
+
         for coord in self.canvas.iter_pixels() {
-            let viewport_coord = self.convert(coord);
-            let color = self.trace_ray(viewport_coord, 1f64, f64::INFINITY);
-            self.canvas.put_pixel(frame, coord, color);
+            if let Some(index) = self.canvas.pixel_index(coord) {
+                self.canvas.put_pixel(
+                    frame,
+                    coord,
+                    image[index].to_color_with_alpha(self.color_space, alpha[index]),
+                );
+            }
         }
     }
 
+    /**
+     * Save the image rendered so far as a PNG, including its alpha channel
+     */
+    pub(crate) fn save_png(&self, path: &Path) -> Result<(), String> {
+        let mut frame = vec![0u8; self.canvas.pixel_count() * 4];
+        self.write_frame(&mut frame);
+        image::save_buffer_with_format(
+            path,
+            &frame,
+            self.canvas.width() as u32,
+            self.canvas.height() as u32,
+            image::ColorType::Rgba8,
+            image::ImageFormat::Png,
+        )
+        .map_err(|e| format!("Failed to save image '{}': {}", path.display(), e))
+    }
+
+    /**
+     * The point on the viewport corresponding to the (possibly fractional) canvas position (x, y)
+     */
+    fn viewport_point(&self, x: f64, y: f64) -> WorldCoordinate {
+        let x = x * (self.viewport.width / self.canvas.width() as f64);
+        let y = y * (self.viewport.height / self.canvas.height() as f64);
+        let z = self.viewport.depth;
+        WorldCoordinate::new(x, y, z)
+    }
+
+    /**
+     * The origin and direction of a ray through the canvas position (x, y), along with the distance
+     * along it of the viewport, where the ray starts counting hits.
+     * With an aperture, the origin is a random point on the lens and the ray is aimed at where the
+     * pinhole ray through (x, y) crosses the plane of focus.
+     */
+    fn camera_ray(&self, x: f64, y: f64, rng: &mut Rng) -> (WorldCoordinate, WorldCoordinate, f64) {
+        let direction = self.viewport_point(x, y);
+        if self.aperture <= 0.0 {
+            return (self.camera_position, direction, 1.0);
+        }
+
+        // The camera looks along z, so the lens lies in the xy plane
+        let (lens_x, lens_y) = uniform_disk(rng);
+        let radius = self.aperture / 2.0;
+        let origin =
+            self.camera_position + WorldCoordinate::new(lens_x * radius, lens_y * radius, 0.0);
+        let focus = self.camera_position + direction * (self.focal_distance / self.viewport.depth);
+        let direction = focus - origin;
+        (origin, direction, self.viewport.depth / self.focal_distance)
+    }
+
+    /**
+     * The file the finished image should be saved to, if it hasn't been taken already
+     */
+    pub(crate) fn take_output(&mut self) -> Option<PathBuf> {
+        self.output.take()
+    }
+
     pub(crate) fn canvas(&self) -> Canvas {
         self.canvas
     }
+
+    pub(crate) fn title(&self) -> &str {
+        &self.title
+    }
 }
 
 impl Converts<CanvasCoordinate, WorldCoordinate> for Scene {
     fn convert(&self, coord: CanvasCoordinate) -> WorldCoordinate {
-        let x = coord.x as f64 * (self.viewport.width as f64 / self.canvas.width() as f64);
-        let y = coord.y as f64 * (self.viewport.height as f64 / self.canvas.height() as f64);
-        let z = self.viewport.depth as f64;
-        WorldCoordinate::new(x, y, z)
+        self.viewport_point(coord.x as f64, coord.y as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coord::ORIGIN;
+    use crate::scene::texture::Texture;
+
+    fn camera(aperture: f64, focal_distance: f64) -> Scene {
+        let canvas = Canvas::default();
+        Scene::new(
+            ORIGIN,
+            ViewPort::for_canvas(canvas),
+            canvas,
+            Background::Color(Texture::Solid(LinearColor::default())),
+            "test".into(),
+        )
+        .with_depth_of_field(aperture, focal_distance)
+    }
+
+    #[test]
+    fn pinhole_rays_start_at_the_camera() {
+        let scene = camera(0.0, 5.0);
+        let (origin, direction, t_min) = scene.camera_ray(10.0, -20.0, &mut Rng::new(1));
+        assert!(origin == ORIGIN);
+        assert!(direction == scene.viewport_point(10.0, -20.0));
+        assert_eq!(t_min, 1.0);
+    }
+
+    #[test]
+    fn rays_through_the_lens_meet_on_the_plane_of_focus() {
+        let scene = camera(0.5, 5.0);
+        let pinhole = scene.viewport_point(10.0, -20.0) * (5.0 / scene.viewport.depth);
+        let mut rng = Rng::new(1);
+        let mut origins = vec![];
+        for _ in 0..20 {
+            let (origin, direction, t_min) = scene.camera_ray(10.0, -20.0, &mut rng);
+            // Start on the lens, with hits counted from the viewport onwards
+            assert!(origin.z() == 0.0 && origin.abs() <= 0.25);
+            assert!(((origin + direction * t_min).z() - scene.viewport.depth).abs() < 1e-12);
+            // The point on the ray at the focal distance is the same for every ray: it is sharp
+            let t = (5.0 - origin.z()) / direction.z();
+            assert!((origin + direction * t - pinhole).abs() < 1e-12);
+            origins.push(origin);
+        }
+        assert!(origins.iter().any(|origin| *origin != origins[0]));
     }
 }
@@ -0,0 +1,252 @@
+use crate::color::LinearColor;
+use crate::coord::WorldCoordinate;
+use crate::raster::{Filter, Raster, Wrap};
+use crate::scene::object::shape::SurfacePoint;
+use crate::scene::sky::Sky;
+use crate::scene::texture::Texture;
+use std::f64::consts::PI;
+
+/**
+ * What is seen along rays that leave the scene without hitting anything
+ */
+pub(crate) enum Background {
+    Color(Texture),
+    /// Blends from `bottom` looking straight down to `top` looking straight up
+    Gradient {
+        top: Texture,
+        bottom: Texture,
+    },
+    /// An equirectangular (latitude/longitude) environment map
+    Image {
+        raster: Raster,
+        intensity: f64,
+    },
+    Sky(Sky),
+}
+
+impl Background {
+    pub(crate) fn radiance(&self, direction: WorldCoordinate) -> LinearColor {
+        match self {
+            Self::Color(color) => color_toward(color, direction),
+            Self::Gradient { top, bottom } => {
+                let t = 0.5 * (direction.normalize().y() + 1.0);
+                color_toward(bottom, direction) * (1.0 - t) + color_toward(top, direction) * t
+            }
+            Self::Image { raster, intensity } => {
+                let (u, v) = equirectangular_uv(direction.normalize());
+                raster.sample(u, v, Filter::Bilinear, Wrap::Repeat, Wrap::Clamp) * *intensity
+            }
+            Self::Sky(sky) => sky.radiance(direction),
+        }
+    }
+}
+
+/**
+ * The diffuse light the background casts onto a surface, precomputed for every surface orientation
+ */
+pub(crate) struct IrradianceMap {
+    values: Vec<LinearColor>,
+}
+
+const IRRADIANCE_WIDTH: usize = 32;
+const IRRADIANCE_HEIGHT: usize = 16;
+const ENVIRONMENT_WIDTH: usize = 64;
+const ENVIRONMENT_HEIGHT: usize = 32;
+
+impl IrradianceMap {
+    pub(crate) fn new(background: &Background) -> Self {
+        let environment = downsample(background);
+        let cell_angle = (2.0 * PI / ENVIRONMENT_WIDTH as f64) * (PI / ENVIRONMENT_HEIGHT as f64);
+        let cells: Vec<(WorldCoordinate, LinearColor)> = environment
+            .into_iter()
+            .enumerate()
+            .map(|(i, radiance)| {
+                let (x, y) = (i % ENVIRONMENT_WIDTH, i / ENVIRONMENT_WIDTH);
+                let u = (x as f64 + 0.5) / ENVIRONMENT_WIDTH as f64;
+                let v = (y as f64 + 0.5) / ENVIRONMENT_HEIGHT as f64;
+                let direction = equirectangular_direction(u, v);
+                // Cells shrink towards the poles
+                let solid_angle = cell_angle * (v * PI).sin();
+                (direction, radiance * solid_angle)
+            })
+            .collect();
+
+        let mut values = Vec::with_capacity(IRRADIANCE_WIDTH * IRRADIANCE_HEIGHT);
+        for y in 0..IRRADIANCE_HEIGHT {
+            for x in 0..IRRADIANCE_WIDTH {
+                let normal = equirectangular_direction(
+                    (x as f64 + 0.5) / IRRADIANCE_WIDTH as f64,
+                    (y as f64 + 0.5) / IRRADIANCE_HEIGHT as f64,
+                );
+                let irradiance =
+                    cells
+                        .iter()
+                        .fold(LinearColor::default(), |sum, (direction, weighted)| {
+                            let cos_theta = normal.dot(*direction);
+                            if cos_theta > 0.0 {
+                                sum + *weighted * cos_theta
+                            } else {
+                                sum
+                            }
+                        });
+                // Divide by pi so that a uniform background of radiance L gives L
+                values.push(irradiance * (1.0 / PI));
+            }
+        }
+
+        Self { values }
+    }
+
+    /**
+     * The light reflected by a white diffuse surface facing `normal`, ignoring any objects in the way
+     */
+    pub(crate) fn irradiance(&self, normal: WorldCoordinate) -> LinearColor {
+        let (u, v) = equirectangular_uv(normal);
+        let x = ((u * IRRADIANCE_WIDTH as f64) as usize).min(IRRADIANCE_WIDTH - 1);
+        let y = ((v * IRRADIANCE_HEIGHT as f64) as usize).min(IRRADIANCE_HEIGHT - 1);
+        self.values[y * IRRADIANCE_WIDTH + x]
+    }
+}
+
+/**
+ * The average radiance of the background over each cell of a coarse latitude/longitude grid
+ */
+fn downsample(background: &Background) -> Vec<LinearColor> {
+    let mut cells = vec![LinearColor::default(); ENVIRONMENT_WIDTH * ENVIRONMENT_HEIGHT];
+    match background {
+        Background::Image { raster, intensity } => {
+            let mut counts = vec![0usize; cells.len()];
+            for y in 0..raster.height() {
+                for x in 0..raster.width() {
+                    let cell_x = x * ENVIRONMENT_WIDTH / raster.width();
+                    let cell_y = y * ENVIRONMENT_HEIGHT / raster.height();
+                    let cell = cell_y * ENVIRONMENT_WIDTH + cell_x;
+                    cells[cell] = cells[cell] + raster.pixel(x, y) * *intensity;
+                    counts[cell] += 1;
+                }
+            }
+            for (cell, count) in cells.iter_mut().zip(counts) {
+                if count > 0 {
+                    *cell = *cell * (1.0 / count as f64);
+                }
+            }
+        }
+        _ => {
+            for (i, cell) in cells.iter_mut().enumerate() {
+                let u = ((i % ENVIRONMENT_WIDTH) as f64 + 0.5) / ENVIRONMENT_WIDTH as f64;
+                let v = ((i / ENVIRONMENT_WIDTH) as f64 + 0.5) / ENVIRONMENT_HEIGHT as f64;
+                *cell = background.radiance(equirectangular_direction(u, v));
+            }
+        }
+    }
+    cells
+}
+
+/**
+ * The color of a texture covering the inside of a unit sphere around the scene, seen looking in
+ * `direction`. Image textures are laid out like image backgrounds.
+ */
+pub(crate) fn color_toward(texture: &Texture, direction: WorldCoordinate) -> LinearColor {
+    let normal = direction.normalize();
+    let phi = normal.x().atan2(normal.z());
+    let tangent = WorldCoordinate::new(phi.cos(), 0.0, -phi.sin());
+    texture.color_at(&SurfacePoint {
+        local: normal,
+        uv: equirectangular_uv(normal),
+        normal,
+        tangent,
+        bitangent: normal.cross(tangent),
+    })
+}
+
+/**
+ * Map a unit direction to equirectangular image coordinates: u runs once around the horizon starting
+ * behind the camera, and v runs from straight up (0) to straight down (1)
+ */
+fn equirectangular_uv(direction: WorldCoordinate) -> (f64, f64) {
+    let u = 0.5 + direction.x().atan2(direction.z()) / (2.0 * PI);
+    let v = direction.y().clamp(-1.0, 1.0).acos() / PI;
+    (u, v)
+}
+
+fn equirectangular_direction(u: f64, v: f64) -> WorldCoordinate {
+    let theta = v * PI;
+    let phi = (u - 0.5) * 2.0 * PI;
+    WorldCoordinate::new(
+        theta.sin() * phi.sin(),
+        theta.cos(),
+        theta.sin() * phi.cos(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::texture::{Pattern, PatternTexture};
+
+    fn close(a: LinearColor, b: LinearColor, tolerance: f64) -> bool {
+        (a + b * -1.0).max_channel() < tolerance && (b + a * -1.0).max_channel() < tolerance
+    }
+
+    #[test]
+    fn looking_ahead_is_the_center_of_an_environment_map() {
+        let (u, v) = equirectangular_uv(WorldCoordinate::new(0.0, 0.0, 1.0));
+        assert!((u - 0.5).abs() < 1e-12 && (v - 0.5).abs() < 1e-12);
+        let (_, v) = equirectangular_uv(WorldCoordinate::new(0.0, 1.0, 0.0));
+        assert_eq!(v, 0.0);
+    }
+
+    #[test]
+    fn directions_survive_the_equirectangular_mapping() {
+        let direction = WorldCoordinate::new(0.3, -0.5, 0.8).normalize();
+        let (u, v) = equirectangular_uv(direction);
+        let back = equirectangular_direction(u, v);
+        assert!((back - direction).abs() < 1e-9);
+    }
+
+    #[test]
+    fn gradients_run_from_bottom_to_top() {
+        let top = LinearColor::new(0.0, 0.0, 1.0);
+        let bottom = LinearColor::new(1.0, 0.0, 0.0);
+        let gradient = Background::Gradient {
+            top: Texture::Solid(top),
+            bottom: Texture::Solid(bottom),
+        };
+        assert_eq!(gradient.radiance(WorldCoordinate::new(0.0, 2.0, 0.0)), top);
+        assert_eq!(
+            gradient.radiance(WorldCoordinate::new(0.0, -1.0, 0.0)),
+            bottom
+        );
+        let horizon = gradient.radiance(WorldCoordinate::new(1.0, 0.0, 0.0));
+        assert!(close(horizon, LinearColor::new(0.5, 0.0, 0.5), 1e-12));
+    }
+
+    #[test]
+    fn textured_backgrounds_are_looked_up_by_direction() {
+        let top = LinearColor::new(0.0, 0.0, 1.0);
+        let bottom = LinearColor::new(1.0, 0.0, 0.0);
+        let stripes = PatternTexture::new(Pattern::Stripes, bottom, top);
+        let background = Background::Color(Texture::Pattern(stripes));
+        assert_eq!(
+            background.radiance(WorldCoordinate::new(0.0, 3.0, 0.0)),
+            top
+        );
+        assert_eq!(
+            background.radiance(WorldCoordinate::new(1.0, 0.5, 0.0)),
+            bottom
+        );
+    }
+
+    #[test]
+    fn a_uniform_background_lights_every_surface_equally() {
+        let color = LinearColor::new(0.2, 0.4, 0.8);
+        let map = IrradianceMap::new(&Background::Color(Texture::Solid(color)));
+        for normal in [
+            WorldCoordinate::new(0.0, 1.0, 0.0),
+            WorldCoordinate::new(1.0, 0.0, 0.0),
+            WorldCoordinate::new(0.0, -0.6, 0.8),
+        ] {
+            assert!(close(map.irradiance(normal), color, 0.02));
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use super::{Scene, SURFACE_EPSILON};
+use crate::color::LinearColor;
+use crate::coord::WorldCoordinate;
+use crate::scene::background::color_toward;
+use crate::scene::object::light::Light;
+use crate::scene::object::shape::Sphere;
+use crate::scene::texture::Texture;
+
+/**
+ * The number of points sampled along each stretch of a ray through a volume
+ */
+const MEDIUM_STEPS: usize = 32;
+
+/**
+ * Exponential fog filling the whole scene, optionally thinning out with height above y = 0
+ */
+pub(crate) struct Fog {
+    color: Texture,
+    density: f64,
+    falloff: f64,
+}
+
+impl Fog {
+    /**
+     * Fog of the given `color` that hides half of what is about 0.7 / `density` units away at
+     * y = 0, with its density shrinking by a factor of e every 1 / `falloff` units higher up. A
+     * textured color is looked up by the direction of each ray, like a background.
+     */
+    pub(crate) fn new(color: Texture, density: f64, falloff: f64) -> Self {
+        Self {
+            color,
+            density,
+            falloff,
+        }
+    }
+
+    /**
+     * The integral of the fog density along `distance` units of a ray from `origin` in the unit
+     * `direction`
+     */
+    fn optical_depth(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        distance: f64,
+    ) -> f64 {
+        let base = self.density * (-self.falloff * origin.y()).exp();
+        let rate = self.falloff * direction.y();
+        if rate.abs() < 1e-9 {
+            return base * distance;
+        }
+        if distance.is_infinite() {
+            // Looking upwards the fog thins out quickly enough for the total to be finite
+            return if rate > 0.0 {
+                base / rate
+            } else {
+                f64::INFINITY
+            };
+        }
+        base * (1.0 - (-rate * distance).exp()) / rate
+    }
+}
+
+impl Scene {
+    /**
+     * What fog and volumes add to a ray between `t_min` and `t_max`, and the fraction of the light
+     * from further along that makes it through them
+     */
+    pub(super) fn along_ray(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+    ) -> (LinearColor, f64) {
+        let (mut added, mut transmittance) =
+            self.scatter_in_volumes(origin, direction, t_min, t_max);
+        if let Some(fog) = &self.fog {
+            let fog_transmittance = self.fog_transmittance(fog, origin, direction, t_min, t_max);
+            added = added * fog_transmittance
+                + color_toward(&fog.color, direction) * (1.0 - fog_transmittance);
+            transmittance *= fog_transmittance;
+        }
+        (added, transmittance)
+    }
+
+    /**
+     * The fraction of light that makes it through fog and volumes between `t_min` and `t_max`
+     */
+    pub(super) fn media_transmittance(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+    ) -> f64 {
+        let scale = direction.abs();
+        let volumes: f64 = self
+            .volume_overlaps(origin, direction, t_min, t_max)
+            .iter()
+            .map(|(volume, start, end)| volume_density(volume) * (end - start) * scale)
+            .sum();
+        let fog = match &self.fog {
+            Some(fog) => self.fog_transmittance(fog, origin, direction, t_min, t_max),
+            None => 1.0,
+        };
+        (-volumes).exp() * fog
+    }
+
+    fn fog_transmittance(
+        &self,
+        fog: &Fog,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+    ) -> f64 {
+        let scale = direction.abs();
+        let unit = direction / scale;
+        let start = origin + direction * t_min;
+        (-fog.optical_depth(start, unit, (t_max - t_min) * scale)).exp()
+    }
+
+    /**
+     * Single scattering: light from the scene's lights scattered towards the ray by every volume it
+     * passes through, found by sampling points along the ray
+     */
+    fn scatter_in_volumes(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+    ) -> (LinearColor, f64) {
+        let mut added = LinearColor::default();
+        let mut transmittance = 1.0;
+        let scale = direction.abs();
+
+        for (volume, start, end) in self.volume_overlaps(origin, direction, t_min, t_max) {
+            let density = volume_density(volume);
+            let dt = (end - start) / MEDIUM_STEPS as f64;
+            let step_transmittance = (-density * dt * scale).exp();
+            for step in 0..MEDIUM_STEPS {
+                let point = origin + direction * (start + (step as f64 + 0.5) * dt);
+                let albedo = volume.material().color_at(&volume.surface_at(point));
+                let scattered = albedo * (self.light_in_volume(point) * density * dt * scale);
+                added = added + scattered * (transmittance * step_transmittance.sqrt());
+                transmittance *= step_transmittance;
+            }
+        }
+        (added, transmittance)
+    }
+
+    /**
+     * The light arriving at a point inside a volume, which scatters equally in every direction.
+     * Lights are scaled as for surfaces, where a white diffuse surface facing a light reflects its
+     * full intensity.
+     */
+    fn light_in_volume(&self, point: WorldCoordinate) -> f64 {
+        self.lights
+            .iter()
+            .map(|light| match light {
+                Light::Ambient { intensity } => *intensity,
+                _ => match light.direction_from(point) {
+                    Some((to_light, distance)) => {
+                        // Spread over the whole sphere of directions rather than a hemisphere
+                        light.intensity()
+                            * self.transmittance(point, to_light, SURFACE_EPSILON, distance)
+                            / 4.0
+                    }
+                    None => 0.0,
+                },
+            })
+            .sum()
+    }
+
+    /**
+     * The stretches of the ray between `t_min` and `t_max` inside each volume, in the order the ray
+     * enters them
+     */
+    fn volume_overlaps(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        t_max: f64,
+    ) -> Vec<(&Sphere, f64, f64)> {
+        let mut overlaps: Vec<(&Sphere, f64, f64)> = self
+            .volumes
+            .iter()
+            .filter_map(|volume| {
+                let (t1, t2) = volume.intersect_ray(origin, direction);
+                let start = t1.min(t2).max(t_min);
+                let end = t1.max(t2).min(t_max);
+                if start < end {
+                    Some((volume, start, end))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        overlaps.sort_by(|a, b| a.1.total_cmp(&b.1));
+        overlaps
+    }
+}
+
+fn volume_density(volume: &Sphere) -> f64 {
+    volume.material().density().unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::coord::ORIGIN;
+    use crate::scene::background::Background;
+    use crate::scene::object::material::Material;
+    use crate::scene::ViewPort;
+
+    fn forward() -> WorldCoordinate {
+        WorldCoordinate::new(0.0, 0.0, 1.0)
+    }
+
+    fn scene(spheres: Vec<Sphere>, lights: Vec<Light>) -> Scene {
+        let canvas = Canvas::default();
+        Scene::new(
+            ORIGIN,
+            ViewPort::for_canvas(canvas),
+            canvas,
+            Background::Color(Texture::Solid(LinearColor::default())),
+            "test".into(),
+        )
+        .with_spheres(spheres)
+        .with_lights(lights)
+    }
+
+    /**
+     * A volume of radius 1 straight ahead of the camera
+     */
+    fn volume(density: f64) -> Sphere {
+        let white = Texture::Solid(LinearColor::new(1.0, 1.0, 1.0));
+        Sphere::new(
+            1.0,
+            WorldCoordinate::new(0.0, 0.0, 5.0),
+            Material::new(white).with_density(density),
+        )
+    }
+
+    fn grey_fog(density: f64, falloff: f64) -> Fog {
+        Fog::new(
+            Texture::Solid(LinearColor::new(0.5, 0.5, 0.5)),
+            density,
+            falloff,
+        )
+    }
+
+    #[test]
+    fn volumes_dim_light_by_their_density_and_thickness() {
+        let scene = scene(vec![volume(0.5)], vec![]);
+        let transmittance = scene.media_transmittance(ORIGIN, forward(), 0.0, f64::INFINITY);
+        assert!((transmittance - (-0.5 * 2.0_f64).exp()).abs() < 1e-12);
+        // Only the part of the ray inside the volume counts
+        let halfway = scene.media_transmittance(ORIGIN, forward(), 0.0, 5.0);
+        assert!((halfway - (-0.5_f64).exp()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn a_fully_opaque_volume_lets_no_light_through() {
+        let scene = scene(vec![volume(1e6)], vec![]);
+        assert_eq!(
+            scene.media_transmittance(ORIGIN, forward(), 0.0, f64::INFINITY),
+            0.0
+        );
+        assert_eq!(
+            scene.along_ray(ORIGIN, forward(), 0.0, f64::INFINITY).1,
+            0.0
+        );
+    }
+
+    #[test]
+    fn unlit_volumes_add_no_light() {
+        let scene = scene(vec![volume(0.5)], vec![]);
+        let (added, _) = scene.along_ray(ORIGIN, forward(), 0.0, f64::INFINITY);
+        assert_eq!(added, LinearColor::default());
+        let lit = scene.with_lights(vec![Light::ambient(1.0)]);
+        let (added, _) = lit.along_ray(ORIGIN, forward(), 0.0, f64::INFINITY);
+        assert!(added.max_channel() > 0.0);
+    }
+
+    #[test]
+    fn fog_hides_the_distance_behind_its_color() {
+        let scene = scene(vec![], vec![]).with_fog(grey_fog(0.1, 0.0));
+        let (added, transmittance) = scene.along_ray(ORIGIN, forward(), 0.0, 10.0);
+        assert!((transmittance - (-1.0_f64).exp()).abs() < 1e-12);
+        assert!((added.red() - 0.5 * (1.0 - transmittance)).abs() < 1e-12);
+
+        let (added, transmittance) = scene.along_ray(ORIGIN, forward(), 0.0, f64::INFINITY);
+        assert_eq!(transmittance, 0.0);
+        assert_eq!(added, LinearColor::new(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn fog_thins_out_with_height() {
+        let fog = grey_fog(0.1, 0.5);
+        let up = WorldCoordinate::new(0.0, 1.0, 0.0);
+        // The whole column of fog above y = 0 adds up to density / falloff
+        assert!((fog.optical_depth(ORIGIN, up, f64::INFINITY) - 0.2).abs() < 1e-12);
+        let high = WorldCoordinate::new(0.0, 10.0, 0.0);
+        assert!(
+            fog.optical_depth(high, forward(), 1.0) < fog.optical_depth(ORIGIN, forward(), 1.0)
+        );
+    }
+}
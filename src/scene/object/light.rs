@@ -34,6 +34,52 @@ impl Light {
         }
     }
 
+    /**
+     * The intensity of the Phong highlight this light makes at `point` when seen from direction `view`
+     */
+    pub(crate) fn specular_at_point(
+        self,
+        point: WorldCoordinate,
+        surface_normal: WorldCoordinate,
+        view: WorldCoordinate,
+        exponent: f64,
+    ) -> f64 {
+        let (to_light, _) = match self.direction_from(point) {
+            Some(direction) => direction,
+            None => return 0.0,
+        };
+        let reflected = to_light.reflect(surface_normal);
+        let r_dot_v = reflected.dot(view);
+        if r_dot_v > 0.0 {
+            self.intensity() * (r_dot_v / (reflected.abs() * view.abs())).powf(exponent)
+        } else {
+            0.0
+        }
+    }
+
+    /**
+     * The unit direction from `point` towards this light and the distance to the light,
+     * or None for ambient light, which has no direction
+     */
+    pub(crate) fn direction_from(self, point: WorldCoordinate) -> Option<(WorldCoordinate, f64)> {
+        match self {
+            Self::Ambient { .. } => None,
+            Self::Direction { direction, .. } => Some((direction.normalize(), f64::INFINITY)),
+            Self::Point { position, .. } => {
+                let to_light = position - point;
+                Some((to_light.normalize(), to_light.abs()))
+            }
+        }
+    }
+
+    pub(crate) fn intensity(self) -> f64 {
+        match self {
+            Self::Ambient { intensity }
+            | Self::Direction { intensity, .. }
+            | Self::Point { intensity, .. } => intensity,
+        }
+    }
+
     pub(crate) fn ambient(intensity: f64) -> Self {
         Self::Ambient { intensity }
     }
@@ -0,0 +1,248 @@
+use super::microfacet::Microfacet;
+use super::shape::SurfacePoint;
+use crate::color::LinearColor;
+use crate::coord::WorldCoordinate;
+use crate::scene::texture::Texture;
+
+/**
+ * How the surface of an object interacts with light
+ */
+#[derive(Clone)]
+pub(crate) struct Material {
+    color: Texture,
+    specular: Option<f64>,
+    microfacet: Option<Microfacet>,
+    reflective: f64,
+    opacity: f64,
+    density: Option<f64>,
+    emission: Texture,
+    normal_map: Option<Texture>,
+    bump: Option<(Texture, f64)>,
+}
+
+impl Material {
+    pub(crate) fn new(color: Texture) -> Self {
+        Self {
+            color,
+            specular: None,
+            microfacet: None,
+            reflective: 0.0,
+            opacity: 1.0,
+            density: None,
+            emission: Texture::Solid(LinearColor::default()),
+            normal_map: None,
+            bump: None,
+        }
+    }
+
+    /**
+     * Give the surface Phong highlights; larger exponents give smaller, sharper highlights
+     */
+    pub(crate) fn with_specular(self, exponent: f64) -> Self {
+        Self {
+            specular: Some(exponent),
+            ..self
+        }
+    }
+
+    /**
+     * Shade the surface with a physically based metallic/roughness model instead of diffuse and
+     * Phong lighting
+     */
+    pub(crate) fn with_microfacet(self, microfacet: Microfacet) -> Self {
+        Self {
+            microfacet: Some(microfacet),
+            ..self
+        }
+    }
+
+    /**
+     * Make the surface mirror-like: 0 reflects nothing and 1 is a perfect mirror
+     */
+    pub(crate) fn with_reflective(self, reflective: f64) -> Self {
+        Self { reflective, ..self }
+    }
+
+    /**
+     * Let some light pass straight through the surface: 1 is fully opaque and 0 is invisible
+     */
+    pub(crate) fn with_opacity(self, opacity: f64) -> Self {
+        Self { opacity, ..self }
+    }
+
+    /**
+     * Turn objects made of this material into volumes of smoke or mist, which have no surface but
+     * scatter light throughout their inside. `density` is the fraction of light scattered or
+     * absorbed per unit distance, and the material's color tints the scattered light.
+     */
+    pub(crate) fn with_density(self, density: f64) -> Self {
+        Self {
+            density: Some(density),
+            ..self
+        }
+    }
+
+    /**
+     * Make the surface glow with the given radiance, independently of any light falling on it
+     */
+    pub(crate) fn with_emission(self, emission: Texture) -> Self {
+        Self { emission, ..self }
+    }
+
+    /**
+     * Bend the shading normal using a tangent-space normal map, where red, green and blue give how
+     * far the normal leans along the tangent, the bitangent and the geometric normal
+     */
+    pub(crate) fn with_normal_map(self, normal_map: Texture) -> Self {
+        Self {
+            normal_map: Some(normal_map),
+            ..self
+        }
+    }
+
+    /**
+     * Bend the shading normal as if the surface were raised by the brightness of `bump`, scaled by
+     * `height`
+     */
+    pub(crate) fn with_bump(self, bump: Texture, height: f64) -> Self {
+        Self {
+            bump: Some((bump, height)),
+            ..self
+        }
+    }
+
+    /**
+     * The normal to shade `point` with, which the normal and bump maps may tilt away from the
+     * geometric normal
+     */
+    pub(crate) fn shading_normal(&self, point: &SurfacePoint) -> WorldCoordinate {
+        let mut normal = point.normal;
+        if let Some(normal_map) = &self.normal_map {
+            let texel = normal_map.color_at(point);
+            normal = point.tangent * (2.0 * texel.red() - 1.0)
+                + point.bitangent * (2.0 * texel.green() - 1.0)
+                + point.normal * (2.0 * texel.blue() - 1.0);
+        }
+        if let Some((bump, height)) = &self.bump {
+            let (along_tangent, along_bitangent) = bump.slope_at(point);
+            normal = normal
+                - (point.tangent * along_tangent + point.bitangent * along_bitangent) * *height;
+        }
+        let normal = normal.normalize();
+        // Never let the shading normal face into the surface
+        if normal.dot(point.normal) > 0.0 {
+            normal
+        } else {
+            point.normal
+        }
+    }
+
+    /**
+     * The surface color at `point`
+     */
+    pub(crate) fn color_at(&self, point: &SurfacePoint) -> LinearColor {
+        self.color.color_at(point)
+    }
+
+    pub(crate) fn specular(&self) -> Option<f64> {
+        self.specular
+    }
+
+    pub(crate) fn microfacet(&self) -> Option<Microfacet> {
+        self.microfacet
+    }
+
+    /**
+     * How much of the light arriving equally from every direction, such as ambient light, the surface
+     * reflects, given its color `albedo` at the point being shaded
+     */
+    pub(crate) fn ambient_reflectance(&self, albedo: LinearColor) -> LinearColor {
+        match self.microfacet {
+            Some(microfacet) => microfacet.ambient(albedo),
+            None => albedo,
+        }
+    }
+
+    pub(crate) fn reflective(&self) -> f64 {
+        self.reflective
+    }
+
+    pub(crate) fn opacity(&self) -> f64 {
+        self.opacity
+    }
+
+    pub(crate) fn density(&self) -> Option<f64> {
+        self.density
+    }
+
+    /**
+     * The emitted radiance at `point`
+     */
+    pub(crate) fn emission_at(&self, point: &SurfacePoint) -> LinearColor {
+        self.emission.color_at(point)
+    }
+
+    pub(crate) fn is_emissive(&self) -> bool {
+        self.emission.max_channel() > 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::texture::{Pattern, PatternTexture};
+
+    /**
+     * A point on a surface facing the camera, with u increasing to the right
+     */
+    fn facing_camera() -> SurfacePoint {
+        SurfacePoint {
+            local: WorldCoordinate::new(0.0, 0.0, -1.0),
+            uv: (0.5, 0.5),
+            normal: WorldCoordinate::new(0.0, 0.0, -1.0),
+            tangent: WorldCoordinate::new(1.0, 0.0, 0.0),
+            bitangent: WorldCoordinate::new(0.0, 1.0, 0.0),
+        }
+    }
+
+    fn plain() -> Material {
+        Material::new(Texture::Solid(LinearColor::new(1.0, 1.0, 1.0)))
+    }
+
+    #[test]
+    fn a_flat_normal_map_keeps_the_geometric_normal() {
+        let flat = Texture::Solid(LinearColor::new(0.5, 0.5, 1.0));
+        let normal = plain()
+            .with_normal_map(flat)
+            .shading_normal(&facing_camera());
+        assert!((normal - facing_camera().normal).abs() < 1e-12);
+    }
+
+    #[test]
+    fn normal_maps_lean_the_normal_along_the_tangent_and_bitangent() {
+        let leaning = Texture::Solid(LinearColor::new(1.0, 0.5, 0.5 + 0.5 * 0.5));
+        let normal = plain()
+            .with_normal_map(leaning)
+            .shading_normal(&facing_camera());
+        let expected = WorldCoordinate::new(1.0, 0.0, -0.5).normalize();
+        assert!((normal - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn bumps_tilt_the_normal_away_from_rising_ground() {
+        let black = LinearColor::new(0.0, 0.0, 0.0);
+        let white = LinearColor::new(1.0, 1.0, 1.0);
+        // Brighter, so higher, towards +x
+        let slope = PatternTexture::new(Pattern::Gradient, black, white)
+            .with_direction(WorldCoordinate::new(1.0, 0.0, 0.0));
+        let normal = plain()
+            .with_bump(Texture::Pattern(slope), 1.0)
+            .shading_normal(&facing_camera());
+        assert!(normal.x() < 0.0);
+        assert!(normal.y().abs() < 1e-9);
+        assert!((normal.abs() - 1.0).abs() < 1e-12);
+
+        let flat = plain().with_bump(Texture::Solid(white), 1.0);
+        assert!(flat.shading_normal(&facing_camera()) == facing_camera().normal);
+    }
+}
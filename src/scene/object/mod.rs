@@ -1,2 +1,4 @@
 pub(crate) mod light;
+pub(crate) mod material;
+pub(crate) mod microfacet;
 pub(crate) mod shape;
@@ -1,11 +1,28 @@
-use crate::color::Color;
+use super::material::Material;
 use crate::scene::WorldCoordinate;
+use std::f64::consts::PI;
 
-#[derive(Copy, Clone)]
+/**
+ * Where on an object a ray hit, in the forms textures need to look up their color
+ */
+pub(crate) struct SurfacePoint {
+    /// The hit point relative to the object's center, for solid (3D) textures
+    pub local: WorldCoordinate,
+    /// Image coordinates on the surface, each running from 0 to 1
+    pub uv: (f64, f64),
+    /// The outward unit normal of the geometry
+    pub normal: WorldCoordinate,
+    /// A unit vector along the surface in the direction of increasing u
+    pub tangent: WorldCoordinate,
+    /// A unit vector along the surface towards the top of the image (decreasing v)
+    pub bitangent: WorldCoordinate,
+}
+
+#[derive(Clone)]
 pub(crate) struct Sphere {
     radius: f64,
     center: WorldCoordinate,
-    color: Color,
+    material: Material,
 }
 
 impl Sphere {
@@ -32,19 +49,77 @@ impl Sphere {
         }
     }
 
-    pub(crate) fn color(self) -> Color {
-        self.color
+    /**
+     * Describe the point on the surface nearest to `point`. UVs use a spherical (latitude/longitude)
+     * mapping, with u running around the equator so that the middle of an image faces the camera,
+     * and v running from the top of the sphere (0) to the bottom (1).
+     */
+    pub(crate) fn surface_at(&self, point: WorldCoordinate) -> SurfacePoint {
+        let local = point - self.center;
+        let normal = local.normalize();
+        let phi = normal.x().atan2(-normal.z());
+        let u = 0.5 + phi / (2.0 * PI);
+        let v = normal.y().clamp(-1.0, 1.0).acos() / PI;
+        // Lines of latitude run along u; this stays perpendicular to the normal even at the poles
+        let tangent = WorldCoordinate::new(phi.cos(), 0.0, phi.sin());
+        SurfacePoint {
+            local,
+            uv: (u, v),
+            normal,
+            tangent,
+            bitangent: tangent.cross(normal),
+        }
+    }
+
+    pub(crate) fn material(&self) -> &Material {
+        &self.material
+    }
+
+    pub(crate) fn radius(&self) -> f64 {
+        self.radius
     }
 
-    pub(crate) fn center(self) -> WorldCoordinate {
+    pub(crate) fn center(&self) -> WorldCoordinate {
         self.center
     }
 
-    pub(crate) fn new(radius: f64, center: WorldCoordinate, color: Color) -> Self {
+    pub(crate) fn new(radius: f64, center: WorldCoordinate, material: Material) -> Self {
         Self {
             radius,
             center,
-            color,
+            material,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::LinearColor;
+    use crate::scene::texture::Texture;
+
+    fn uv_at(x: f64, y: f64, z: f64) -> (f64, f64) {
+        let center = WorldCoordinate::new(0.0, 0.0, 5.0);
+        let sphere = Sphere::new(
+            2.0,
+            center,
+            Material::new(Texture::Solid(LinearColor::default())),
+        );
+        sphere
+            .surface_at(center + WorldCoordinate::new(x, y, z) * 2.0)
+            .uv
+    }
+
+    #[test]
+    fn the_middle_of_an_image_faces_the_camera() {
+        assert_eq!(uv_at(0.0, 0.0, -1.0), (0.5, 0.5));
+        assert_eq!(uv_at(1.0, 0.0, 0.0), (0.75, 0.5));
+        assert_eq!(uv_at(-1.0, 0.0, 0.0), (0.25, 0.5));
+    }
+
+    #[test]
+    fn images_run_from_the_top_of_a_sphere_to_the_bottom() {
+        assert_eq!(uv_at(0.0, 1.0, 0.0).1, 0.0);
+        assert_eq!(uv_at(0.0, -1.0, 0.0).1, 1.0);
+    }
+}
@@ -0,0 +1,407 @@
+use super::sampling::{cosine_hemisphere, sphere_cone, Rng};
+use super::{Scene, SURFACE_EPSILON};
+use crate::color::LinearColor;
+use crate::coord::WorldCoordinate;
+use crate::scene::object::light::Light;
+use crate::scene::object::shape::Sphere;
+use std::f64::consts::PI;
+
+/**
+ * The number of bounces after which paths may be terminated by Russian roulette
+ */
+const MIN_BOUNCES: usize = 3;
+
+impl Scene {
+    /**
+     * Estimate the light arriving along a ray by following a single random path through the scene.
+     *
+     * Surfaces are treated as Lambertian reflectors of their color, unless they use the microfacet
+     * model. Light intensities keep the meaning
+     * they have for the Whitted integrator (no falloff with distance), and ambient light acts as a
+     * uniform environment that lights any path escaping the scene after at least one bounce, as does
+     * the background when environment lighting is enabled.
+     * Emissive objects are sampled as area lights, so their emission is only added directly when
+     * seen by the camera or in a mirror; adding it again when a diffuse bounce happens to hit them
+     * would count it twice.
+     * Fog and volumes only scatter light from the lights directly into each stretch of the path.
+     * Passing through a partly transparent surface doesn't count as a bounce towards the maximum
+     * depth.
+     */
+    pub(super) fn trace_path(
+        &self,
+        origin: WorldCoordinate,
+        direction: WorldCoordinate,
+        t_min: f64,
+        rng: &mut Rng,
+    ) -> LinearColor {
+        let mut radiance = LinearColor::default();
+        let mut throughput = LinearColor::new(1.0, 1.0, 1.0);
+        let mut origin = origin;
+        let mut direction = direction;
+        let mut t_min = t_min;
+        let mut after_mirror = false;
+        let mut from_camera = true;
+
+        let mut bounces = 0;
+        while bounces < self.max_depth {
+            let hit = self.closest_intersection(origin, direction, t_min, f64::INFINITY);
+            // Fog and volumes between here and whatever the path hits next
+            let t_end = hit.map_or(f64::INFINITY, |(_, t)| t);
+            let (added, transmittance) = self.along_ray(origin, direction, t_min, t_end);
+            radiance = radiance + throughput * added;
+            throughput = throughput * transmittance;
+
+            let (sphere, t) = match hit {
+                Some(hit) => hit,
+                None => {
+                    let ambient = LinearColor::new(1.0, 1.0, 1.0) * self.ambient_intensity();
+                    let escaped = if from_camera {
+                        self.escaped(direction, true)
+                    } else if self.environment_lighting.is_some() {
+                        self.background.radiance(direction) + ambient
+                    } else {
+                        ambient
+                    };
+                    radiance = radiance + throughput * escaped;
+                    break;
+                }
+            };
+
+            let point = origin + direction * t;
+            let material = sphere.material();
+            let surface = sphere.surface_at(point);
+            let normal = {
+                let outward = material.shading_normal(&surface);
+                if surface.normal.dot(direction) > 0.0 {
+                    -outward
+                } else {
+                    outward
+                }
+            };
+            let albedo = material.color_at(&surface);
+
+            origin = point;
+            t_min = SURFACE_EPSILON;
+            // Passing through a partly transparent surface with probability 1 - opacity weights what
+            // is behind it by the same amount, leaving the throughput unchanged
+            if rng.next_f64() >= material.opacity() {
+                continue;
+            }
+
+            // Emitters are not sampled through mirrors, so what a mirror reflects must be counted here
+            if from_camera || after_mirror {
+                radiance = radiance + throughput * material.emission_at(&surface);
+            }
+            // Choosing a mirror bounce with probability `reflective` weights it by `reflective`, so the
+            // throughput is unchanged whichever way the path continues
+            if rng.next_f64() < material.reflective() {
+                direction = (-direction).reflect(normal);
+                after_mirror = true;
+                from_camera = false;
+            } else {
+                // Next event estimation: light arriving directly from each light source
+                let view = -direction.normalize();
+                radiance = radiance
+                    + throughput * self.direct_lighting(point, normal, view, sphere, albedo, rng);
+
+                // Continue the path in a cosine-weighted direction; the cosine and 1/pi of the
+                // Lambertian BRDF cancel with the sampling density, leaving only the albedo, while
+                // a microfacet surface's pi-scaled reflectance only loses the cosine
+                direction = cosine_hemisphere(normal, rng);
+                throughput = throughput
+                    * match material.microfacet() {
+                        Some(microfacet) => {
+                            let cos_theta = normal.dot(direction).max(1e-6);
+                            microfacet.reflect(albedo, normal, view, direction) * (1.0 / cos_theta)
+                        }
+                        None => albedo,
+                    };
+                after_mirror = false;
+                from_camera = false;
+            }
+
+            bounces += 1;
+            if bounces >= MIN_BOUNCES {
+                let survival = throughput.max_channel().min(0.95);
+                if rng.next_f64() >= survival {
+                    break;
+                }
+                throughput = throughput * (1.0 / survival);
+            }
+        }
+
+        radiance
+    }
+
+    /**
+     * The light reflected towards `view` from light arriving at `point` on `sphere` directly from each
+     * light source that isn't blocked by another object. Spheres are convex, so an emissive sphere
+     * can't light itself.
+     */
+    fn direct_lighting(
+        &self,
+        point: WorldCoordinate,
+        normal: WorldCoordinate,
+        view: WorldCoordinate,
+        sphere: &Sphere,
+        albedo: LinearColor,
+        rng: &mut Rng,
+    ) -> LinearColor {
+        let material = sphere.material();
+        let from_lights = self
+            .lights
+            .iter()
+            .filter_map(|light| {
+                let (to_light, distance) = light.direction_from(point)?;
+                let cos_theta = normal.dot(to_light);
+                if cos_theta <= 0.0 {
+                    return None;
+                }
+                let transmittance = self.transmittance(point, to_light, SURFACE_EPSILON, distance);
+                if transmittance <= 0.0 {
+                    return None;
+                }
+                let intensity = light.intensity() * transmittance;
+                Some(match material.microfacet() {
+                    Some(microfacet) => {
+                        microfacet.reflect(albedo, normal, view, to_light) * intensity
+                    }
+                    None => {
+                        let highlight = match material.specular() {
+                            Some(exponent) => {
+                                light.specular_at_point(point, normal, view, exponent)
+                            }
+                            None => 0.0,
+                        };
+                        albedo * (intensity * cos_theta + highlight * transmittance)
+                    }
+                })
+            })
+            .fold(LinearColor::default(), |sum, reflected| sum + reflected);
+
+        let mut from_emitters = LinearColor::default();
+        let emitters = self
+            .spheres
+            .iter()
+            .filter(|s| s.material().is_emissive() && !std::ptr::eq(*s, sphere));
+        for emitter in emitters {
+            let (to_emitter, solid_angle) =
+                match sphere_cone(point, emitter.center(), emitter.radius(), rng) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+            let cos_theta = normal.dot(to_emitter);
+            if cos_theta <= 0.0 {
+                continue;
+            }
+            // The near side of the emitter, which the sample is aimed at
+            let t = match emitter.intersect_ray(point, to_emitter) {
+                (_, near) if near >= SURFACE_EPSILON => near,
+                (far, _) => far,
+            };
+            if !t.is_finite() || t < SURFACE_EPSILON {
+                continue;
+            }
+            // Whatever is in the way, including partly transparent surfaces, fog and volumes,
+            // dims the sample
+            let transmittance =
+                self.transmittance(point, to_emitter, SURFACE_EPSILON, t - SURFACE_EPSILON);
+            if transmittance <= 0.0 {
+                continue;
+            }
+            let emitter_point = emitter.surface_at(point + to_emitter * t);
+            let reflectance = match material.microfacet() {
+                Some(microfacet) => microfacet.reflect(albedo, normal, view, to_emitter),
+                None => albedo * cos_theta,
+            };
+            from_emitters = from_emitters
+                + reflectance
+                    * emitter.material().emission_at(&emitter_point)
+                    * (solid_angle / PI * transmittance);
+        }
+
+        from_lights + from_emitters
+    }
+
+    fn ambient_intensity(&self) -> f64 {
+        self.lights
+            .iter()
+            .filter_map(|light| match light {
+                Light::Ambient { intensity } => Some(*intensity),
+                _ => None,
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::coord::ORIGIN;
+    use crate::scene::background::Background;
+    use crate::scene::object::material::Material;
+    use crate::scene::texture::Texture;
+    use crate::scene::{Integrator, ViewPort};
+
+    fn forward() -> WorldCoordinate {
+        WorldCoordinate::new(0.0, 0.0, 1.0)
+    }
+
+    /**
+     * A sphere straight ahead of the camera
+     */
+    fn ahead(material: Material) -> Sphere {
+        Sphere::new(1.0, WorldCoordinate::new(0.0, 0.0, 5.0), material)
+    }
+
+    fn grey() -> Material {
+        Material::new(Texture::Solid(LinearColor::new(0.5, 0.5, 0.5)))
+    }
+
+    fn scene(
+        spheres: Vec<Sphere>,
+        background: LinearColor,
+        lights: Vec<Light>,
+        max_depth: usize,
+    ) -> Scene {
+        let canvas = Canvas::default();
+        Scene::new(
+            ORIGIN,
+            ViewPort::for_canvas(canvas),
+            canvas,
+            Background::Color(Texture::Solid(background)),
+            "test".into(),
+        )
+        .with_spheres(spheres)
+        .with_lights(lights)
+        .with_integrator(Integrator::Path, 1, max_depth)
+    }
+
+    #[test]
+    fn paths_that_miss_everything_see_the_background() {
+        let background = LinearColor::new(0.2, 0.3, 0.4);
+        let scene = scene(vec![ahead(grey())], background, vec![], 8);
+        let up = WorldCoordinate::new(0.0, 1.0, 0.0);
+        assert_eq!(
+            scene.trace_path(ORIGIN, up, 0.0, &mut Rng::new(1)),
+            background
+        );
+    }
+
+    #[test]
+    fn a_scene_without_lights_is_black() {
+        let scene = scene(vec![ahead(grey())], LinearColor::default(), vec![], 8);
+        for seed in 0..100 {
+            let radiance = scene.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(seed));
+            assert_eq!(radiance, LinearColor::default());
+        }
+    }
+
+    #[test]
+    fn light_reaching_the_first_surface_directly_is_reflected_by_its_albedo() {
+        // The light is behind the camera, so it shines straight at the sphere
+        let lights = vec![Light::point(ORIGIN, 1.0)];
+        let scene = scene(vec![ahead(grey())], LinearColor::default(), lights, 1);
+        let radiance = scene.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(1));
+        assert!((radiance.max_channel() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ambient_light_reaches_paths_that_bounce_out_of_the_scene() {
+        // Every bounce off the only sphere escapes, picking up the ambient light
+        let lights = vec![Light::ambient(1.0)];
+        let scene = scene(vec![ahead(grey())], LinearColor::default(), lights, 2);
+        for seed in 0..20 {
+            let radiance = scene.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(seed));
+            assert!((radiance.max_channel() - 0.5).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn emitters_seen_directly_show_only_their_own_emission() {
+        let glowing = grey().with_emission(Texture::Solid(LinearColor::new(2.0, 1.0, 0.5)));
+        let scene = scene(vec![ahead(glowing)], LinearColor::default(), vec![], 1);
+        let radiance = scene.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(1));
+        assert_eq!(radiance, LinearColor::new(2.0, 1.0, 0.5));
+    }
+
+    #[test]
+    fn emitters_light_other_surfaces() {
+        // Behind the camera, facing the grey sphere
+        let lamp = Sphere::new(
+            1.0,
+            WorldCoordinate::new(0.0, 0.0, -3.0),
+            Material::new(Texture::Solid(LinearColor::default()))
+                .with_emission(Texture::Solid(LinearColor::new(1.0, 1.0, 1.0))),
+        );
+        let scene = scene(vec![ahead(grey()), lamp], LinearColor::default(), vec![], 1);
+        for seed in 0..20 {
+            let radiance = scene.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(seed));
+            assert!(radiance.max_channel() > 0.0);
+        }
+    }
+
+    #[test]
+    fn partly_opaque_spheres_let_some_light_through_each_side() {
+        let scene = scene(
+            vec![ahead(grey().with_opacity(0.5))],
+            LinearColor::default(),
+            vec![],
+            1,
+        )
+        .with_transparent_background();
+        assert_eq!(
+            scene.transmittance(ORIGIN, forward(), 0.0, f64::INFINITY),
+            0.25
+        );
+        assert_eq!(scene.coverage(ORIGIN, forward(), 1.0), 0.75);
+        assert_eq!(
+            scene.coverage(ORIGIN, WorldCoordinate::new(0.0, 1.0, 0.0), 1.0),
+            0.0
+        );
+    }
+
+    #[test]
+    fn passing_through_a_surface_is_not_a_bounce() {
+        let background = LinearColor::new(0.2, 0.3, 0.4);
+        let invisible = ahead(grey().with_opacity(0.0));
+        let scene = scene(vec![invisible], background, vec![], 1);
+        let radiance = scene.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(1));
+        assert_eq!(radiance, background);
+    }
+
+    #[test]
+    fn partly_opaque_spheres_dim_emitters_behind_them() {
+        let lamp = Sphere::new(
+            1.0,
+            WorldCoordinate::new(0.0, 0.0, -6.0),
+            Material::new(Texture::Solid(LinearColor::default()))
+                .with_emission(Texture::Solid(LinearColor::new(1.0, 1.0, 1.0))),
+        );
+        // Between the lamp and the grey sphere, but behind the camera
+        let veil = Sphere::new(
+            0.8,
+            WorldCoordinate::new(0.0, 0.0, -2.0),
+            grey().with_opacity(0.5),
+        );
+        let clear = scene(
+            vec![ahead(grey()), lamp.clone()],
+            LinearColor::default(),
+            vec![],
+            1,
+        );
+        let veiled = scene(
+            vec![ahead(grey()), lamp, veil],
+            LinearColor::default(),
+            vec![],
+            1,
+        );
+        for seed in 0..20 {
+            let clear = clear.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(seed));
+            let veiled = veiled.trace_path(ORIGIN, forward(), 0.0, &mut Rng::new(seed));
+            assert!(clear.max_channel() > 0.0);
+            assert!((veiled.max_channel() - 0.25 * clear.max_channel()).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::coord::WorldCoordinate;
+use std::f64::consts::PI;
+
+/**
+ * A small xorshift* pseudo-random number generator; fast, deterministic per seed,
+ * and plenty good enough for Monte Carlo sampling
+ */
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // Mix the seed so that consecutive seeds give unrelated sequences; the state must never be 0
+        let state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        Self { state }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /**
+     * A uniformly distributed value in [0, 1)
+     */
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/**
+ * Two unit vectors which, together with `normal`, form an orthonormal basis
+ */
+pub(crate) fn orthonormal_basis(normal: WorldCoordinate) -> (WorldCoordinate, WorldCoordinate) {
+    let helper = if normal.x().abs() > 0.9 {
+        WorldCoordinate::new(0.0, 1.0, 0.0)
+    } else {
+        WorldCoordinate::new(1.0, 0.0, 0.0)
+    };
+    let tangent = normal.cross(helper).normalize();
+    let bitangent = normal.cross(tangent);
+
+    (tangent, bitangent)
+}
+
+/**
+ * A direction in the hemisphere around `normal`, with probability density cos(theta) / pi
+ */
+pub(crate) fn cosine_hemisphere(normal: WorldCoordinate, rng: &mut Rng) -> WorldCoordinate {
+    let (u1, u2) = (rng.next_f64(), rng.next_f64());
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    let (tangent, bitangent) = orthonormal_basis(normal);
+
+    tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * (1.0 - u1).sqrt()
+}
+
+/**
+ * A direction from `point` towards the sphere at `center`, chosen uniformly from the cone of
+ * directions in which the sphere is visible, together with the solid angle of that cone.
+ * Returns None if `point` is inside the sphere.
+ */
+pub(crate) fn sphere_cone(
+    point: WorldCoordinate,
+    center: WorldCoordinate,
+    radius: f64,
+    rng: &mut Rng,
+) -> Option<(WorldCoordinate, f64)> {
+    let to_center = center - point;
+    let distance_squared = to_center.dot(to_center);
+    if distance_squared <= radius * radius {
+        return None;
+    }
+
+    let axis = to_center / distance_squared.sqrt();
+    let cos_max = (1.0 - radius * radius / distance_squared).sqrt();
+    let cos_theta = 1.0 - rng.next_f64() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.next_f64();
+    let (tangent, bitangent) = orthonormal_basis(axis);
+
+    let direction =
+        tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + axis * cos_theta;
+
+    Some((direction, 2.0 * PI * (1.0 - cos_max)))
+}
+
+/**
+ * A point distributed uniformly over the unit disk
+ */
+pub(crate) fn uniform_disk(rng: &mut Rng) -> (f64, f64) {
+    let r = rng.next_f64().sqrt();
+    let phi = 2.0 * PI * rng.next_f64();
+
+    (r * phi.cos(), r * phi.sin())
+}
@@ -0,0 +1,192 @@
+use crate::color::LinearColor;
+use crate::coord::WorldCoordinate;
+use crate::scene::object::light::Light;
+
+/**
+ * The luminance the sky is scaled to at the zenith, so that the horizon and the area around the sun,
+ * which are several times brighter, stay within the displayable range
+ */
+const ZENITH_LUMINANCE: f64 = 0.25;
+
+/**
+ * A procedural clear daylight sky, following the analytic model of Preetham, Shirley and Smits,
+ * "A Practical Analytic Model for Daylight" (1999)
+ */
+pub(crate) struct Sky {
+    sun: WorldCoordinate,
+    turbidity: f64,
+    intensity: f64,
+    luminance: Perez,
+    chroma_x: Perez,
+    chroma_y: Perez,
+    zenith_x: f64,
+    zenith_y: f64,
+}
+
+impl Sky {
+    /**
+     * A sky lit by the sun in direction `sun` (which must be above the horizon), with the haziness of
+     * the atmosphere given by `turbidity`: about 2 for a very clear sky up to 10 for a hazy one
+     */
+    pub(crate) fn new(sun: WorldCoordinate, turbidity: f64, intensity: f64) -> Self {
+        let t = turbidity;
+        let sun = sun.normalize();
+        let theta_s = sun.y().clamp(-1.0, 1.0).acos();
+
+        let zenith_x = zenith_chromaticity(
+            t,
+            theta_s,
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        );
+        let zenith_y = zenith_chromaticity(
+            t,
+            theta_s,
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        );
+
+        Self {
+            sun,
+            turbidity,
+            intensity,
+            luminance: Perez::new(
+                [0.1787, -0.3554, -0.0227, 0.1206, -0.0670],
+                [-1.4630, 0.4275, 5.3251, -2.5771, 0.3703],
+                t,
+                theta_s,
+            ),
+            chroma_x: Perez::new(
+                [-0.0193, -0.0665, -0.0004, -0.0641, -0.0033],
+                [-0.2592, 0.0008, 0.2125, -0.8989, 0.0452],
+                t,
+                theta_s,
+            ),
+            chroma_y: Perez::new(
+                [-0.0167, -0.0950, -0.0079, -0.0441, -0.0109],
+                [-0.2608, 0.0092, 0.2102, -1.6537, 0.0529],
+                t,
+                theta_s,
+            ),
+            zenith_x,
+            zenith_y,
+        }
+    }
+
+    pub(crate) fn radiance(&self, direction: WorldCoordinate) -> LinearColor {
+        let direction = direction.normalize();
+        // The model is only defined above the horizon; below it, repeat the color at the horizon
+        let cos_theta = direction.y().max(0.001);
+        let gamma = direction.dot(self.sun).clamp(-1.0, 1.0).acos();
+
+        let luminance = ZENITH_LUMINANCE * self.luminance.relative(cos_theta, gamma);
+        let x = self.zenith_x * self.chroma_x.relative(cos_theta, gamma);
+        let y = self.zenith_y * self.chroma_y.relative(cos_theta, gamma);
+
+        xyy_to_linear_srgb(x, y, luminance) * self.intensity
+    }
+
+    /**
+     * A directional light shining from the sun, dimmed by the extra atmosphere sunlight passes through
+     * when the sun is low or the air is hazy
+     */
+    pub(crate) fn sun_light(&self) -> Light {
+        let air_mass = 1.0 / self.sun.y().max(0.05);
+        let transmittance = (-0.1 * self.turbidity * air_mass).exp();
+        Light::direction(self.sun, self.intensity * transmittance)
+    }
+}
+
+/**
+ * The Perez sky distribution function for one of the luminance or chromaticity channels, normalised
+ * so that it gives 1 at the zenith
+ */
+struct Perez {
+    coefficients: [f64; 5],
+    at_zenith: f64,
+}
+
+impl Perez {
+    fn new(slopes: [f64; 5], intercepts: [f64; 5], turbidity: f64, theta_s: f64) -> Self {
+        let mut coefficients = [0.0; 5];
+        for (i, coefficient) in coefficients.iter_mut().enumerate() {
+            *coefficient = slopes[i] * turbidity + intercepts[i];
+        }
+        let at_zenith = perez(&coefficients, 1.0, theta_s);
+        Self {
+            coefficients,
+            at_zenith,
+        }
+    }
+
+    /**
+     * The value in a direction at angle theta (given as its cosine) from the zenith and gamma from the
+     * sun, relative to the value at the zenith
+     */
+    fn relative(&self, cos_theta: f64, gamma: f64) -> f64 {
+        perez(&self.coefficients, cos_theta, gamma) / self.at_zenith
+    }
+}
+
+fn perez(coefficients: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    let cos_gamma = gamma.cos();
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+fn zenith_chromaticity(
+    turbidity: f64,
+    theta_s: f64,
+    t2: [f64; 4],
+    t1: [f64; 4],
+    t0: [f64; 4],
+) -> f64 {
+    let cubic = |c: [f64; 4]| ((c[0] * theta_s + c[1]) * theta_s + c[2]) * theta_s + c[3];
+    turbidity * turbidity * cubic(t2) + turbidity * cubic(t1) + cubic(t0)
+}
+
+fn xyy_to_linear_srgb(x: f64, y: f64, luminance: f64) -> LinearColor {
+    let big_x = x * luminance / y;
+    let big_z = (1.0 - x - y) * luminance / y;
+
+    LinearColor::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::ColorSpace;
+
+    fn sky(turbidity: f64) -> Sky {
+        Sky::new(WorldCoordinate::new(1.0, 0.5, 0.0), turbidity, 1.0)
+    }
+
+    #[test]
+    fn the_sky_is_brighter_towards_the_sun() {
+        let sky = sky(3.0);
+        let towards = sky.radiance(WorldCoordinate::new(1.0, 0.5, 0.0));
+        let away = sky.radiance(WorldCoordinate::new(-1.0, 0.5, 0.0));
+        assert!(towards.max_channel() > 2.0 * away.max_channel());
+    }
+
+    #[test]
+    fn the_clear_sky_overhead_is_blue() {
+        let zenith = sky(3.0)
+            .radiance(WorldCoordinate::new(0.0, 1.0, 0.0))
+            .to_color_with_alpha(ColorSpace::Srgb, 1.0);
+        assert!(zenith.blue() > zenith.green() && zenith.green() > zenith.red());
+    }
+
+    #[test]
+    fn haze_dims_the_sun() {
+        let clear = sky(2.0).sun_light().intensity();
+        let hazy = sky(10.0).sun_light().intensity();
+        assert!(hazy < clear && clear < 1.0);
+    }
+}
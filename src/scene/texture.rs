@@ -0,0 +1,305 @@
+use super::noise::{fbm, perlin};
+use super::object::shape::SurfacePoint;
+use crate::color::LinearColor;
+use crate::coord::WorldCoordinate;
+use crate::raster::{Filter, Raster, Wrap};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+/**
+ * The ways a pattern texture can alternate or blend between its two colors
+ */
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Pattern {
+    /// A 3D checkerboard of cubes
+    Checker,
+    /// Parallel slabs across the pattern direction
+    Stripes,
+    /// Concentric cylinders around the pattern direction
+    Rings,
+    /// A smooth blend along the pattern direction
+    Gradient,
+    /// Fractal Perlin noise
+    Noise,
+    /// Stripes distorted by noise
+    Marble,
+    /// Rings distorted by noise
+    Wood,
+}
+
+/**
+ * A color which may vary over the surface of an object
+ */
+#[derive(Clone)]
+pub(crate) enum Texture {
+    Solid(LinearColor),
+    Pattern(PatternTexture),
+    Image(ImageTexture),
+}
+
+impl Texture {
+    /**
+     * The color at `point` on the surface of the textured object
+     */
+    pub(crate) fn color_at(&self, point: &SurfacePoint) -> LinearColor {
+        match self {
+            Self::Solid(color) => *color,
+            Self::Pattern(pattern) => pattern.color_at(point.local),
+            Self::Image(image) => image.color_at(point.uv),
+        }
+    }
+
+    /**
+     * How quickly the brightness of the texture changes along the tangent and bitangent at `point`,
+     * measured per pattern repetition for pattern textures and per pixel for image textures
+     */
+    pub(crate) fn slope_at(&self, point: &SurfacePoint) -> (f64, f64) {
+        match self {
+            Self::Solid(_) => (0.0, 0.0),
+            Self::Pattern(pattern) => {
+                let step = pattern.scale * 1e-3;
+                let height = |offset: WorldCoordinate| {
+                    pattern.color_at(point.local + offset * step).average()
+                };
+                let slope = |axis: WorldCoordinate| (height(axis) - height(-axis)) / 2e-3;
+                (slope(point.tangent), slope(point.bitangent))
+            }
+            Self::Image(image) => {
+                let (u, v) = point.uv;
+                let du = 1.0 / image.raster.width() as f64;
+                let dv = 1.0 / image.raster.height() as f64;
+                let height = |u, v| image.color_at((u, v)).average();
+                (
+                    (height(u + du, v) - height(u - du, v)) / 2.0,
+                    // The bitangent points towards the top of the image, where v is smaller
+                    (height(u, v - dv) - height(u, v + dv)) / 2.0,
+                )
+            }
+        }
+    }
+
+    /**
+     * The brightest any channel of this texture can be
+     */
+    pub(crate) fn max_channel(&self) -> f64 {
+        match self {
+            Self::Solid(color) => color.max_channel(),
+            Self::Pattern(pattern) => pattern
+                .primary
+                .max_channel()
+                .max(pattern.secondary.max_channel()),
+            Self::Image(image) => image.max_channel * image.intensity,
+        }
+    }
+
+    pub(crate) fn scaled(self, factor: f64) -> Self {
+        match self {
+            Self::Solid(color) => Self::Solid(color * factor),
+            Self::Pattern(pattern) => Self::Pattern(PatternTexture {
+                primary: pattern.primary * factor,
+                secondary: pattern.secondary * factor,
+                ..pattern
+            }),
+            Self::Image(image) => Self::Image(ImageTexture {
+                intensity: image.intensity * factor,
+                ..image
+            }),
+        }
+    }
+}
+
+/**
+ * A procedural texture that alternates or blends between two colors
+ */
+#[derive(Copy, Clone)]
+pub(crate) struct PatternTexture {
+    pattern: Pattern,
+    primary: LinearColor,
+    secondary: LinearColor,
+    scale: f64,
+    direction: WorldCoordinate,
+    octaves: usize,
+    turbulence: f64,
+}
+
+impl PatternTexture {
+    pub(crate) fn new(pattern: Pattern, primary: LinearColor, secondary: LinearColor) -> Self {
+        Self {
+            pattern,
+            primary,
+            secondary,
+            scale: 1.0,
+            direction: WorldCoordinate::new(0.0, 1.0, 0.0),
+            octaves: 4,
+            turbulence: 1.0,
+        }
+    }
+
+    /**
+     * Set the size of one repetition of the pattern
+     */
+    pub(crate) fn with_scale(self, scale: f64) -> Self {
+        Self { scale, ..self }
+    }
+
+    /**
+     * Set the orientation of stripes, rings, gradients, marble and wood
+     */
+    pub(crate) fn with_direction(self, direction: WorldCoordinate) -> Self {
+        Self {
+            direction: direction.normalize(),
+            ..self
+        }
+    }
+
+    /**
+     * Set the number of layers of noise, each adding finer detail
+     */
+    pub(crate) fn with_octaves(self, octaves: usize) -> Self {
+        Self { octaves, ..self }
+    }
+
+    /**
+     * Set how strongly noise distorts the marble and wood patterns
+     */
+    pub(crate) fn with_turbulence(self, turbulence: f64) -> Self {
+        Self { turbulence, ..self }
+    }
+
+    fn color_at(&self, point: WorldCoordinate) -> LinearColor {
+        let p = point / self.scale;
+        let along = p.dot(self.direction);
+        let across = (p - self.direction * along).abs();
+        let noise = || fbm(p, self.octaves);
+
+        let t = match self.pattern {
+            Pattern::Checker => alternate(p.x().floor() + p.y().floor() + p.z().floor()),
+            Pattern::Stripes => alternate(along.floor()),
+            Pattern::Rings => alternate(across.floor()),
+            Pattern::Gradient => (0.5 + 0.5 * along).clamp(0.0, 1.0),
+            Pattern::Noise => (0.5 + 0.5 * noise()).clamp(0.0, 1.0),
+            Pattern::Marble => 0.5 + 0.5 * (PI * (along + self.turbulence * noise())).sin(),
+            Pattern::Wood => {
+                let grain = across + self.turbulence * perlin(p);
+                grain - grain.floor()
+            }
+        };
+
+        self.primary * (1.0 - t) + self.secondary * t
+    }
+}
+
+/**
+ * A texture that wraps an image onto an object using the UV coordinates of its surface
+ */
+#[derive(Clone)]
+pub(crate) struct ImageTexture {
+    raster: Rc<Raster>,
+    filter: Filter,
+    wrap: Wrap,
+    intensity: f64,
+    max_channel: f64,
+}
+
+impl ImageTexture {
+    pub(crate) fn new(raster: Raster) -> Self {
+        let max_channel = raster.max_channel();
+        Self {
+            raster: Rc::new(raster),
+            filter: Filter::Bilinear,
+            wrap: Wrap::Repeat,
+            intensity: 1.0,
+            max_channel,
+        }
+    }
+
+    /**
+     * Set how pixels are combined when the image is magnified
+     */
+    pub(crate) fn with_filter(self, filter: Filter) -> Self {
+        Self { filter, ..self }
+    }
+
+    /**
+     * Set whether the image tiles or stretches its edges outside the 0 to 1 UV range
+     */
+    pub(crate) fn with_wrap(self, wrap: Wrap) -> Self {
+        Self { wrap, ..self }
+    }
+
+    fn color_at(&self, (u, v): (f64, f64)) -> LinearColor {
+        self.raster.sample(u, v, self.filter, self.wrap, self.wrap) * self.intensity
+    }
+}
+
+/**
+ * 0 for even whole numbers and 1 for odd ones
+ */
+fn alternate(n: f64) -> f64 {
+    if n.rem_euclid(2.0) < 1.0 {
+        0.0
+    } else {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: LinearColor = LinearColor::new(0.0, 0.0, 0.0);
+    const WHITE: LinearColor = LinearColor::new(1.0, 1.0, 1.0);
+
+    fn at(x: f64, y: f64, z: f64) -> SurfacePoint {
+        SurfacePoint {
+            local: WorldCoordinate::new(x, y, z),
+            uv: (0.0, 0.0),
+            normal: WorldCoordinate::new(0.0, 0.0, -1.0),
+            tangent: WorldCoordinate::new(1.0, 0.0, 0.0),
+            bitangent: WorldCoordinate::new(0.0, 1.0, 0.0),
+        }
+    }
+
+    fn color_at(texture: PatternTexture, x: f64, y: f64, z: f64) -> LinearColor {
+        Texture::Pattern(texture).color_at(&at(x, y, z))
+    }
+
+    #[test]
+    fn checkers_alternate_between_neighbouring_cubes() {
+        let checker = PatternTexture::new(Pattern::Checker, BLACK, WHITE);
+        assert_eq!(color_at(checker, 0.5, 0.5, 0.5), BLACK);
+        assert_eq!(color_at(checker, 1.5, 0.5, 0.5), WHITE);
+        assert_eq!(color_at(checker, -0.5, 0.5, 0.5), WHITE);
+        assert_eq!(color_at(checker, 1.5, -0.5, 0.5), BLACK);
+    }
+
+    #[test]
+    fn stripes_repeat_along_their_direction_at_their_scale() {
+        let stripes = PatternTexture::new(Pattern::Stripes, BLACK, WHITE)
+            .with_direction(WorldCoordinate::new(2.0, 0.0, 0.0))
+            .with_scale(2.0);
+        assert_eq!(color_at(stripes, 1.0, 0.0, 0.0), BLACK);
+        assert_eq!(color_at(stripes, 3.0, 0.0, 0.0), WHITE);
+        assert_eq!(color_at(stripes, 3.0, 7.0, -5.0), WHITE);
+        assert_eq!(color_at(stripes, 5.0, 0.0, 0.0), BLACK);
+    }
+
+    #[test]
+    fn noise_is_halfway_between_the_colors_at_lattice_points() {
+        let noise = PatternTexture::new(Pattern::Noise, BLACK, WHITE).with_octaves(1);
+        assert_eq!(
+            color_at(noise, 3.0, -2.0, 7.0),
+            LinearColor::new(0.5, 0.5, 0.5)
+        );
+    }
+
+    #[test]
+    fn scaling_scales_both_colors() {
+        let checker = PatternTexture::new(Pattern::Checker, WHITE, WHITE * 0.5);
+        let scaled = Texture::Pattern(checker).scaled(2.0);
+        assert_eq!(scaled.max_channel(), 2.0);
+        assert_eq!(scaled.color_at(&at(1.5, 0.5, 0.5)), WHITE);
+    }
+}